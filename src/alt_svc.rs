@@ -0,0 +1,330 @@
+use crate::{Body, Error, HttpContext};
+use hyper::{
+    Response,
+    StatusCode,
+    body::Bytes,
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderName, HeaderValue},
+    http::uri::Scheme,
+};
+use std::collections::HashMap;
+use tracing::error;
+
+const ALT_SVC: HeaderName = HeaderName::from_static("alt-svc");
+
+/// DNS resource record type of HTTPS records.
+const HTTPS_RECORD: u16 = 65;
+
+/// Largest DNS message that can be carried by DNS over HTTPS.
+const MAX_DNS_MESSAGE_LEN: usize = 65_535;
+
+/// What to do with alternative service hints in a response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AltSvcAction {
+    /// Pass hints through unchanged.
+    Keep,
+    /// Remove `alt-svc` headers, and answer DNS over HTTPS queries for HTTPS
+    /// records with no records.
+    #[default]
+    Strip,
+    /// Only remove the alternatives that would be reached over QUIC, keeping
+    /// the rest. DNS over HTTPS responses are handled as with
+    /// [`AltSvcAction::Strip`], as HTTPS records are how clients discover
+    /// HTTP/3 without an `alt-svc` header.
+    RemoveQuic,
+}
+
+/// Controls the alternative service hints that clients see in responses.
+///
+/// Clients that learn from an `alt-svc` header, or from an HTTPS DNS record,
+/// that an origin speaks HTTP/3 will connect to it over QUIC, which bypasses
+/// the proxy. This policy is applied to every response after
+/// [`HttpHandler::handle_response`](crate::HttpHandler::handle_response), and
+/// by default strips the hints from responses of intercepted hosts while
+/// leaving plain HTTP responses untouched.
+///
+/// HTTPS records are only seen when clients resolve names with DNS over HTTPS
+/// through the proxy.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{AltSvcAction, AltSvcPolicy};
+///
+/// let policy = AltSvcPolicy::new()
+///     .with_host("example.com", AltSvcAction::RemoveQuic)
+///     .with_host("trusted.example.com", AltSvcAction::Keep);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AltSvcPolicy {
+    intercepted: AltSvcAction,
+    plain: AltSvcAction,
+    hosts: HashMap<String, AltSvcAction>,
+}
+
+impl AltSvcPolicy {
+    /// Create a policy that strips hints from responses of intercepted hosts.
+    pub fn new() -> Self {
+        Self {
+            intercepted: AltSvcAction::Strip,
+            plain: AltSvcAction::Keep,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Set the action for responses of intercepted hosts without an action of
+    /// their own.
+    pub fn with_intercepted(mut self, action: AltSvcAction) -> Self {
+        self.intercepted = action;
+        self
+    }
+
+    /// Set the action for plain HTTP responses of hosts without an action of
+    /// their own.
+    pub fn with_plain(mut self, action: AltSvcAction) -> Self {
+        self.plain = action;
+        self
+    }
+
+    /// Set the action for responses of a host.
+    pub fn with_host(mut self, host: &str, action: AltSvcAction) -> Self {
+        self.hosts.insert(host.to_ascii_lowercase(), action);
+        self
+    }
+
+    fn action(&self, ctx: &HttpContext) -> AltSvcAction {
+        let host = ctx
+            .uri
+            .host()
+            .map(str::to_ascii_lowercase)
+            .and_then(|host| self.hosts.get(&host));
+
+        match host {
+            Some(&action) => action,
+            None if ctx.uri.scheme() == Some(&Scheme::HTTPS) => self.intercepted,
+            None => self.plain,
+        }
+    }
+
+    pub(crate) async fn apply(&self, ctx: &HttpContext, mut res: Response<Body>) -> Response<Body> {
+        let action = self.action(ctx);
+
+        match action {
+            AltSvcAction::Keep => return res,
+            AltSvcAction::Strip => {
+                res.headers_mut().remove(ALT_SVC);
+            }
+            AltSvcAction::RemoveQuic => {
+                let value = res
+                    .headers_mut()
+                    .remove(ALT_SVC)
+                    .and_then(|value| remove_quic(value.to_str().ok()?));
+
+                if let Some(value) = value {
+                    res.headers_mut().insert(ALT_SVC, value);
+                }
+            }
+        }
+
+        if !is_dns_message(&res) {
+            return res;
+        }
+
+        let (mut parts, body) = res.into_parts();
+
+        let message = match body.collect_bytes_limited(MAX_DNS_MESSAGE_LEN).await {
+            Ok(message) => message,
+            Err(Error::LengthLimit(e)) => return Response::from_parts(parts, e.into_body()),
+            Err(e) => {
+                error!("Failed to read DNS message: {}", e);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .expect("Failed to build response");
+            }
+        };
+
+        match remove_https_records(&message) {
+            Some(message) => {
+                parts.headers.remove(CONTENT_LENGTH);
+                Response::from_parts(parts, Body::from(Bytes::from(message)))
+            }
+            None => Response::from_parts(parts, Body::from(message)),
+        }
+    }
+}
+
+impl Default for AltSvcPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether the alternative is reached over QUIC.
+fn is_quic(protocol: &str) -> bool {
+    protocol == "h3" || protocol.starts_with("h3-") || protocol == "quic"
+}
+
+/// Remove the QUIC alternatives from an `alt-svc` header, returning `None` if
+/// none are left.
+fn remove_quic(value: &str) -> Option<HeaderValue> {
+    if value.trim() == "clear" {
+        return Some(HeaderValue::from_static("clear"));
+    }
+
+    let kept = value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| {
+            let protocol = entry
+                .split_once('=')
+                .map_or(*entry, |(protocol, _)| protocol);
+            !is_quic(protocol.trim())
+        })
+        .collect::<Vec<_>>();
+
+    if kept.is_empty() {
+        return None;
+    }
+
+    HeaderValue::from_str(&kept.join(", ")).ok()
+}
+
+fn is_dns_message(res: &Response<Body>) -> bool {
+    res.status() == StatusCode::OK
+        && !res.headers().contains_key(CONTENT_ENCODING)
+        && res
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"application/dns-message"))
+}
+
+/// Turn the answer to a query for HTTPS records into an answer with no
+/// records, returning `None` if the message answers any other query.
+fn remove_https_records(message: &[u8]) -> Option<Vec<u8>> {
+    let header = message.get(..12)?;
+    let is_response = header[2] & 0x80 != 0;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let records = header[6..12].iter().any(|&b| b != 0);
+
+    if !is_response || questions != 1 || !records {
+        return None;
+    }
+
+    let mut pos = 12;
+
+    loop {
+        let len = *message.get(pos)? as usize;
+
+        match len & 0xc0 {
+            0 if len == 0 => {
+                pos += 1;
+                break;
+            }
+            0 => pos += 1 + len,
+            0xc0 => {
+                pos += 2;
+                break;
+            }
+            _ => return None,
+        }
+    }
+
+    let question = message.get(pos..pos + 4)?;
+
+    if u16::from_be_bytes([question[0], question[1]]) != HTTPS_RECORD {
+        return None;
+    }
+
+    let mut stripped = message[..pos + 4].to_vec();
+    stripped[6..12].fill(0);
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::Request;
+
+    fn context(uri: &str) -> HttpContext {
+        let req = Request::get(uri).body(()).unwrap();
+        HttpContext::from_request(&req, "127.0.0.1:8080".parse().unwrap())
+    }
+
+    fn response() -> Response<Body> {
+        Response::builder()
+            .header(ALT_SVC, r#"h3=":443"; ma=86400, h2="alt.example.com:443""#)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn strips_alt_svc_for_intercepted_hosts() {
+        let policy = AltSvcPolicy::new();
+
+        let res = policy
+            .apply(&context("https://example.com/"), response())
+            .await;
+        assert!(!res.headers().contains_key(ALT_SVC));
+
+        let res = policy
+            .apply(&context("http://example.com/"), response())
+            .await;
+        assert!(res.headers().contains_key(ALT_SVC));
+    }
+
+    #[tokio::test]
+    async fn applies_host_actions() {
+        let policy = AltSvcPolicy::new()
+            .with_host("Quic.example.com", AltSvcAction::RemoveQuic)
+            .with_host("keep.example.com", AltSvcAction::Keep);
+
+        let res = policy
+            .apply(&context("https://quic.example.com/"), response())
+            .await;
+        assert_eq!(res.headers()[ALT_SVC], r#"h2="alt.example.com:443""#);
+
+        let res = policy
+            .apply(&context("https://keep.example.com/"), response())
+            .await;
+        assert_eq!(res.headers()[ALT_SVC], response().headers()[ALT_SVC]);
+    }
+
+    #[test]
+    fn removes_quic_alternatives() {
+        assert_eq!(remove_quic(r#"h3-29=":443", quic=":443""#), None);
+        assert_eq!(remove_quic("clear").unwrap(), "clear");
+    }
+
+    #[tokio::test]
+    async fn removes_https_records() {
+        let question = b"\x07example\x03com\x00\x00\x41\x00\x01";
+        let answer = b"\xc0\x0c\x00\x41\x00\x01\x00\x00\x0e\x10\x00\x03\x00\x01\x00";
+
+        let mut message = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        message.extend_from_slice(question);
+        message.extend_from_slice(answer);
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/dns-message")
+            .header(CONTENT_LENGTH, message.len())
+            .body(Body::from(Bytes::from(message.clone())))
+            .unwrap();
+
+        let res = AltSvcPolicy::new()
+            .apply(&context("https://dns.example.com/dns-query"), res)
+            .await;
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            &body[..12],
+            b"\x12\x34\x81\x80\x00\x01\x00\x00\x00\x00\x00\x00"
+        );
+        assert_eq!(&body[12..], question);
+
+        // Queries for other record types are left alone.
+        message[12 + question.len() - 3] = 0x01;
+        assert_eq!(remove_https_records(&message), None);
+    }
+}
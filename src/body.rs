@@ -1,16 +1,30 @@
-use crate::Error;
-use futures::{Stream, TryStream, TryStreamExt};
-use http_body_util::{BodyExt, Collected, Empty, Full, StreamBody, combinators::BoxBody};
+use crate::{BodyObserver, Error, tap::Tap};
+use futures::{Stream, StreamExt, TryStream, TryStreamExt, stream};
+use http_body_util::{
+    BodyExt,
+    BodyStream,
+    Collected,
+    Empty,
+    Full,
+    StreamBody,
+    combinators::BoxBody,
+};
 use hyper::{
     Request,
     Response,
     body::{Body as HttpBody, Bytes, Frame, Incoming, SizeHint},
+    header::HeaderMap,
+};
+use std::{
+    pin::Pin,
+    task::{Poll, ready},
 };
-use std::{pin::Pin, task::Poll};
+use thiserror::Error;
 
 #[derive(Debug)]
 enum Internal {
     BoxBody(BoxBody<Bytes, Error>),
+    Buffered(Buffered),
     Collected(Collected<Bytes>),
     Empty(Empty<Bytes>),
     Full(Full<Bytes>),
@@ -24,11 +38,219 @@ pub struct Body {
     inner: Internal,
 }
 
+/// Error returned when a body exceeds the limit passed to
+/// [`Body::collect_bytes_limited`].
+///
+/// The bytes read before the limit was exceeded are retained, so the original
+/// body can be reconstructed with [`into_body`](Self::into_body).
+#[derive(Debug, Error)]
+#[error("body exceeded limit of {limit} bytes")]
+pub struct LengthLimitError {
+    limit: usize,
+    read: Vec<Bytes>,
+    rest: Body,
+}
+
+impl LengthLimitError {
+    /// The limit that was exceeded.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The chunks that were read before the limit was exceeded.
+    pub fn read(&self) -> &[Bytes] {
+        &self.read
+    }
+
+    /// Reconstruct the original body from the chunks already read followed by
+    /// the unread remainder.
+    pub fn into_body(mut self) -> Body {
+        self.take_body()
+    }
+
+    /// Take the original body out of the error, leaving it empty.
+    pub(crate) fn take_body(&mut self) -> Body {
+        let read = std::mem::take(&mut self.read);
+        let rest = std::mem::replace(&mut self.rest, Body::empty());
+
+        Body::from(StreamBody::new(
+            stream::iter(read)
+                .map(|chunk| Ok(Frame::data(chunk)))
+                .chain(BodyStream::new(rest)),
+        ))
+    }
+}
+
 impl Body {
     pub fn empty() -> Self {
         Self::from(Empty::new())
     }
-   
+
+    /// Collect the body into a single buffer of at most `limit` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::LengthLimit`] if the body is larger than `limit`, from
+    /// which the original body can be recovered. Returns other errors if the
+    /// body fails while it is being read.
+    ///
+    /// Only the data of the body is returned. Use
+    /// [`collect`](BodyExt::collect) to keep its trailers.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use hudsucker::{Body, Error};
+    ///
+    /// let body = Body::from("hello, world");
+    ///
+    /// let body = match body.collect_bytes_limited(5).await {
+    ///     Ok(bytes) => Body::from(bytes),
+    ///     Err(Error::LengthLimit(e)) => e.into_body(),
+    ///     Err(e) => panic!("{}", e),
+    /// };
+    /// # }
+    /// ```
+    pub async fn collect_bytes_limited(mut self, limit: usize) -> Result<Bytes, Error> {
+        let mut read = Vec::new();
+        let mut len = 0;
+
+        if self.size_hint().lower() > limit as u64 {
+            return Err(Box::new(LengthLimitError {
+                limit,
+                read,
+                rest: self,
+            })
+            .into());
+        }
+
+        while let Some(frame) = self.frame().await {
+            let Ok(chunk) = frame?.into_data() else {
+                continue;
+            };
+
+            len += chunk.len();
+            read.push(chunk);
+
+            if len > limit {
+                return Err(Box::new(LengthLimitError {
+                    limit,
+                    read,
+                    rest: self,
+                })
+                .into());
+            }
+        }
+
+        Ok(match read.len() {
+            0 => Bytes::new(),
+            1 => read.swap_remove(0),
+            _ => Bytes::from(read.concat()),
+        })
+    }
+
+    /// Attach an observer that sees every frame of the body as it streams,
+    /// without buffering it.
+    ///
+    /// See [`BodyObserver`] for an example.
+    pub fn tap(self, observer: impl BodyObserver) -> Self {
+        Self::from(BoxBody::new(Tap::new(self, observer)))
+    }
+
+    /// Send trailers at the end of the body.
+    ///
+    /// The trailers are merged into the trailers the body already has,
+    /// replacing values of the same name. Trailers are only sent to peers that
+    /// support them, such as HTTP/2 peers and HTTP/1.1 clients that sent
+    /// `te: trailers`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::{Body, hyper::header::HeaderMap};
+    ///
+    /// let mut trailers = HeaderMap::new();
+    /// trailers.insert("x-checksum", "abc".parse().unwrap());
+    ///
+    /// let body = Body::from("hello, world").with_trailers(trailers);
+    /// ```
+    pub fn with_trailers(self, trailers: HeaderMap) -> Self {
+        self.map_trailers(move |existing| {
+            let mut existing = existing.unwrap_or_default();
+            existing.extend(trailers);
+            Some(existing)
+        })
+    }
+
+    /// Inspect or replace the trailers of the body as it streams.
+    ///
+    /// The function is called once, when the body has been read to the end,
+    /// with the trailers of the body if it has any. The trailers it returns
+    /// are sent in their place.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::Body;
+    ///
+    /// let body = Body::from("hello, world").map_trailers(|trailers| {
+    ///     println!("trailers: {:?}", trailers);
+    ///     trailers
+    /// });
+    /// ```
+    pub fn map_trailers<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Option<HeaderMap>) -> Option<HeaderMap> + Send + Sync + 'static,
+    {
+        Self::from(BoxBody::new(MapTrailers {
+            inner: self,
+            f: Some(f),
+        }))
+    }
+
+    /// Create a copy of a buffered body that shares the same underlying
+    /// buffer.
+    ///
+    /// Returns `None` if the body is streamed and cannot be replayed. Buffered
+    /// bodies are converted in place, so repeated replays do not copy the
+    /// contents. The trailers of a collected body are replayed with it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::Body;
+    ///
+    /// let mut body = Body::from("hello, world");
+    /// let copy = body.replay().unwrap();
+    /// ```
+    pub fn replay(&mut self) -> Option<Self> {
+        match &mut self.inner {
+            Internal::Collected(body) => {
+                let trailers = body.trailers().cloned();
+                let data = std::mem::take(body).to_bytes();
+                self.inner = Internal::Buffered(Buffered {
+                    data: Some(data),
+                    trailers,
+                });
+            }
+            Internal::String(body) => {
+                let bytes = Bytes::from(std::mem::take(body));
+                self.inner = Internal::Full(Full::new(bytes));
+            }
+            _ => (),
+        }
+
+        match &self.inner {
+            Internal::Buffered(body) => Some(Self {
+                inner: Internal::Buffered(body.clone()),
+            }),
+            Internal::Empty(_) => Some(Self::empty()),
+            Internal::Full(body) => Some(Self::from(body.clone())),
+            _ => None,
+        }
+    }
 
     pub fn from_stream<S>(stream: S) -> Self
     where
@@ -57,6 +279,7 @@ impl HttpBody for Body {
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         match &mut self.inner {
             Internal::BoxBody(body) => Pin::new(body).poll_frame(cx),
+            Internal::Buffered(body) => Pin::new(body).poll_frame(cx),
             Internal::Collected(body) => Pin::new(body).poll_frame(cx).map_err(|e| match e {}),
             Internal::Empty(body) => Pin::new(body).poll_frame(cx).map_err(|e| match e {}),
             Internal::Full(body) => Pin::new(body).poll_frame(cx).map_err(|e| match e {}),
@@ -68,6 +291,7 @@ impl HttpBody for Body {
     fn is_end_stream(&self) -> bool {
         match &self.inner {
             Internal::BoxBody(body) => body.is_end_stream(),
+            Internal::Buffered(body) => body.is_end_stream(),
             Internal::Collected(body) => body.is_end_stream(),
             Internal::Empty(body) => body.is_end_stream(),
             Internal::Full(body) => body.is_end_stream(),
@@ -79,6 +303,7 @@ impl HttpBody for Body {
     fn size_hint(&self) -> SizeHint {
         match &self.inner {
             Internal::BoxBody(body) => body.size_hint(),
+            Internal::Buffered(body) => body.size_hint(),
             Internal::Collected(body) => body.size_hint(),
             Internal::Empty(body) => body.size_hint(),
             Internal::Full(body) => body.size_hint(),
@@ -88,6 +313,94 @@ impl HttpBody for Body {
     }
 }
 
+/// A buffered body with trailers, which can be cheaply cloned for
+/// [`Body::replay`].
+#[derive(Clone, Debug)]
+struct Buffered {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl HttpBody for Buffered {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(data) = self.data.take().filter(|data| !data.is_empty()) {
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+
+        Poll::Ready(
+            self.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.as_ref().is_none_or(Bytes::is_empty) && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.data.as_ref().map_or(0, |data| data.len() as u64))
+    }
+}
+
+struct MapTrailers<F> {
+    inner: Body,
+    f: Option<F>,
+}
+
+// The function is never pinned, only the body is polled through the pin.
+impl<F> Unpin for MapTrailers<F> {}
+
+impl<F> HttpBody for MapTrailers<F>
+where
+    F: FnOnce(Option<HeaderMap>) -> Option<HeaderMap>,
+{
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        let Some(f) = this.f.take() else {
+            return Pin::new(&mut this.inner).poll_frame(cx);
+        };
+
+        let trailers = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => match frame.into_trailers() {
+                Ok(trailers) => Some(trailers),
+                Err(frame) => {
+                    this.f = Some(f);
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+            },
+            Some(Err(err)) => {
+                this.f = Some(f);
+                return Poll::Ready(Some(Err(err)));
+            }
+            None => None,
+        };
+
+        Poll::Ready(f(trailers).map(|trailers| Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.f.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
 impl From<BoxBody<Bytes, Error>> for Body {
     fn from(value: BoxBody<Bytes, Error>) -> Self {
         Self {
@@ -139,6 +452,14 @@ where
     }
 }
 
+impl From<Bytes> for Body {
+    fn from(value: Bytes) -> Self {
+        Self {
+            inner: Internal::Full(Full::new(value)),
+        }
+    }
+}
+
 impl From<String> for Body {
     fn from(value: String) -> Self {
         Self {
@@ -180,3 +501,153 @@ where
         value.into_body().into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn to_bytes(body: Body) -> Bytes {
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        Body::from_stream(stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, Error>(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    mod collect_bytes_limited {
+        use super::*;
+
+        #[tokio::test]
+        async fn within_limit() {
+            let body = chunked(&["hello, ", "world"]);
+
+            assert_eq!(
+                &body.collect_bytes_limited(12).await.unwrap()[..],
+                b"hello, world"
+            );
+        }
+
+        #[tokio::test]
+        async fn rejects_by_size_hint() {
+            let body = Body::from("hello, world");
+
+            match body.collect_bytes_limited(5).await {
+                Err(Error::LengthLimit(e)) => {
+                    assert_eq!(e.limit(), 5);
+                    assert!(e.read().is_empty());
+                    assert_eq!(&to_bytes(e.into_body()).await[..], b"hello, world");
+                }
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn reconstructs_streamed_body() {
+            let body = chunked(&["hello", ", ", "world"]);
+
+            match body.collect_bytes_limited(6).await {
+                Err(Error::LengthLimit(e)) => {
+                    assert_eq!(e.read().len(), 2);
+                    assert_eq!(&to_bytes(e.into_body()).await[..], b"hello, world");
+                }
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    mod trailers {
+        use super::*;
+
+        #[tokio::test]
+        async fn adds_trailers() {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-a", "1".parse().unwrap());
+
+            let collected = Body::from("hello")
+                .with_trailers(trailers.clone())
+                .collect()
+                .await
+                .unwrap();
+
+            assert_eq!(collected.trailers(), Some(&trailers));
+            assert_eq!(&collected.to_bytes()[..], b"hello");
+        }
+
+        #[tokio::test]
+        async fn merges_trailers() {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-a", "1".parse().unwrap());
+            trailers.insert("x-b", "2".parse().unwrap());
+
+            let body = Body::from(StreamBody::new(stream::iter(vec![
+                Ok(Frame::data(Bytes::from_static(b"hello"))),
+                Ok(Frame::trailers(trailers)),
+            ])));
+
+            let mut replacement = HeaderMap::new();
+            replacement.insert("x-b", "3".parse().unwrap());
+
+            let collected = body.with_trailers(replacement).collect().await.unwrap();
+            let trailers = collected.trailers().unwrap();
+
+            assert_eq!(trailers["x-a"], "1");
+            assert_eq!(trailers["x-b"], "3");
+        }
+
+        #[tokio::test]
+        async fn removes_trailers() {
+            let collected = Body::from("hello")
+                .with_trailers(HeaderMap::new())
+                .map_trailers(|_| None)
+                .collect()
+                .await
+                .unwrap();
+
+            assert!(collected.trailers().is_none());
+        }
+    }
+
+    mod replay {
+        use super::*;
+
+        #[tokio::test]
+        async fn replays_buffered_body() {
+            let mut body = Body::from(String::from("hello, world"));
+            let copy = body.replay().unwrap();
+
+            assert_eq!(&to_bytes(copy).await[..], b"hello, world");
+            assert_eq!(&to_bytes(body).await[..], b"hello, world");
+        }
+
+        #[tokio::test]
+        async fn replays_trailers() {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", "abc".parse().unwrap());
+
+            let collected = Body::from("hello, world")
+                .with_trailers(trailers)
+                .collect()
+                .await
+                .unwrap();
+            let mut body = Body::from(collected);
+
+            for body in [body.replay().unwrap(), body] {
+                let collected = body.collect().await.unwrap();
+                assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc");
+                assert_eq!(&collected.to_bytes()[..], b"hello, world");
+            }
+        }
+
+        #[test]
+        fn does_not_replay_streamed_body() {
+            let mut body = chunked(&["hello, world"]);
+
+            assert!(body.replay().is_none());
+        }
+    }
+}
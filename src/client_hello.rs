@@ -0,0 +1,255 @@
+use tokio_rustls::rustls::ProtocolVersion;
+
+/// Largest ClientHello that is buffered before giving up on parsing it.
+pub(crate) const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+
+const SERVER_NAME: u16 = 0x0000;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Details of the TLS ClientHello sent by a client at the start of a CONNECT
+/// tunnel.
+///
+/// When a tunnel starts with TLS, the ClientHello is added to the extensions of
+/// the CONNECT request before
+/// [`HttpHandler::should_intercept`](crate::HttpHandler::should_intercept) is
+/// called. This allows interception decisions to be made by name when the
+/// client connects to an IP address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientHello {
+    /// Server name from the SNI extension.
+    pub server_name: Option<String>,
+    /// Protocols offered through ALPN, in order of preference.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// TLS versions offered by the client, from the `supported_versions`
+    /// extension or the legacy version field when it is absent. GREASE values
+    /// are left out.
+    pub versions: Vec<ProtocolVersion>,
+}
+
+/// The ClientHello could not be parsed.
+#[derive(Debug)]
+pub(crate) struct Malformed;
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        if self.0.len() < len {
+            return Err(Malformed);
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Malformed> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec8(&mut self) -> Result<Reader<'a>, Malformed> {
+        let len = self.u8()? as usize;
+        Ok(Reader(self.take(len)?))
+    }
+
+    fn vec16(&mut self) -> Result<Reader<'a>, Malformed> {
+        let len = self.u16()? as usize;
+        Ok(Reader(self.take(len)?))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Gather the handshake message carried by the TLS records at the start of the
+/// data, returning `None` if more data is needed.
+fn handshake_message(mut data: &[u8]) -> Result<Option<Vec<u8>>, Malformed> {
+    let mut message = Vec::new();
+
+    loop {
+        let Some(header) = data.get(..RECORD_HEADER_LEN) else {
+            return Ok(None);
+        };
+
+        if header[0] != HANDSHAKE_RECORD || header[1] != 0x03 {
+            return Err(Malformed);
+        }
+
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(fragment) = data.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+            return Ok(None);
+        };
+
+        message.extend_from_slice(fragment);
+        data = &data[RECORD_HEADER_LEN + len..];
+
+        if message.len() >= 4 {
+            let len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+
+            if message.len() >= 4 + len {
+                message.truncate(4 + len);
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+impl ClientHello {
+    /// Parse the ClientHello at the start of the data, returning `None` if
+    /// more data is needed.
+    pub(crate) fn parse(data: &[u8]) -> Result<Option<Self>, Malformed> {
+        let Some(message) = handshake_message(data)? else {
+            return Ok(None);
+        };
+
+        let mut message = Reader(&message);
+
+        if message.u8()? != CLIENT_HELLO {
+            return Err(Malformed);
+        }
+
+        message.take(3)?;
+        let legacy_version = message.u16()?;
+        message.take(32)?;
+        message.vec8()?;
+        message.vec16()?;
+        message.vec8()?;
+
+        let mut hello = ClientHello::default();
+
+        if message.is_empty() {
+            hello.versions.push(ProtocolVersion::from(legacy_version));
+            return Ok(Some(hello));
+        }
+
+        let mut extensions = message.vec16()?;
+
+        while !extensions.is_empty() {
+            let kind = extensions.u16()?;
+            let mut data = extensions.vec16()?;
+
+            match kind {
+                SERVER_NAME => {
+                    let mut names = data.vec16()?;
+
+                    while !names.is_empty() {
+                        let kind = names.u8()?;
+                        let name = names.vec16()?;
+
+                        if kind == 0 {
+                            hello.server_name =
+                                Some(String::from_utf8(name.0.to_vec()).map_err(|_| Malformed)?);
+                        }
+                    }
+                }
+                ALPN => {
+                    let mut protocols = data.vec16()?;
+
+                    while !protocols.is_empty() {
+                        hello.alpn_protocols.push(protocols.vec8()?.0.to_vec());
+                    }
+                }
+                SUPPORTED_VERSIONS => {
+                    let mut versions = data.vec8()?;
+
+                    while !versions.is_empty() {
+                        let version = versions.u16()?;
+
+                        if !is_grease(version) {
+                            hello.versions.push(ProtocolVersion::from(version));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if hello.versions.is_empty() {
+            hello.versions.push(ProtocolVersion::from(legacy_version));
+        }
+
+        Ok(Some(hello))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, crypto::aws_lc_rs};
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let mut config =
+            ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let mut conn =
+            ClientConnection::new(Arc::new(config), server_name.to_owned().try_into().unwrap())
+                .unwrap();
+        let mut data = Vec::new();
+        conn.write_tls(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn parses_client_hello() {
+        let data = client_hello("example.com");
+
+        for len in [0, 3, RECORD_HEADER_LEN, data.len() - 1] {
+            assert!(ClientHello::parse(&data[..len]).unwrap().is_none());
+        }
+
+        let hello = ClientHello::parse(&data).unwrap().unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn_protocols, [&b"h2"[..], b"http/1.1"]);
+        assert_eq!(
+            hello.versions,
+            [ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+        );
+    }
+
+    #[test]
+    fn parses_client_hello_split_across_records() {
+        let data = client_hello("example.com");
+        let message = &data[RECORD_HEADER_LEN..];
+        let (first, second) = message.split_at(100);
+
+        let mut split = Vec::new();
+        for fragment in [first, second] {
+            split.extend_from_slice(&[HANDSHAKE_RECORD, 0x03, 0x01]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+
+        assert_eq!(
+            ClientHello::parse(&split).unwrap(),
+            ClientHello::parse(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert!(ClientHello::parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(ClientHello::parse(b"\x16\x03\x01\x00\x04\x02\x00\x00\x00").is_err());
+    }
+}
@@ -0,0 +1,546 @@
+use super::{DecodeLimits, decode_body, extract_encodings};
+use crate::{Body, BodyObserver, TapSummary};
+use async_compression::tokio::bufread::ZstdDecoder;
+use aws_lc_rs::digest::{SHA256, digest};
+use brotli::{
+    Allocator,
+    BrotliDecompressStream,
+    BrotliResult,
+    BrotliState,
+    SliceWrapperMut,
+    enc::StandardAlloc,
+};
+use http_body_util::BodyExt;
+use hyper::{Response, body::Bytes, header::HeaderName};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+const USE_AS_DICTIONARY: HeaderName = HeaderName::from_static("use-as-dictionary");
+
+const DCB_MAGIC: &[u8] = &[0xff, 0x44, 0x43, 0x42];
+const DCZ_MAGIC: &[u8] = &[0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+type Hash = [u8; 32];
+
+struct Dictionaries {
+    entries: HashMap<Hash, Bytes>,
+    order: VecDeque<Hash>,
+    capacity: usize,
+    max_size: usize,
+}
+
+/// Dictionaries used to decode `dcb` and `dcz` bodies, as defined by
+/// Compression Dictionary Transport.
+///
+/// Dictionaries are captured from responses with a `use-as-dictionary` header
+/// and looked up by the SHA-256 hash that prefixes each dictionary-compressed
+/// body. When the store is attached to a request or response as an extension,
+/// [`decode_request`](crate::decode_request) and
+/// [`decode_response`](crate::decode_response) use it to decode those bodies.
+/// The proxy does this automatically when a store is configured with
+/// [`ProxyBuilder::with_dictionary_store`](crate::builder::ProxyBuilder::with_dictionary_store).
+///
+/// Clients may advertise dictionaries that they cached before using the
+/// proxy, so decoding can still fail for dictionaries the store has never seen.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, DictionaryStore, hyper::Response};
+///
+/// let store = DictionaryStore::new().with_capacity(8);
+/// let hash = store.insert("shared dictionary contents");
+///
+/// let mut res = Response::new(Body::empty());
+/// res.extensions_mut().insert(store.clone());
+///
+/// assert!(store.get(&hash).is_some());
+/// ```
+#[derive(Clone)]
+pub struct DictionaryStore {
+    inner: Arc<Mutex<Dictionaries>>,
+}
+
+impl DictionaryStore {
+    /// Number of dictionaries kept by default.
+    pub const DEFAULT_CAPACITY: usize = 16;
+    /// Size of the largest dictionary captured by default.
+    pub const DEFAULT_MAX_DICTIONARY_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Dictionaries {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                capacity: Self::DEFAULT_CAPACITY,
+                max_size: Self::DEFAULT_MAX_DICTIONARY_SIZE,
+            })),
+        }
+    }
+
+    /// Keep at most the given number of dictionaries, evicting the oldest
+    /// first.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        self.lock().capacity = capacity;
+        self
+    }
+
+    /// Do not capture dictionaries larger than the given number of bytes.
+    pub fn with_max_dictionary_size(self, bytes: usize) -> Self {
+        self.lock().max_size = bytes;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Dictionaries> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Add a dictionary to the store, returning its SHA-256 hash.
+    pub fn insert(&self, dictionary: impl Into<Bytes>) -> [u8; 32] {
+        let dictionary = dictionary.into();
+        let hash: Hash = digest(&SHA256, &dictionary)
+            .as_ref()
+            .try_into()
+            .expect("SHA-256 digest is 32 bytes");
+
+        let mut inner = self.lock();
+
+        if inner.entries.insert(hash, dictionary).is_none() {
+            inner.order.push_back(hash);
+        }
+
+        while inner.order.len() > inner.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+
+        hash
+    }
+
+    /// Get the dictionary with the given SHA-256 hash.
+    pub fn get(&self, hash: &[u8; 32]) -> Option<Bytes> {
+        self.lock().entries.get(hash).cloned()
+    }
+
+    /// Number of dictionaries in the store.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Capture the body of the response as a dictionary if it has a
+    /// `use-as-dictionary` header.
+    ///
+    /// The body is observed as it streams to the client and the dictionary is
+    /// added once it has been read to the end.
+    pub fn capture_response(&self, res: Response<Body>) -> Response<Body> {
+        if !res.status().is_success() || !res.headers().contains_key(USE_AS_DICTIONARY) {
+            return res;
+        }
+
+        let capture = Capture {
+            store: self.clone(),
+            encodings: extract_encodings(res.headers()).map(Vec::from).collect(),
+            max_size: self.lock().max_size,
+            data: Vec::new(),
+            oversized: false,
+        };
+
+        res.map(|body| body.tap(capture))
+    }
+}
+
+impl Default for DictionaryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for DictionaryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DictionaryStore")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+struct Capture {
+    store: DictionaryStore,
+    encodings: Vec<Vec<u8>>,
+    max_size: usize,
+    data: Vec<u8>,
+    oversized: bool,
+}
+
+impl BodyObserver for Capture {
+    fn on_data(&mut self, data: &Bytes) {
+        if self.data.len() + data.len() > self.max_size {
+            self.oversized = true;
+            self.data = Vec::new();
+        } else if !self.oversized {
+            self.data.extend_from_slice(data);
+        }
+    }
+
+    fn on_end(&mut self, summary: TapSummary) {
+        if !summary.complete || self.oversized {
+            return;
+        }
+
+        let data = Bytes::from(std::mem::take(&mut self.data));
+
+        if self.encodings.is_empty() {
+            self.store.insert(data);
+            return;
+        }
+
+        // Captured bodies are still encoded, so they are decoded off the
+        // response path.
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let store = self.store.clone();
+        let encodings = std::mem::take(&mut self.encodings);
+        let limits = DecodeLimits::new().with_max_decoded_size(self.max_size as u64);
+
+        handle.spawn(async move {
+            let encodings = encodings.iter().map(Vec::as_slice);
+
+            if let Ok(body) = decode_body(encodings, Body::from(data), &limits, None) {
+                if let Ok(collected) = body.collect().await {
+                    store.insert(collected.to_bytes());
+                }
+            }
+        });
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum DictionaryEncoding {
+    Brotli,
+    Zstd,
+}
+
+impl DictionaryEncoding {
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Self::Brotli => DCB_MAGIC,
+            Self::Zstd => DCZ_MAGIC,
+        }
+    }
+
+    fn header_len(self) -> usize {
+        self.magic().len() + 32
+    }
+}
+
+enum State<R> {
+    Header(R),
+    Decoding(Box<dyn AsyncRead + Send + Sync + Unpin>),
+    Failed,
+}
+
+/// Decoder for `dcb` and `dcz` bodies, which start with the hash of the
+/// dictionary they were compressed with.
+pub(super) struct DictionaryDecoder<R> {
+    encoding: DictionaryEncoding,
+    store: DictionaryStore,
+    header: Vec<u8>,
+    state: State<R>,
+}
+
+impl<R> DictionaryDecoder<R> {
+    pub(super) fn new(encoding: DictionaryEncoding, store: DictionaryStore, reader: R) -> Self {
+        Self {
+            encoding,
+            store,
+            header: Vec::with_capacity(encoding.header_len()),
+            state: State::Header(reader),
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Send + Sync + Unpin + 'static> DictionaryDecoder<R> {
+    fn start(&mut self, reader: R) -> io::Result<Box<dyn AsyncRead + Send + Sync + Unpin>> {
+        let (magic, hash) = self.header.split_at(self.encoding.magic().len());
+
+        if magic != self.encoding.magic() {
+            return Err(invalid_data("invalid dictionary-compressed header"));
+        }
+
+        let hash: Hash = hash.try_into().expect("Header contains a 32 byte hash");
+        let dictionary = self
+            .store
+            .get(&hash)
+            .ok_or_else(|| invalid_data("unknown compression dictionary"))?;
+
+        Ok(match self.encoding {
+            DictionaryEncoding::Brotli => {
+                Box::new(BrotliDictionaryDecoder::new(reader, &dictionary))
+            }
+            DictionaryEncoding::Zstd => Box::new(ZstdDecoder::with_dict(reader, &dictionary)?),
+        })
+    }
+}
+
+impl<R: AsyncBufRead + Send + Sync + Unpin + 'static> AsyncRead for DictionaryDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match &mut this.state {
+                State::Header(reader) => {
+                    let input = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+
+                    if input.is_empty() {
+                        this.state = State::Failed;
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    let len = input
+                        .len()
+                        .min(this.encoding.header_len() - this.header.len());
+                    this.header.extend_from_slice(&input[..len]);
+                    Pin::new(&mut *reader).consume(len);
+
+                    if this.header.len() == this.encoding.header_len() {
+                        let State::Header(reader) =
+                            std::mem::replace(&mut this.state, State::Failed)
+                        else {
+                            unreachable!()
+                        };
+                        this.state = State::Decoding(this.start(reader)?);
+                    }
+                }
+                State::Decoding(decoder) => return Pin::new(decoder).poll_read(cx, buf),
+                State::Failed => return Poll::Ready(Err(invalid_data("decoding failed"))),
+            }
+        }
+    }
+}
+
+/// Brotli decoder that uses a raw dictionary as a prefix to the stream.
+struct BrotliDictionaryDecoder<R> {
+    reader: R,
+    state: Box<BrotliState<StandardAlloc, StandardAlloc, StandardAlloc>>,
+    finished: bool,
+}
+
+impl<R> BrotliDictionaryDecoder<R> {
+    fn new(reader: R, dictionary: &[u8]) -> Self {
+        let mut alloc = StandardAlloc::default();
+        let mut custom_dictionary = Allocator::<u8>::alloc_cell(&mut alloc, dictionary.len());
+        custom_dictionary.slice_mut().copy_from_slice(dictionary);
+
+        Self {
+            reader,
+            state: Box::new(BrotliState::new_with_custom_dictionary(
+                alloc,
+                StandardAlloc::default(),
+                StandardAlloc::default(),
+                custom_dictionary,
+            )),
+            finished: false,
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for BrotliDictionaryDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.finished {
+            let input = ready!(Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+            let eof = input.is_empty();
+
+            let mut available_in = input.len();
+            let mut input_offset = 0;
+            let output = buf.initialize_unfilled();
+            let mut available_out = output.len();
+            let mut output_offset = 0;
+            let mut total_out = 0;
+
+            let result = BrotliDecompressStream(
+                &mut available_in,
+                &mut input_offset,
+                input,
+                &mut available_out,
+                &mut output_offset,
+                output,
+                &mut total_out,
+                &mut this.state,
+            );
+
+            Pin::new(&mut this.reader).consume(input_offset);
+            buf.advance(output_offset);
+
+            match result {
+                BrotliResult::ResultSuccess => this.finished = true,
+                BrotliResult::NeedsMoreInput if eof => {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                BrotliResult::NeedsMoreInput => {}
+                BrotliResult::NeedsMoreOutput => {}
+                BrotliResult::ResultFailure => {
+                    return Poll::Ready(Err(invalid_data("invalid brotli stream")));
+                }
+            }
+
+            if output_offset > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_response;
+    use async_compression::tokio::bufread::ZstdEncoder;
+    use hyper::header::{CONTENT_ENCODING, HeaderValue};
+    use tokio::io::AsyncReadExt;
+
+    const DICTIONARY: &[u8] = b"function hudsucker() { return 'a shared dictionary'; }";
+
+    fn response(encoding: &'static str, body: Vec<u8>, store: &DictionaryStore) -> Response<Body> {
+        let mut res = Response::builder()
+            .header(CONTENT_ENCODING, encoding)
+            .body(Body::from(Bytes::from(body)))
+            .unwrap();
+        res.extensions_mut().insert(store.clone());
+        res
+    }
+
+    async fn to_bytes(res: Response<Body>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn decodes_dcz() {
+        let store = DictionaryStore::new();
+        let hash = store.insert(DICTIONARY);
+
+        let mut body = DCZ_MAGIC.to_vec();
+        body.extend_from_slice(&hash);
+        ZstdEncoder::with_dict(
+            &b"function hudsucker() { return 'hello'; }"[..],
+            async_compression::Level::Default,
+            DICTIONARY,
+        )
+        .unwrap()
+        .read_to_end(&mut body)
+        .await
+        .unwrap();
+
+        let res = decode_response(response("dcz", body, &store)).unwrap();
+
+        assert_eq!(
+            &to_bytes(res).await[..],
+            b"function hudsucker() { return 'hello'; }"
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_dcb() {
+        use brotli::enc::encode::{BrotliEncoderOperation, BrotliEncoderStateStruct};
+
+        let store = DictionaryStore::new();
+        let hash = store.insert(DICTIONARY);
+        let content = b"function hudsucker() { return 'hello'; }";
+
+        let mut encoder = BrotliEncoderStateStruct::new(StandardAlloc::default());
+        encoder.set_custom_dictionary(DICTIONARY.len(), DICTIONARY);
+
+        let mut output = vec![0; 1024];
+        let (mut available_in, mut input_offset) = (content.len(), 0);
+        let (mut available_out, mut output_offset) = (output.len(), 0);
+        assert!(encoder.compress_stream(
+            BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+            &mut available_in,
+            content,
+            &mut input_offset,
+            &mut available_out,
+            &mut output,
+            &mut output_offset,
+            &mut None,
+            &mut |_, _, _, _| (),
+        ));
+        assert!(encoder.is_finished());
+
+        let mut body = DCB_MAGIC.to_vec();
+        body.extend_from_slice(&hash);
+        body.extend_from_slice(&output[..output_offset]);
+
+        let res = decode_response(response("dcb", body, &store)).unwrap();
+
+        assert_eq!(&to_bytes(res).await[..], content);
+    }
+
+    #[tokio::test]
+    async fn fails_on_unknown_dictionary() {
+        let store = DictionaryStore::new();
+
+        let mut body = DCZ_MAGIC.to_vec();
+        body.extend_from_slice(&[0; 32]);
+
+        let res = decode_response(response("dcz", body, &store)).unwrap();
+
+        assert!(res.into_body().collect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn captures_dictionaries() {
+        let store = DictionaryStore::new();
+        let res = Response::builder()
+            .header(
+                USE_AS_DICTIONARY,
+                HeaderValue::from_static("match=\"/app/*\""),
+            )
+            .body(Body::from(DICTIONARY))
+            .unwrap();
+
+        to_bytes(store.capture_response(res)).await;
+
+        let hash: Hash = digest(&SHA256, DICTIONARY).as_ref().try_into().unwrap();
+        assert_eq!(store.get(&hash).as_deref(), Some(DICTIONARY));
+    }
+
+    #[test]
+    fn evicts_oldest_dictionary() {
+        let store = DictionaryStore::new().with_capacity(1);
+        let first = store.insert("first");
+        let second = store.insert("second");
+
+        assert!(store.get(&first).is_none());
+        assert!(store.get(&second).is_some());
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+const MAGIC: [u8; 2] = [0x1f, 0x9d];
+const BLOCK_MODE: u8 = 0x80;
+const BITS_MASK: u8 = 0x1f;
+const INIT_BITS: u8 = 9;
+const CLEAR: u32 = 256;
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decoder for the LZW format produced by the Unix `compress` utility.
+struct Lzw {
+    header: Vec<u8>,
+    max_bits: u8,
+    block_mode: bool,
+    n_bits: u8,
+    max_code: u32,
+    free_ent: u32,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    old_code: Option<u32>,
+    fin_char: u8,
+    acc: u64,
+    acc_bits: u32,
+    group_bits: usize,
+    skip_bytes: usize,
+    stack: Vec<u8>,
+}
+
+impl Lzw {
+    fn new() -> Self {
+        Self {
+            header: Vec::with_capacity(3),
+            max_bits: 0,
+            block_mode: false,
+            n_bits: INIT_BITS,
+            max_code: (1 << INIT_BITS) - 1,
+            free_ent: 0,
+            prefix: Vec::new(),
+            suffix: Vec::new(),
+            old_code: None,
+            fin_char: 0,
+            acc: 0,
+            acc_bits: 0,
+            group_bits: 0,
+            skip_bytes: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    fn max_max_code(&self) -> u32 {
+        1 << self.max_bits
+    }
+
+    fn init(&mut self) -> io::Result<()> {
+        if self.header[..2] != MAGIC {
+            return Err(invalid_data("invalid compress header"));
+        }
+
+        self.max_bits = self.header[2] & BITS_MASK;
+        self.block_mode = self.header[2] & BLOCK_MODE != 0;
+
+        if !(INIT_BITS..=16).contains(&self.max_bits) {
+            return Err(invalid_data("unsupported compress code width"));
+        }
+
+        self.reset_width();
+        self.free_ent = if self.block_mode { CLEAR + 1 } else { CLEAR };
+        self.prefix = vec![0; 1 << self.max_bits];
+        self.suffix = (0..1usize << self.max_bits).map(|i| i as u8).collect();
+        Ok(())
+    }
+
+    fn reset_width(&mut self) {
+        // Like `compress`, this starts at 9 bits even if that is the maximum.
+        self.n_bits = INIT_BITS;
+        self.max_code = (1 << INIT_BITS) - 1;
+    }
+
+    /// Codes are written in groups of eight, and `compress` discards the rest
+    /// of the current group whenever the code width changes.
+    fn skip_to_group_end(&mut self) {
+        let group = usize::from(self.n_bits) * 8;
+        let skip = (group - self.group_bits % group) % group;
+        self.group_bits = 0;
+
+        let skip = skip as u32;
+        if skip <= self.acc_bits {
+            self.acc >>= skip;
+            self.acc_bits -= skip;
+        } else {
+            self.skip_bytes = ((skip - self.acc_bits) / 8) as usize;
+            self.acc = 0;
+            self.acc_bits = 0;
+        }
+    }
+
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for &byte in input {
+            if self.header.len() < 3 {
+                self.header.push(byte);
+                if self.header.len() == 3 {
+                    self.init()?;
+                }
+                continue;
+            }
+
+            if self.skip_bytes > 0 {
+                self.skip_bytes -= 1;
+                continue;
+            }
+
+            self.acc |= u64::from(byte) << self.acc_bits;
+            self.acc_bits += 8;
+
+            while self.skip_bytes == 0 && self.acc_bits >= u32::from(self.n_bits) {
+                if self.free_ent > self.max_code {
+                    self.skip_to_group_end();
+                    self.n_bits += 1;
+                    self.max_code = if self.n_bits == self.max_bits {
+                        self.max_max_code()
+                    } else {
+                        (1 << self.n_bits) - 1
+                    };
+                    continue;
+                }
+
+                let code = (self.acc & ((1 << self.n_bits) - 1)) as u32;
+                self.acc >>= self.n_bits;
+                self.acc_bits -= u32::from(self.n_bits);
+                self.group_bits += usize::from(self.n_bits);
+
+                self.code(code, out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn code(&mut self, code: u32, out: &mut Vec<u8>) -> io::Result<()> {
+        let Some(old_code) = self.old_code else {
+            if code >= CLEAR {
+                return Err(invalid_data("invalid compress code"));
+            }
+            self.fin_char = code as u8;
+            self.old_code = Some(code);
+            out.push(self.fin_char);
+            return Ok(());
+        };
+
+        if code == CLEAR && self.block_mode {
+            self.free_ent = CLEAR;
+            self.skip_to_group_end();
+            self.reset_width();
+            return Ok(());
+        }
+
+        let in_code = code;
+        let mut code = code;
+        self.stack.clear();
+
+        if code >= self.free_ent {
+            if code > self.free_ent {
+                return Err(invalid_data("invalid compress code"));
+            }
+            self.stack.push(self.fin_char);
+            code = old_code;
+        }
+
+        while code >= CLEAR {
+            if self.stack.len() >= self.prefix.len() {
+                return Err(invalid_data("invalid compress code"));
+            }
+            self.stack.push(self.suffix[code as usize]);
+            code = u32::from(self.prefix[code as usize]);
+        }
+
+        self.fin_char = self.suffix[code as usize];
+        self.stack.push(self.fin_char);
+        out.extend(self.stack.iter().rev());
+
+        if self.free_ent < self.max_max_code() {
+            self.prefix[self.free_ent as usize] = old_code as u16;
+            self.suffix[self.free_ent as usize] = self.fin_char;
+            self.free_ent += 1;
+        }
+
+        self.old_code = Some(in_code);
+        Ok(())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        match self.header.len() {
+            0 | 3 => Ok(()),
+            _ => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+pub(super) struct LzwDecoder<R> {
+    reader: R,
+    lzw: Lzw,
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R> LzwDecoder<R> {
+    pub(super) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            lzw: Lzw::new(),
+            output: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for LzwDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.pos < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.pos);
+                buf.put_slice(&this.output[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+
+            this.output.clear();
+            this.pos = 0;
+
+            let input = ready!(Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+
+            if input.is_empty() {
+                this.done = true;
+                this.lzw.finish()?;
+                continue;
+            }
+
+            let len = input.len();
+            this.lzw.decode(input, &mut this.output)?;
+            Pin::new(&mut this.reader).consume(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn decode(input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        LzwDecoder::new(input).read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn decodes_compress_output() {
+        // `printf 'TOBEORNOTTOBEORTOBEORNOT' | compress -c`
+        let input = [
+            0x1f, 0x9d, 0x90, 0x54, 0x9e, 0x08, 0x29, 0xf2, 0x44, 0x8a, 0x93, 0x27, 0x54, 0x02,
+            0x0e, 0x2c, 0xa8, 0x90, 0xa0, 0x41, 0x84,
+        ];
+
+        assert_eq!(decode(&input).await.unwrap(), b"TOBEORNOTTOBEORTOBEORNOT");
+    }
+
+    #[tokio::test]
+    async fn handles_width_changes_and_clear_codes() {
+        // `compress -b 10`, which fills the table and clears it several times.
+        let input = include_bytes!("testdata/words.txt.Z");
+        let expected = include_bytes!("testdata/words.txt");
+
+        assert_eq!(decode(input).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_header() {
+        assert!(decode(&[0x1f, 0x8b, 0x08, 0x00]).await.is_err());
+    }
+}
@@ -0,0 +1,1558 @@
+mod dictionary;
+mod lzw;
+
+pub use dictionary::DictionaryStore;
+
+use crate::{Body, BodyObserver, Error, HttpContext};
+use async_compression::tokio::bufread::{
+    BrotliDecoder,
+    BrotliEncoder,
+    DeflateDecoder,
+    GzipDecoder,
+    GzipEncoder,
+    ZlibDecoder,
+    ZlibEncoder,
+    ZstdDecoder,
+    ZstdEncoder,
+};
+use bstr::ByteSlice;
+use dictionary::{DictionaryDecoder, DictionaryEncoding};
+use futures::Stream;
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::{
+        ACCEPT_ENCODING,
+        CONTENT_ENCODING,
+        CONTENT_LENGTH,
+        CONTENT_RANGE,
+        CONTENT_TYPE,
+        ETAG,
+        HeaderMap,
+        HeaderValue,
+        VARY,
+    },
+};
+use lzw::LzwDecoder;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+        PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader, ReadBuf};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Content codings that can be applied with [`encode_request`] and
+/// [`encode_response`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Encoding {
+    /// `gzip`
+    Gzip,
+    /// `deflate`
+    Deflate,
+    /// `br`
+    Brotli,
+    /// `zstd`
+    Zstd,
+}
+
+impl Encoding {
+    /// Parse the name of a content coding, as found in `content-encoding` and
+    /// `accept-encoding` headers.
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        Some(match name.trim() {
+            b"gzip" | b"x-gzip" => Self::Gzip,
+            b"deflate" => Self::Deflate,
+            b"br" => Self::Brotli,
+            b"zstd" => Self::Zstd,
+            _ => return None,
+        })
+    }
+
+    /// The name of the content coding.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Encodings removed by [`decode_request`] or [`decode_response`], in the
+/// order they were originally applied.
+#[derive(Clone, Debug)]
+struct DecodedEncodings(Vec<Encoding>);
+
+/// A body that is read through an [`IoStream`], shared so that its trailers
+/// can be sent after the transformed body, even if the transformation stopped
+/// reading before the end.
+#[derive(Clone)]
+struct Source(Arc<Mutex<SourceState>>);
+
+struct SourceState {
+    body: Body,
+    trailers: Option<HeaderMap>,
+    done: bool,
+}
+
+impl Source {
+    fn new(body: Body) -> Self {
+        Self(Arc::new(Mutex::new(SourceState {
+            body,
+            trailers: None,
+            done: false,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SourceState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn poll_data(&self, cx: &mut Context) -> Poll<Option<Result<Bytes, Error>>> {
+        let mut state = self.lock();
+
+        while !state.done {
+            match futures::ready!(Pin::new(&mut state.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(buf) => return Poll::Ready(Some(Ok(buf))),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            state.trailers = Some(trailers);
+                            state.done = true;
+                        }
+                    }
+                },
+                Some(Err(err)) => {
+                    state.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => state.done = true,
+            }
+        }
+
+        Poll::Ready(None)
+    }
+
+    /// Read the rest of the body for its trailers, discarding any data that
+    /// was not read.
+    fn poll_trailers(&self, cx: &mut Context) -> Poll<Result<Option<HeaderMap>, Error>> {
+        loop {
+            match futures::ready!(self.poll_data(cx)) {
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Ok(self.lock().trailers.take())),
+            }
+        }
+    }
+
+    /// Take back the body of a source that has not been read from.
+    fn into_body(self) -> Body {
+        std::mem::replace(&mut self.lock().body, Body::empty())
+    }
+
+    /// Observe the frames of the body of a source that has not been read from.
+    fn tap(&self, observer: impl BodyObserver) {
+        let mut state = self.lock();
+        let body = std::mem::replace(&mut state.body, Body::empty());
+        state.body = body.tap(observer);
+    }
+
+    /// Send the trailers of the source at the end of a transformed body.
+    fn restore(self, body: Body) -> Body {
+        Body::from(BoxBody::new(Restored {
+            body,
+            source: self,
+            ended: false,
+        }))
+    }
+}
+
+struct IoStream(Source);
+
+impl Stream for IoStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match futures::ready!(self.0.poll_data(cx)) {
+            Some(Ok(buf)) => Some(Ok(buf)),
+            Some(Err(Error::Io(err))) => Some(Err(err)),
+            Some(Err(err)) => Some(Err(io::Error::other(err))),
+            None => None,
+        })
+    }
+}
+
+#[cfg(feature = "payload")]
+/// The data frames of a [`Source`].
+struct DataStream(Source);
+
+#[cfg(feature = "payload")]
+impl Stream for DataStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_data(cx)
+    }
+}
+
+#[cfg(feature = "payload")]
+/// The trailers of a body split by [`split_trailers`].
+pub(crate) struct Trailers(Source);
+
+#[cfg(feature = "payload")]
+impl Trailers {
+    /// Send the trailers at the end of a transformed body.
+    pub(crate) fn restore(self, body: Body) -> Body {
+        self.0.restore(body)
+    }
+}
+
+#[cfg(feature = "payload")]
+/// Split a body into its data frames and its trailers, so that it can be
+/// passed through a transformation that only handles data.
+pub(crate) fn split_trailers(body: Body) -> (Body, Trailers) {
+    let source = Source::new(body);
+    (
+        Body::from_stream(DataStream(source.clone())),
+        Trailers(source),
+    )
+}
+
+/// A transformed body, followed by the trailers of its [`Source`].
+struct Restored {
+    body: Body,
+    source: Source,
+    ended: bool,
+}
+
+impl HttpBody for Restored {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        if !this.ended {
+            match futures::ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(frame) => return Poll::Ready(Some(frame)),
+                None => this.ended = true,
+            }
+        }
+
+        Poll::Ready(
+            futures::ready!(this.source.poll_trailers(cx))
+                .transpose()
+                .map(|trailers| trailers.map(Frame::trailers)),
+        )
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Counts the bytes of an encoded body, for [`DecodeLimits`].
+struct EncodedSize(Arc<AtomicU64>);
+
+impl BodyObserver for EncodedSize {
+    fn on_data(&mut self, data: &Bytes) {
+        self.0.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Decoder for `deflate`, which some servers send as a raw deflate stream
+/// instead of the zlib format it is defined as.
+enum DeflateDecoderAuto<R> {
+    Detecting(Option<R>),
+    Decoding(Box<dyn AsyncRead + Send + Sync + Unpin>),
+}
+
+impl<R: AsyncBufRead + Send + Sync + Unpin + 'static> AsyncRead for DeflateDecoderAuto<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match this {
+                Self::Detecting(reader) => {
+                    let input = futures::ready!(
+                        Pin::new(reader.as_mut().expect("Reader taken")).poll_fill_buf(cx)
+                    )?;
+
+                    // A zlib header is a deflate method and window size, with a
+                    // checksum making the first two bytes a multiple of 31.
+                    let zlib = match input {
+                        [cmf, flg, ..] => {
+                            cmf & 0x0f == 8
+                                && cmf >> 4 <= 7
+                                && u16::from_be_bytes([*cmf, *flg]) % 31 == 0
+                        }
+                        _ => true,
+                    };
+
+                    let reader = reader.take().expect("Reader taken");
+                    *this = Self::Decoding(if zlib {
+                        Box::new(ZlibDecoder::new(reader))
+                    } else {
+                        Box::new(DeflateDecoder::new(reader))
+                    });
+                }
+                Self::Decoding(decoder) => return Pin::new(decoder).poll_read(cx, buf),
+            }
+        }
+    }
+}
+
+fn decode(
+    encoding: &[u8],
+    reader: impl AsyncBufRead + Send + Sync + Unpin + 'static,
+    dictionaries: Option<&DictionaryStore>,
+) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin>, Error> {
+    Ok(match encoding {
+        b"gzip" | b"x-gzip" => Box::new(GzipDecoder::new(reader)),
+        b"deflate" => Box::new(DeflateDecoderAuto::Detecting(Some(reader))),
+        b"br" => Box::new(BrotliDecoder::new(reader)),
+        b"zstd" => Box::new(ZstdDecoder::new(reader)),
+        b"compress" | b"x-compress" => Box::new(LzwDecoder::new(reader)),
+        b"dcb" => Box::new(DictionaryDecoder::new(
+            DictionaryEncoding::Brotli,
+            dictionaries.ok_or(Error::Decode)?.clone(),
+            reader,
+        )),
+        b"dcz" => Box::new(DictionaryDecoder::new(
+            DictionaryEncoding::Zstd,
+            dictionaries.ok_or(Error::Decode)?.clone(),
+            reader,
+        )),
+        _ => Err(Error::Decode)?,
+    })
+}
+
+enum Decoder {
+    Body(Source),
+    Decoder(Box<dyn AsyncRead + Send + Sync + Unpin>),
+}
+
+impl Decoder {
+    pub fn decode(
+        self,
+        encoding: &[u8],
+        dictionaries: Option<&DictionaryStore>,
+    ) -> Result<Self, Error> {
+        if encoding == b"identity" {
+            return Ok(self);
+        }
+
+        Ok(Self::Decoder(match self {
+            Self::Body(source) => {
+                decode(encoding, StreamReader::new(IoStream(source)), dictionaries)
+            }
+            Self::Decoder(decoder) => decode(encoding, BufReader::new(decoder), dictionaries),
+        }?))
+    }
+}
+
+pub(crate) fn extract_encodings(headers: &HeaderMap<HeaderValue>) -> impl Iterator<Item = &[u8]> {
+    headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .rev()
+        .flat_map(|val| val.as_bytes().rsplit_str(b",").map(|v| v.trim()))
+}
+
+fn decoded_encodings(headers: &HeaderMap<HeaderValue>) -> DecodedEncodings {
+    let mut encodings = extract_encodings(headers)
+        .filter_map(Encoding::from_name)
+        .collect::<Vec<_>>();
+    encodings.reverse();
+    DecodedEncodings(encodings)
+}
+
+/// Limits applied while decoding a body, to protect against decompression
+/// bombs.
+///
+/// No limits are applied by default.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::DecodeLimits;
+///
+/// let limits = DecodeLimits::new()
+///     .with_max_decoded_size(16 * 1024 * 1024)
+///     .with_max_ratio(100)
+///     .with_max_layers(2);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeLimits {
+    max_decoded_size: Option<u64>,
+    max_ratio: Option<u64>,
+    max_layers: Option<usize>,
+}
+
+impl DecodeLimits {
+    /// Create limits that do not restrict decoding.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail once the decoded body exceeds the given number of bytes.
+    pub fn with_max_decoded_size(mut self, bytes: u64) -> Self {
+        self.max_decoded_size = Some(bytes);
+        self
+    }
+
+    /// Fail once the decoded body is more than `ratio` times larger than the
+    /// encoded body read so far.
+    ///
+    /// The ratio is only checked after [`DecodeLimits::RATIO_THRESHOLD`] bytes
+    /// have been decoded, as small bodies routinely compress very well.
+    pub fn with_max_ratio(mut self, ratio: u64) -> Self {
+        self.max_ratio = Some(ratio);
+        self
+    }
+
+    /// Fail if more than the given number of `content-encoding` layers are
+    /// applied to the body.
+    pub fn with_max_layers(mut self, layers: usize) -> Self {
+        self.max_layers = Some(layers);
+        self
+    }
+
+    /// Number of decoded bytes after which the compression ratio is checked.
+    pub const RATIO_THRESHOLD: u64 = 64 * 1024;
+
+    fn check(&self, decoded: u64, encoded: u64) -> Result<(), DecodeLimitError> {
+        if let Some(limit) = self.max_decoded_size {
+            if decoded > limit {
+                return Err(DecodeLimitError::DecodedSize(limit));
+            }
+        }
+
+        if let Some(limit) = self.max_ratio {
+            if decoded > Self::RATIO_THRESHOLD && decoded > encoded.saturating_mul(limit) {
+                return Err(DecodeLimitError::Ratio(limit));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The [`DecodeLimits`] that were exceeded while decoding a body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum DecodeLimitError {
+    /// The decoded body was larger than the given number of bytes.
+    #[error("decoded body exceeded {0} bytes")]
+    DecodedSize(u64),
+    /// The decoded body was more than the given number of times larger than
+    /// the encoded body.
+    #[error("decoded body exceeded a compression ratio of {0}")]
+    Ratio(u64),
+    /// More than the given number of encodings were applied to the body.
+    #[error("body has more than {0} content encodings")]
+    Layers(usize),
+}
+
+struct LimitedStream<S> {
+    inner: S,
+    limits: DecodeLimits,
+    encoded: Arc<AtomicU64>,
+    decoded: u64,
+    tripped: bool,
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for LimitedStream<S> {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.tripped {
+            return Poll::Ready(None);
+        }
+
+        let buf = match futures::ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(buf)) => buf,
+            Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+            None => return Poll::Ready(None),
+        };
+
+        self.decoded += buf.len() as u64;
+        let encoded = self.encoded.load(Ordering::Relaxed);
+
+        match self.limits.check(self.decoded, encoded) {
+            Ok(()) => Poll::Ready(Some(Ok(buf))),
+            Err(err) => {
+                self.tripped = true;
+                Poll::Ready(Some(Err(err.into())))
+            }
+        }
+    }
+}
+
+pub(crate) fn decode_body<'a>(
+    encodings: impl IntoIterator<Item = &'a [u8]>,
+    mut body: Body,
+    limits: &DecodeLimits,
+    dictionaries: Option<&DictionaryStore>,
+) -> Result<Body, Error> {
+    decode_body_in_place(encodings, &mut body, limits, dictionaries)?;
+    Ok(body)
+}
+
+/// Decode a body in place, leaving it untouched if it cannot be decoded.
+pub(crate) fn decode_body_in_place<'a>(
+    encodings: impl IntoIterator<Item = &'a [u8]>,
+    body: &mut Body,
+    limits: &DecodeLimits,
+    dictionaries: Option<&DictionaryStore>,
+) -> Result<(), Error> {
+    let encodings = encodings
+        .into_iter()
+        .filter(|encoding| *encoding != b"identity")
+        .collect::<Vec<_>>();
+
+    if let Some(limit) = limits.max_layers {
+        if encodings.len() > limit {
+            return Err(DecodeLimitError::Layers(limit).into());
+        }
+    }
+
+    if encodings.is_empty() {
+        return Ok(());
+    }
+
+    let limited = limits.max_decoded_size.is_some() || limits.max_ratio.is_some();
+    let encoded = Arc::new(AtomicU64::new(0));
+    let source = Source::new(std::mem::replace(body, Body::empty()));
+
+    let mut decoder = Decoder::Body(source.clone());
+
+    for encoding in encodings {
+        decoder = match decoder.decode(encoding, dictionaries) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                *body = source.into_body();
+                return Err(e);
+            }
+        };
+    }
+
+    if limited {
+        source.tap(EncodedSize(Arc::clone(&encoded)));
+    }
+
+    let decoded = match decoder {
+        Decoder::Body(source) => Body::from_stream(IoStream(source)),
+        Decoder::Decoder(decoder) if limited => Body::from_stream(LimitedStream {
+            inner: ReaderStream::new(decoder),
+            limits: *limits,
+            encoded,
+            decoded: 0,
+            tripped: false,
+        }),
+        Decoder::Decoder(decoder) => Body::from_stream(ReaderStream::new(decoder)),
+    };
+
+    *body = source.restore(decoded);
+    Ok(())
+}
+
+/// Decode the body of a request.
+///
+/// # Errors
+///
+/// This will return an error if one of the values specified in the
+/// `content-encoding` header is not supported.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Body,
+///     HttpContext,
+///     HttpHandler,
+///     RequestOrResponse,
+///     decode_request,
+///     hyper::Request,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         let req = decode_request(req).unwrap();
+///
+///         // Do something with the request
+///
+///         RequestOrResponse::Request(req)
+///     }
+/// }
+/// ```
+pub fn decode_request(req: Request<Body>) -> Result<Request<Body>, Error> {
+    decode_request_with_limits(req, &DecodeLimits::default())
+}
+
+/// Decode the body of a request, failing if the given limits are exceeded.
+///
+/// Limits on the size and ratio of the decoded body are checked as the body is
+/// read, so they surface as an [`Error::DecodeLimit`] from the body rather than
+/// from this function.
+///
+/// # Errors
+///
+/// This will return an error if one of the values specified in the
+/// `content-encoding` header is not supported, or if more encodings are applied
+/// than the limits allow.
+pub fn decode_request_with_limits(
+    mut req: Request<Body>,
+    limits: &DecodeLimits,
+) -> Result<Request<Body>, Error> {
+    if !req.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(req);
+    }
+
+    if let Some(val) = req.headers_mut().remove(CONTENT_LENGTH) {
+        if val == "0" {
+            return Ok(req);
+        }
+    }
+
+    let (mut parts, body) = req.into_parts();
+
+    let body = {
+        let encodings = extract_encodings(&parts.headers);
+        let dictionaries = parts.extensions.get::<DictionaryStore>();
+        decode_body(encodings, body, limits, dictionaries)?
+    };
+
+    let decoded = decoded_encodings(&parts.headers);
+    parts.extensions.insert(decoded);
+    parts.headers.remove(CONTENT_ENCODING);
+
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Decode the body of a response.
+///
+/// # Errors
+///
+/// This will return an error if one of the values specified in the
+/// `content-encoding` header is not supported.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, HttpContext, HttpHandler, decode_response, hyper::Response};
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         res: Response<Body>,
+///     ) -> Response<Body> {
+///         let res = decode_response(res).unwrap();
+///
+///         // Do something with the response
+///
+///         res
+///     }
+/// }
+/// ```
+pub fn decode_response(res: Response<Body>) -> Result<Response<Body>, Error> {
+    decode_response_with_limits(res, &DecodeLimits::default())
+}
+
+/// Decode the body of a response, failing if the given limits are exceeded.
+///
+/// Limits on the size and ratio of the decoded body are checked as the body is
+/// read, so they surface as an [`Error::DecodeLimit`] from the body rather than
+/// from this function.
+///
+/// # Errors
+///
+/// This will return an error if one of the values specified in the
+/// `content-encoding` header is not supported, or if more encodings are applied
+/// than the limits allow.
+pub fn decode_response_with_limits(
+    mut res: Response<Body>,
+    limits: &DecodeLimits,
+) -> Result<Response<Body>, Error> {
+    if !res.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(res);
+    }
+
+    if let Some(val) = res.headers_mut().remove(CONTENT_LENGTH) {
+        if val == "0" {
+            return Ok(res);
+        }
+    }
+
+    let (mut parts, body) = res.into_parts();
+
+    let body = {
+        let encodings = extract_encodings(&parts.headers);
+        let dictionaries = parts.extensions.get::<DictionaryStore>();
+        decode_body(encodings, body, limits, dictionaries)?
+    };
+
+    let decoded = decoded_encodings(&parts.headers);
+    parts.extensions.insert(decoded);
+    parts.headers.remove(CONTENT_ENCODING);
+
+    Ok(Response::from_parts(parts, body))
+}
+
+fn encode_body(encoding: Encoding, body: Body) -> Body {
+    let source = Source::new(body);
+    let reader = StreamReader::new(IoStream(source.clone()));
+
+    source.restore(match encoding {
+        Encoding::Gzip => Body::from_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::from_stream(ReaderStream::new(ZlibEncoder::new(reader))),
+        Encoding::Brotli => Body::from_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Zstd => Body::from_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+    })
+}
+
+fn encode_parts(headers: &mut HeaderMap<HeaderValue>, encoding: Encoding) {
+    headers.remove(CONTENT_LENGTH);
+    headers.append(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+}
+
+/// Encode the body of a request.
+///
+/// The encoding is added to the `content-encoding` header and the
+/// `content-length` header is removed, as the encoded length is not known in
+/// advance.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, Encoding, encode_request, hyper::Request};
+///
+/// let req = Request::new(Body::from("hello, world"));
+/// let req = encode_request(req, Encoding::Gzip);
+///
+/// assert_eq!(req.headers()["content-encoding"], "gzip");
+/// ```
+pub fn encode_request(req: Request<Body>, encoding: Encoding) -> Request<Body> {
+    let (mut parts, body) = req.into_parts();
+    encode_parts(&mut parts.headers, encoding);
+    Request::from_parts(parts, encode_body(encoding, body))
+}
+
+/// Encode the body of a response.
+///
+/// The encoding is added to the `content-encoding` header and the
+/// `content-length` header is removed, as the encoded length is not known in
+/// advance.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, Encoding, HttpContext, HttpHandler, encode_response, hyper::Response};
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         res: Response<Body>,
+///     ) -> Response<Body> {
+///         encode_response(res, Encoding::Brotli)
+///     }
+/// }
+/// ```
+pub fn encode_response(res: Response<Body>, encoding: Encoding) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    encode_parts(&mut parts.headers, encoding);
+    Response::from_parts(parts, encode_body(encoding, body))
+}
+
+/// Re-apply the encodings that were removed by [`decode_request`].
+///
+/// Requests that were not decoded are returned unchanged.
+pub fn reencode_request(mut req: Request<Body>) -> Request<Body> {
+    let Some(DecodedEncodings(encodings)) = req.extensions_mut().remove() else {
+        return req;
+    };
+
+    for encoding in encodings {
+        req = encode_request(req, encoding);
+    }
+
+    req
+}
+
+/// Re-apply the encodings that were removed by [`decode_response`].
+///
+/// Responses that were not decoded are returned unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Body,
+///     HttpContext,
+///     HttpHandler,
+///     decode_response,
+///     hyper::Response,
+///     reencode_response,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         res: Response<Body>,
+///     ) -> Response<Body> {
+///         let res = decode_response(res).unwrap();
+///
+///         // Modify the decoded response
+///
+///         reencode_response(res)
+///     }
+/// }
+/// ```
+pub fn reencode_response(mut res: Response<Body>) -> Response<Body> {
+    let Some(DecodedEncodings(encodings)) = res.extensions_mut().remove() else {
+        return res;
+    };
+
+    for encoding in encodings {
+        res = encode_response(res, encoding);
+    }
+
+    res
+}
+
+/// Parse `accept-encoding` values into the supported encodings the client
+/// accepts, most preferred first.
+fn accepted_encodings<'a>(values: impl IntoIterator<Item = &'a [u8]>) -> Vec<Encoding> {
+    let mut explicit = Vec::new();
+    let mut wildcard = None;
+
+    for coding in values.into_iter().flat_map(|value| value.split_str(b",")) {
+        let mut params = coding.split_str(b";");
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix(b"q="))
+            .find_map(|q| q.to_str().ok()?.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name == b"*" {
+            wildcard = Some(q);
+        } else if let Some(encoding) = Encoding::from_name(&name.to_ascii_lowercase()) {
+            explicit.push((encoding, q));
+        }
+    }
+
+    if let Some(q) = wildcard {
+        for encoding in [
+            Encoding::Brotli,
+            Encoding::Zstd,
+            Encoding::Gzip,
+            Encoding::Deflate,
+        ] {
+            if !explicit.iter().any(|(e, _)| *e == encoding) {
+                explicit.push((encoding, q));
+            }
+        }
+    }
+
+    explicit.retain(|(_, q)| *q > 0.0);
+    explicit.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    explicit.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Rewrites the `accept-encoding` header of requests before they are forwarded
+/// upstream.
+///
+/// This lets handlers work with uncompressed responses without having to
+/// decode them. The client's original header is still available through
+/// [`HttpContext::accept_encoding`], and with recompression enabled the
+/// response is compressed with the client's preferred encoding after it has
+/// been handled.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{AcceptEncodingPolicy, Encoding};
+///
+/// // Only ever receive uncompressed responses, but compress them again for
+/// // clients that support it.
+/// let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+///
+/// // Allow gzip if the client accepts it.
+/// let policy = AcceptEncodingPolicy::restricted([Encoding::Gzip]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AcceptEncodingPolicy {
+    allowed: Vec<Encoding>,
+    recompress: bool,
+}
+
+impl AcceptEncodingPolicy {
+    /// Only request uncompressed responses from upstream servers.
+    pub fn identity() -> Self {
+        Self {
+            allowed: Vec::new(),
+            recompress: false,
+        }
+    }
+
+    /// Only request the given encodings from upstream servers, limited to
+    /// those the client also accepts.
+    pub fn restricted(encodings: impl IntoIterator<Item = Encoding>) -> Self {
+        Self {
+            allowed: encodings.into_iter().collect(),
+            recompress: false,
+        }
+    }
+
+    /// Compress uncompressed responses with the client's preferred encoding
+    /// before they are sent to the client.
+    ///
+    /// Partial responses are left uncompressed. Otherwise `accept-encoding` is
+    /// added to the `vary` header, and a strong `etag` of a compressed
+    /// response is marked as weak.
+    pub fn with_recompression(mut self, recompress: bool) -> Self {
+        self.recompress = recompress;
+        self
+    }
+
+    pub(crate) fn rewrite_request<T>(&self, mut req: Request<T>) -> Request<T> {
+        let accepted = accepted_encodings(
+            req.headers()
+                .get_all(ACCEPT_ENCODING)
+                .iter()
+                .map(HeaderValue::as_bytes),
+        );
+
+        let encodings = accepted
+            .into_iter()
+            .filter(|encoding| self.allowed.contains(encoding))
+            .map(|encoding| encoding.as_str())
+            .collect::<Vec<_>>();
+
+        let value = if encodings.is_empty() {
+            HeaderValue::from_static("identity")
+        } else {
+            HeaderValue::from_str(&encodings.join(", ")).expect("Invalid header value")
+        };
+
+        req.headers_mut().insert(ACCEPT_ENCODING, value);
+        req
+    }
+
+    pub(crate) fn recompress_response(
+        &self,
+        ctx: &HttpContext,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        if !self.recompress
+            || ctx.method == Method::HEAD
+            || res.status().is_informational()
+            || res.status() == StatusCode::NO_CONTENT
+            || res.status() == StatusCode::NOT_MODIFIED
+            // Ranges refer to the unencoded bytes, so they can't be encoded.
+            || res.status() == StatusCode::PARTIAL_CONTENT
+            || res.headers().contains_key(CONTENT_RANGE)
+            || res.headers().contains_key(CONTENT_ENCODING)
+            // Encoders buffer their output, which would stall event streams.
+            || res
+                .headers()
+                .get(CONTENT_TYPE)
+                .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"))
+        {
+            return res;
+        }
+
+        let mut res = res;
+        add_vary(res.headers_mut());
+
+        let preferred = ctx
+            .accept_encoding
+            .as_deref()
+            .and_then(|value| accepted_encodings([value.as_bytes()]).into_iter().next());
+
+        match preferred {
+            Some(encoding) => {
+                weaken_etag(res.headers_mut());
+                encode_response(res, encoding)
+            }
+            None => res,
+        }
+    }
+}
+
+/// Add `accept-encoding` to the `vary` header, since the response depends on
+/// the encodings the client accepts.
+fn add_vary(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&b| b == b','))
+        .map(<[u8]>::trim)
+        .any(|name| name == b"*" || name.eq_ignore_ascii_case(b"accept-encoding"));
+
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// Mark a strong `etag` as weak, since the encoded body is no longer
+/// byte-for-byte the same as the one it was computed for.
+fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let weak = [b"W/", etag.as_bytes()].concat();
+            match HeaderValue::from_bytes(&weak) {
+                Ok(weak) => {
+                    headers.insert(ETAG, weak);
+                }
+                Err(_) => {
+                    headers.remove(ETAG);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod extract_encodings {
+        use super::*;
+
+        #[test]
+        fn no_headers() {
+            let headers = HeaderMap::new();
+
+            assert_eq!(extract_encodings(&headers).count(), 0);
+        }
+
+        #[test]
+        fn single_header_single_value() {
+            let mut headers = HeaderMap::new();
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+            assert_eq!(
+                extract_encodings(&headers).collect::<Vec<_>>(),
+                vec![b"gzip"]
+            );
+        }
+
+        #[test]
+        fn single_header_multiple_values() {
+            let mut headers = HeaderMap::new();
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+
+            assert_eq!(
+                extract_encodings(&headers).collect::<Vec<_>>(),
+                vec![&b"deflate"[..], &b"gzip"[..]]
+            );
+        }
+
+        #[test]
+        fn multiple_headers_single_value() {
+            let mut headers = HeaderMap::new();
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("deflate"));
+
+            assert_eq!(
+                extract_encodings(&headers).collect::<Vec<_>>(),
+                vec![&b"deflate"[..], &b"gzip"[..]]
+            );
+        }
+
+        #[test]
+        fn multiple_headers_multiple_values() {
+            let mut headers = HeaderMap::new();
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+            headers.append(CONTENT_ENCODING, HeaderValue::from_static("br, zstd"));
+
+            assert_eq!(
+                extract_encodings(&headers).collect::<Vec<_>>(),
+                vec![&b"zstd"[..], &b"br"[..], &b"deflate"[..], &b"gzip"[..]]
+            );
+        }
+    }
+
+    async fn to_bytes<H: HttpBody>(body: H) -> Bytes
+    where
+        H::Error: std::fmt::Debug,
+    {
+        use http_body_util::BodyExt;
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    mod decode_body {
+        use super::*;
+        use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+
+        #[tokio::test]
+        async fn no_encodings() {
+            let content = "hello, world";
+            let body = Body::from(content);
+
+            assert_eq!(
+                &to_bytes(decode_body(vec![], body, &DecodeLimits::default(), None).unwrap()).await
+                    [..],
+                content.as_bytes()
+            );
+        }
+
+        #[tokio::test]
+        async fn identity_encoding() {
+            let content = "hello, world";
+            let body = Body::from(content);
+
+            assert_eq!(
+                &to_bytes(
+                    decode_body(vec![&b"identity"[..]], body, &DecodeLimits::default(), None)
+                        .unwrap()
+                )
+                .await[..],
+                content.as_bytes()
+            );
+        }
+
+        #[tokio::test]
+        async fn single_encoding() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let body = Body::from_stream(ReaderStream::new(encoder));
+
+            assert_eq!(
+                &to_bytes(
+                    decode_body(vec![&b"gzip"[..]], body, &DecodeLimits::default(), None).unwrap()
+                )
+                .await[..],
+                content
+            );
+        }
+
+        #[tokio::test]
+        async fn multiple_encodings() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let encoder = BrotliEncoder::new(BufReader::new(encoder));
+            let body = Body::from_stream(ReaderStream::new(encoder));
+
+            assert_eq!(
+                &to_bytes(
+                    decode_body(
+                        vec![&b"br"[..], &b"gzip"[..]],
+                        body,
+                        &DecodeLimits::default(),
+                        None,
+                    )
+                    .unwrap()
+                )
+                .await[..],
+                content
+            );
+        }
+
+        #[tokio::test]
+        async fn raw_deflate() {
+            use async_compression::tokio::bufread::{DeflateEncoder, ZlibEncoder};
+
+            let content = b"hello, world";
+
+            let body = Body::from_stream(ReaderStream::new(DeflateEncoder::new(&content[..])));
+            let decoded = decode_body(vec![&b"deflate"[..]], body, &DecodeLimits::default(), None);
+            assert_eq!(&to_bytes(decoded.unwrap()).await[..], content);
+
+            let body = Body::from_stream(ReaderStream::new(ZlibEncoder::new(&content[..])));
+            let decoded = decode_body(vec![&b"deflate"[..]], body, &DecodeLimits::default(), None);
+            assert_eq!(&to_bytes(decoded.unwrap()).await[..], content);
+        }
+
+        #[test]
+        fn dictionary_encoding_without_store() {
+            let body = Body::empty();
+
+            assert!(decode_body(vec![&b"dcb"[..]], body, &DecodeLimits::default(), None).is_err());
+        }
+
+        #[test]
+        fn invalid_encoding() {
+            let body = Body::empty();
+
+            assert!(
+                decode_body(vec![&b"invalid"[..]], body, &DecodeLimits::default(), None).is_err()
+            );
+        }
+
+        fn bomb() -> Body {
+            let content = vec![0; 1024 * 1024];
+            let encoder = GzipEncoder::new(std::io::Cursor::new(content));
+            Body::from_stream(ReaderStream::new(encoder))
+        }
+
+        #[tokio::test]
+        async fn rejects_too_many_layers() {
+            let limits = DecodeLimits::new().with_max_layers(1);
+
+            assert!(matches!(
+                decode_body(vec![&b"br"[..], &b"gzip"[..]], Body::empty(), &limits, None),
+                Err(Error::DecodeLimit(DecodeLimitError::Layers(1)))
+            ));
+        }
+
+        #[tokio::test]
+        async fn rejects_oversized_body() {
+            use http_body_util::BodyExt;
+
+            let limits = DecodeLimits::new().with_max_decoded_size(512 * 1024);
+            let body = decode_body(vec![&b"gzip"[..]], bomb(), &limits, None).unwrap();
+
+            assert!(matches!(
+                body.collect().await,
+                Err(Error::DecodeLimit(DecodeLimitError::DecodedSize(_)))
+            ));
+        }
+
+        #[tokio::test]
+        async fn rejects_excessive_ratio() {
+            use http_body_util::BodyExt;
+
+            let limits = DecodeLimits::new().with_max_ratio(100);
+            let body = decode_body(vec![&b"gzip"[..]], bomb(), &limits, None).unwrap();
+
+            assert!(matches!(
+                body.collect().await,
+                Err(Error::DecodeLimit(DecodeLimitError::Ratio(100)))
+            ));
+        }
+
+        #[tokio::test]
+        async fn allows_body_within_limits() {
+            let limits = DecodeLimits::new()
+                .with_max_decoded_size(2 * 1024 * 1024)
+                .with_max_ratio(2000)
+                .with_max_layers(1);
+            let body = decode_body(vec![&b"gzip"[..]], bomb(), &limits, None).unwrap();
+
+            assert_eq!(to_bytes(body).await.len(), 1024 * 1024);
+        }
+    }
+
+    mod decode_request {
+        use super::*;
+        use async_compression::tokio::bufread::GzipEncoder;
+
+        #[tokio::test]
+        async fn decodes_request() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let req = Request::builder()
+                .header(CONTENT_LENGTH, 123)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from_stream(ReaderStream::new(encoder)))
+                .unwrap();
+
+            let req = decode_request(req).unwrap();
+
+            assert!(!req.headers().contains_key(CONTENT_LENGTH));
+            assert!(!req.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(&to_bytes(req.into_body()).await[..], content);
+        }
+    }
+
+    mod encode_body {
+        use super::*;
+
+        #[tokio::test]
+        async fn round_trips() {
+            let content = b"hello, world";
+
+            for encoding in [
+                Encoding::Gzip,
+                Encoding::Deflate,
+                Encoding::Brotli,
+                Encoding::Zstd,
+            ] {
+                let body = encode_body(encoding, Body::from(&content[..]));
+                let body = decode_body(
+                    vec![encoding.as_str().as_bytes()],
+                    body,
+                    &DecodeLimits::default(),
+                    None,
+                )
+                .unwrap();
+
+                assert_eq!(&to_bytes(body).await[..], content);
+            }
+        }
+
+        #[tokio::test]
+        async fn keeps_trailers() {
+            use http_body_util::BodyExt;
+
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+
+            for limits in [
+                DecodeLimits::default(),
+                DecodeLimits::new().with_max_decoded_size(1024),
+            ] {
+                let body = Body::from("hello, world").with_trailers(trailers.clone());
+                let body = encode_body(Encoding::Gzip, body);
+                let body = decode_body(vec![&b"gzip"[..]], body, &limits, None).unwrap();
+
+                let collected = body.collect().await.unwrap();
+                assert_eq!(collected.trailers(), Some(&trailers));
+                assert_eq!(&collected.to_bytes()[..], b"hello, world");
+            }
+        }
+    }
+
+    mod encode_request {
+        use super::*;
+
+        #[tokio::test]
+        async fn encodes_request() {
+            let content = b"hello, world";
+            let req = Request::builder()
+                .header(CONTENT_LENGTH, 12)
+                .body(Body::from(&content[..]))
+                .unwrap();
+
+            let req = encode_request(req, Encoding::Gzip);
+
+            assert!(!req.headers().contains_key(CONTENT_LENGTH));
+            assert_eq!(req.headers()[CONTENT_ENCODING], "gzip");
+
+            let req = decode_request(req).unwrap();
+            assert_eq!(&to_bytes(req.into_body()).await[..], content);
+        }
+    }
+
+    mod reencode_response {
+        use super::*;
+
+        #[tokio::test]
+        async fn restores_original_encodings() {
+            let content = b"hello, world";
+            let res = Response::new(Body::from(&content[..]));
+            let res = encode_response(encode_response(res, Encoding::Gzip), Encoding::Brotli);
+
+            let res = reencode_response(decode_response(res).unwrap());
+
+            assert_eq!(
+                extract_encodings(res.headers()).collect::<Vec<_>>(),
+                vec![&b"br"[..], &b"gzip"[..]]
+            );
+
+            let res = decode_response(res).unwrap();
+            assert_eq!(&to_bytes(res.into_body()).await[..], content);
+        }
+
+        #[test]
+        fn ignores_undecoded_response() {
+            let res = Response::new(Body::empty());
+
+            assert!(
+                !reencode_response(res)
+                    .headers()
+                    .contains_key(CONTENT_ENCODING)
+            );
+        }
+    }
+
+    mod accepted_encodings {
+        use super::*;
+
+        #[test]
+        fn orders_by_quality() {
+            assert_eq!(
+                accepted_encodings([&b"gzip;q=0.5, br, deflate;q=0.8, unknown"[..]]),
+                vec![Encoding::Brotli, Encoding::Deflate, Encoding::Gzip]
+            );
+        }
+
+        #[test]
+        fn excludes_rejected() {
+            assert_eq!(
+                accepted_encodings([&b"*, gzip;q=0, deflate;q=0"[..]]),
+                vec![Encoding::Brotli, Encoding::Zstd]
+            );
+        }
+    }
+
+    mod accept_encoding_policy {
+        use super::*;
+
+        fn ctx(accept_encoding: &'static str) -> HttpContext {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding));
+
+            HttpContext::from_headers(
+                &headers,
+                "127.0.0.1:8080".parse().unwrap(),
+                Method::GET,
+                "http://example.com/".parse().unwrap(),
+            )
+        }
+
+        fn request(accept_encoding: &'static str) -> Request<()> {
+            Request::builder()
+                .header(ACCEPT_ENCODING, accept_encoding)
+                .body(())
+                .unwrap()
+        }
+
+        #[test]
+        fn rewrites_to_identity() {
+            let req = AcceptEncodingPolicy::identity().rewrite_request(request("gzip, br"));
+
+            assert_eq!(req.headers()[ACCEPT_ENCODING], "identity");
+        }
+
+        #[test]
+        fn rewrites_to_restricted_set() {
+            let policy = AcceptEncodingPolicy::restricted([Encoding::Gzip, Encoding::Zstd]);
+
+            let req = policy.rewrite_request(request("zstd;q=0.5, br, gzip"));
+            assert_eq!(req.headers()[ACCEPT_ENCODING], "gzip, zstd");
+
+            let req = policy.rewrite_request(request("br"));
+            assert_eq!(req.headers()[ACCEPT_ENCODING], "identity");
+        }
+
+        #[tokio::test]
+        async fn recompresses_response() {
+            let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+            let res = Response::new(Body::from("hello, world"));
+
+            let res = policy.recompress_response(&ctx("gzip;q=0.5, br"), res);
+
+            assert_eq!(res.headers()[CONTENT_ENCODING], "br");
+            let res = decode_response(res).unwrap();
+            assert_eq!(&to_bytes(res.into_body()).await[..], b"hello, world");
+        }
+
+        #[test]
+        fn marks_recompressed_response() {
+            let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+            let res = Response::builder()
+                .header(VARY, "origin")
+                .header(ETAG, "\"abc\"")
+                .body(Body::from("hello, world"))
+                .unwrap();
+
+            let res = policy.recompress_response(&ctx("gzip"), res);
+
+            let vary = res.headers().get_all(VARY).iter().collect::<Vec<_>>();
+            assert_eq!(vary, ["origin", "accept-encoding"]);
+            assert_eq!(res.headers()[ETAG], "W/\"abc\"");
+        }
+
+        #[test]
+        fn does_not_duplicate_vary() {
+            let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+            let res = Response::builder()
+                .header(VARY, "Accept-Encoding")
+                .header(ETAG, "W/\"abc\"")
+                .body(Body::from("hello, world"))
+                .unwrap();
+
+            let res = policy.recompress_response(&ctx("gzip"), res);
+
+            assert_eq!(res.headers().get_all(VARY).iter().count(), 1);
+            assert_eq!(res.headers()[ETAG], "W/\"abc\"");
+        }
+
+        #[test]
+        fn does_not_recompress_partial_response() {
+            let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+            let res = Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, "bytes 0-4/12")
+                .header(ETAG, "\"abc\"")
+                .body(Body::from("hello"))
+                .unwrap();
+
+            let res = policy.recompress_response(&ctx("gzip"), res);
+
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(res.headers()[ETAG], "\"abc\"");
+        }
+
+        #[test]
+        fn does_not_recompress_encoded_response() {
+            let policy = AcceptEncodingPolicy::identity().with_recompression(true);
+            let res = Response::builder()
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+
+            let res = policy.recompress_response(&ctx("br"), res);
+
+            assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        }
+    }
+
+    mod decode_response {
+        use super::*;
+        use async_compression::tokio::bufread::GzipEncoder;
+
+        #[tokio::test]
+        async fn decodes_response() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, 123)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from_stream(ReaderStream::new(encoder)))
+                .unwrap();
+
+            let res = decode_response(res).unwrap();
+
+            assert!(!res.headers().contains_key(CONTENT_LENGTH));
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(&to_bytes(res.into_body()).await[..], content);
+        }
+    }
+}
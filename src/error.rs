@@ -1,4 +1,4 @@
-use crate::builder;
+use crate::{LengthLimitError, builder};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,6 +10,20 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("unable to decode body")]
     Decode,
+    #[cfg(feature = "decoder")]
+    #[error("{0}")]
+    DecodeLimit(#[from] crate::DecodeLimitError),
+    #[cfg(feature = "payload")]
+    #[error("{0}")]
+    Payload(#[from] crate::PayloadError),
+    #[cfg(feature = "grpc")]
+    #[error("{0}")]
+    Grpc(#[from] crate::GrpcError),
+    #[cfg(feature = "http3")]
+    #[error("{0}")]
+    Http3(Box<dyn std::error::Error + Send + Sync>),
+    #[error("{0}")]
+    LengthLimit(#[from] Box<LengthLimitError>),
     #[error("builder error")]
     Builder(#[from] builder::Error),
     #[error("unknown error")]
@@ -0,0 +1,1013 @@
+use crate::{
+    Body,
+    Error,
+    HttpContext,
+    HttpHandler,
+    RequestOrResponse,
+    WebSocketContext,
+    WebSocketHandler,
+};
+use futures::{Sink, Stream, StreamExt, stream};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    Uri,
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+};
+use hyper_util::rt::TokioIo;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use regex::Regex;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::error;
+
+/// TLS alert record signalling a fatal `handshake_failure`.
+const HANDSHAKE_FAILURE_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+
+/// A failure that can be injected by a [`FaultHandler`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// Respond with the given status without contacting the upstream server.
+    Status(StatusCode),
+    /// Delay the response by the given duration.
+    Delay(Duration),
+    /// End the response body cleanly after the given number of bytes.
+    TruncateBody(usize),
+    /// Reset the connection after the given number of response body bytes.
+    ResetBody(usize),
+    /// Send a chunked response body that is cut off before its terminating
+    /// chunk.
+    MalformedChunked,
+    /// Fail the TLS handshake of a CONNECT tunnel with a `handshake_failure`
+    /// alert.
+    TlsHandshakeFailure,
+    /// Close a WebSocket connection without a close handshake once the given
+    /// number of messages have been forwarded.
+    WebSocketClose {
+        /// Number of messages, in either direction, forwarded before closing.
+        after_messages: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Http,
+    Connect,
+    WebSocket,
+}
+
+impl Fault {
+    fn target(&self) -> Target {
+        match self {
+            Self::TlsHandshakeFailure => Target::Connect,
+            Self::WebSocketClose { .. } => Target::WebSocket,
+            _ => Target::Http,
+        }
+    }
+}
+
+/// A rule describing when a [`Fault`] should be injected.
+///
+/// A rule matches every request unless it is restricted by method, host or
+/// path. Matching requests trigger the fault with the configured probability.
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    fault: Fault,
+    probability: f64,
+    method: Option<Method>,
+    host: Option<String>,
+    path: Option<Regex>,
+}
+
+impl FaultRule {
+    /// Create a rule that always injects the given fault.
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            probability: 1.0,
+            method: None,
+            host: None,
+            path: None,
+        }
+    }
+
+    /// Set the probability, between `0.0` and `1.0`, that a matching request
+    /// triggers the fault.
+    pub fn with_probability(mut self, probability: f64) -> Self {
+        self.probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Only match requests with the given method.
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Only match requests to the given host.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Only match requests whose path matches the given pattern.
+    pub fn with_path(mut self, path: Regex) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    fn matches(&self, method: &Method, uri: &Uri) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && self
+                .host
+                .as_deref()
+                .is_none_or(|host| uri.host().is_some_and(|h| h.eq_ignore_ascii_case(host)))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| path.is_match(uri.path()))
+    }
+}
+
+/// Fault decisions of open WebSocket connections, keyed by connection id.
+type WebSocketTrips = HashMap<u64, OpenWebSocket>;
+
+/// Fault decision shared by the directions of a WebSocket connection.
+#[derive(Debug)]
+struct OpenWebSocket {
+    trip: Option<Arc<WebSocketTrip>>,
+    directions: usize,
+}
+
+/// Releases the fault decision of a WebSocket connection once all of its
+/// directions have finished.
+struct WebSocketDirection {
+    websockets: Arc<Mutex<WebSocketTrips>>,
+    id: u64,
+}
+
+impl Drop for WebSocketDirection {
+    fn drop(&mut self) {
+        let mut websockets = self.websockets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Entry::Occupied(mut entry) = websockets.entry(self.id) {
+            entry.get_mut().directions -= 1;
+
+            if entry.get().directions == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Tracks when a faulty WebSocket connection should be closed.
+#[derive(Debug)]
+struct WebSocketTrip {
+    remaining: AtomicUsize,
+    tripped: watch::Sender<bool>,
+}
+
+impl WebSocketTrip {
+    fn new(after_messages: usize) -> Self {
+        Self {
+            remaining: AtomicUsize::new(after_messages),
+            tripped: watch::Sender::new(after_messages == 0),
+        }
+    }
+
+    fn record(&self) {
+        let remaining = self
+            .remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+
+        if remaining == Ok(1) {
+            self.tripped.send_replace(true);
+        }
+    }
+}
+
+/// A handler that injects faults into proxied traffic for chaos testing.
+///
+/// Requests, responses and WebSocket messages are passed to the wrapped
+/// handlers, and faults are injected into what they forward. Each request is
+/// checked against the configured [`FaultRule`]s in order, and every matching
+/// rule rolls its probability using a random number generator seeded with the
+/// provided seed. Given the same seed and the same sequence of
+/// requests, the same faults are injected.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Fault,
+///     FaultHandler,
+///     FaultRule,
+///     NoopHandler,
+///     hyper::{Method, StatusCode},
+/// };
+///
+/// let handler = FaultHandler::new(NoopHandler::default(), NoopHandler::default(), 42)
+///     .with_rule(
+///         FaultRule::new(Fault::Status(StatusCode::SERVICE_UNAVAILABLE))
+///             .with_method(Method::POST)
+///             .with_probability(0.1),
+///     )
+///     .with_rule(FaultRule::new(Fault::TruncateBody(1024)).with_host("example.com"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct FaultHandler<H, W> {
+    http_handler: H,
+    websocket_handler: W,
+    rules: Arc<Vec<FaultRule>>,
+    rng: Arc<Mutex<StdRng>>,
+    websockets: Arc<Mutex<WebSocketTrips>>,
+    delay: Duration,
+    body_fault: Option<Fault>,
+}
+
+impl<H, W> FaultHandler<H, W> {
+    /// Create a fault handler with no rules, that passes requests and
+    /// responses to `http_handler` and WebSocket messages to
+    /// `websocket_handler`.
+    pub fn new(http_handler: H, websocket_handler: W, seed: u64) -> Self {
+        Self {
+            http_handler,
+            websocket_handler,
+            rules: Arc::new(Vec::new()),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            websockets: Arc::new(Mutex::new(HashMap::new())),
+            delay: Duration::ZERO,
+            body_fault: None,
+        }
+    }
+
+    /// Add a rule to the handler.
+    pub fn with_rule(mut self, rule: FaultRule) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    fn roll(&self, target: Target, method: &Method, uri: &Uri) -> Vec<Fault> {
+        let mut rng = self.rng.lock().expect("Failed to lock fault rng");
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.fault.target() == target && rule.matches(method, uri))
+            .filter(|rule| rng.random::<f64>() < rule.probability)
+            .map(|rule| rule.fault.clone())
+            .collect()
+    }
+
+    fn websocket_trip(
+        &self,
+        ctx: &WebSocketContext,
+    ) -> (Option<Arc<WebSocketTrip>>, WebSocketDirection) {
+        let (id, method, uri) = match ctx {
+            WebSocketContext::ClientToServer {
+                id, method, dst, ..
+            } => (*id, method, dst),
+            WebSocketContext::ServerToClient {
+                id, method, src, ..
+            } => (*id, method, src),
+        };
+
+        let mut websockets = self
+            .websockets
+            .lock()
+            .expect("Failed to lock fault websockets");
+
+        // Both directions of a connection share one decision, the first
+        // direction to start rolls the dice and the others reuse the result.
+        let open = websockets.entry(id).or_insert_with(|| OpenWebSocket {
+            trip: self
+                .roll(Target::WebSocket, method, uri)
+                .into_iter()
+                .find_map(|fault| match fault {
+                    Fault::WebSocketClose { after_messages } => {
+                        Some(Arc::new(WebSocketTrip::new(after_messages)))
+                    }
+                    _ => None,
+                }),
+            directions: 0,
+        });
+        open.directions += 1;
+
+        let direction = WebSocketDirection {
+            websockets: Arc::clone(&self.websockets),
+            id,
+        };
+
+        (open.trip.clone(), direction)
+    }
+}
+
+fn fail_tls_handshake(req: &mut Request<Body>) {
+    let upgrade = hyper::upgrade::on(req);
+
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let mut upgraded = TokioIo::new(upgraded);
+                let mut buffer = [0; 5];
+
+                if let Err(e) = upgraded.read(&mut buffer).await {
+                    error!("Failed to read from upgraded connection: {}", e);
+                    return;
+                }
+
+                if let Err(e) = upgraded.write_all(&HANDSHAKE_FAILURE_ALERT).await {
+                    error!("Failed to send TLS alert: {}", e);
+                    return;
+                }
+
+                let _ = upgraded.shutdown().await;
+            }
+            Err(e) => error!("Upgrade error: {}", e),
+        }
+    });
+}
+
+fn apply_body_fault(res: Response<Body>, fault: Fault) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+
+    let body = match fault {
+        Fault::TruncateBody(len) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            FaultyBody::new(body, len, false)
+        }
+        Fault::ResetBody(len) => FaultyBody::new(body, len, true),
+        Fault::MalformedChunked => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.insert(
+                TRANSFER_ENCODING,
+                "chunked".parse().expect("Invalid header"),
+            );
+            FaultyBody::new(body, 1, true)
+        }
+        _ => return Response::from_parts(parts, body),
+    };
+
+    Response::from_parts(parts, Body::from(BoxBody::new(body)))
+}
+
+/// Body that stops after a fixed number of bytes, optionally with an error.
+struct FaultyBody {
+    inner: Body,
+    remaining: usize,
+    reset: bool,
+    exhausted: bool,
+    finished: bool,
+}
+
+impl FaultyBody {
+    fn new(inner: Body, remaining: usize, reset: bool) -> Self {
+        Self {
+            inner,
+            remaining,
+            reset,
+            exhausted: remaining == 0,
+            finished: false,
+        }
+    }
+}
+
+impl HttpBody for FaultyBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        if self.exhausted {
+            self.finished = true;
+
+            return Poll::Ready(self.reset.then(|| {
+                Err(Error::Io(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "connection reset by fault injection",
+                )))
+            }));
+        }
+
+        match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(mut data) => {
+                    if data.len() >= self.remaining {
+                        data.truncate(self.remaining);
+                        self.exhausted = true;
+                    }
+
+                    self.remaining -= data.len();
+                    Poll::Ready(Some(Ok(Frame::data(data))))
+                }
+                Err(frame) => Poll::Ready(Some(Ok(frame))),
+            },
+            other => {
+                self.finished = true;
+                Poll::Ready(other)
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.finished
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+impl<H, W> HttpHandler for FaultHandler<H, W>
+where
+    H: HttpHandler,
+    W: Clone + Send + Sync + 'static,
+{
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let mut req = match self.http_handler.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => req,
+            res => return res,
+        };
+
+        if req.method() == Method::CONNECT {
+            if !self
+                .roll(Target::Connect, req.method(), req.uri())
+                .is_empty()
+            {
+                fail_tls_handshake(&mut req);
+                return Response::new(Body::empty()).into();
+            }
+
+            return req.into();
+        }
+
+        if hyper_tungstenite::is_upgrade_request(&req) {
+            return req.into();
+        }
+
+        let faults = self.roll(Target::Http, req.method(), req.uri());
+
+        self.delay = faults
+            .iter()
+            .filter_map(|fault| match fault {
+                Fault::Delay(delay) => Some(*delay),
+                _ => None,
+            })
+            .sum();
+
+        if let Some(status) = faults.iter().find_map(|fault| match fault {
+            Fault::Status(status) => Some(*status),
+            _ => None,
+        }) {
+            tokio::time::sleep(self.delay).await;
+
+            return Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .expect("Failed to build response")
+                .into();
+        }
+
+        self.body_fault = faults.into_iter().find(|fault| {
+            matches!(
+                fault,
+                Fault::TruncateBody(_) | Fault::ResetBody(_) | Fault::MalformedChunked
+            )
+        });
+
+        req.into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.http_handler.handle_response(ctx, res).await;
+
+        tokio::time::sleep(self.delay).await;
+
+        match self.body_fault.take() {
+            Some(fault) => apply_body_fault(res, fault),
+            None => res,
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.http_handler.handle_error(ctx, err).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.http_handler.should_intercept(ctx, req).await
+    }
+}
+
+impl<H, W> WebSocketHandler for FaultHandler<H, W>
+where
+    H: Clone + Send + Sync + 'static,
+    W: WebSocketHandler,
+{
+    async fn handle_websocket(
+        self,
+        ctx: WebSocketContext,
+        stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+        let (trip, _direction) = self.websocket_trip(&ctx);
+
+        let Some(trip) = trip else {
+            return self
+                .websocket_handler
+                .handle_websocket(ctx, stream, sink)
+                .await;
+        };
+
+        let mut tripped = trip.tripped.subscribe();
+
+        if *tripped.borrow_and_update() {
+            return;
+        }
+
+        let sink = CountingSink {
+            inner: sink,
+            trip: Arc::clone(&trip),
+            pending: 0,
+        };
+
+        // Stop passing messages to the handler once tripped, as it may never
+        // yield if the stream is always ready.
+        let gate = Arc::clone(&trip);
+        let mut stream = stream;
+        let stream = stream::poll_fn(move |cx| {
+            if *gate.tripped.borrow() {
+                Poll::Pending
+            } else {
+                stream.poll_next_unpin(cx)
+            }
+        });
+
+        // Returning drops both halves of the connection without sending a
+        // close frame.
+        tokio::select! {
+            biased;
+
+            _ = tripped.changed() => (),
+            _ = self.websocket_handler.handle_websocket(ctx, stream, sink) => (),
+        }
+    }
+}
+
+/// Sink that records the messages forwarded by a WebSocket handler once they
+/// have been flushed.
+struct CountingSink<S> {
+    inner: S,
+    trip: Arc<WebSocketTrip>,
+    pending: usize,
+}
+
+impl<S> Sink<Message> for CountingSink<S>
+where
+    S: Sink<Message> + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+
+        for _ in 0..std::mem::take(&mut self.pending) {
+            self.trip.record();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoopHandler;
+    use http_body_util::BodyExt;
+
+    fn fault_handler(seed: u64) -> FaultHandler<NoopHandler, NoopHandler> {
+        FaultHandler::new(NoopHandler::new(), NoopHandler::new(), seed)
+    }
+
+    /// Handler that tags what it forwards, to check that it is delegated to.
+    #[derive(Clone)]
+    struct Tagger;
+
+    impl HttpHandler for Tagger {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            req.headers_mut().insert("x-tagged", "1".parse().unwrap());
+            req.into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            mut res: Response<Body>,
+        ) -> Response<Body> {
+            res.headers_mut().insert("x-tagged", "1".parse().unwrap());
+            res
+        }
+    }
+
+    impl WebSocketHandler for Tagger {
+        async fn handle_message(
+            &mut self,
+            _ctx: &WebSocketContext,
+            message: Message,
+        ) -> Option<Message> {
+            Some(Message::text(format!("{}!", message.to_text().unwrap())))
+        }
+    }
+
+    fn ctx() -> HttpContext {
+        HttpContext::from_headers(
+            &Default::default(),
+            "127.0.0.1:8080".parse().unwrap(),
+            Method::GET,
+            "http://example.com/".parse().unwrap(),
+        )
+    }
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    mod fault_rule {
+        use super::*;
+
+        #[test]
+        fn matches_everything_by_default() {
+            let rule = FaultRule::new(Fault::MalformedChunked);
+
+            assert!(rule.matches(&Method::GET, &"http://example.com/".parse().unwrap()));
+            assert!(rule.matches(&Method::POST, &"http://foo.com/bar".parse().unwrap()));
+        }
+
+        #[test]
+        fn matches_restrictions() {
+            let rule = FaultRule::new(Fault::MalformedChunked)
+                .with_method(Method::POST)
+                .with_host("example.com")
+                .with_path(Regex::new("^/api/").unwrap());
+
+            assert!(rule.matches(
+                &Method::POST,
+                &"http://EXAMPLE.com/api/users".parse().unwrap()
+            ));
+            assert!(!rule.matches(
+                &Method::GET,
+                &"http://example.com/api/users".parse().unwrap()
+            ));
+            assert!(!rule.matches(&Method::POST, &"http://foo.com/api/users".parse().unwrap()));
+            assert!(!rule.matches(&Method::POST, &"http://example.com/users".parse().unwrap()));
+        }
+    }
+
+    mod handle_request {
+        use super::*;
+
+        #[tokio::test]
+        async fn returns_status() {
+            let mut handler = fault_handler(0).with_rule(FaultRule::new(Fault::Status(
+                StatusCode::SERVICE_UNAVAILABLE,
+            )));
+
+            match handler
+                .handle_request(&ctx(), request("http://example.com/"))
+                .await
+            {
+                RequestOrResponse::Response(res) => {
+                    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE)
+                }
+                RequestOrResponse::Request(_) => panic!("Expected a response"),
+            }
+        }
+
+        #[tokio::test]
+        async fn forwards_unmatched_requests() {
+            let mut handler = fault_handler(0).with_rule(
+                FaultRule::new(Fault::Status(StatusCode::SERVICE_UNAVAILABLE)).with_host("foo.com"),
+            );
+
+            assert!(matches!(
+                handler
+                    .handle_request(&ctx(), request("http://example.com/"))
+                    .await,
+                RequestOrResponse::Request(_)
+            ));
+        }
+
+        #[tokio::test]
+        async fn is_deterministic() {
+            async fn outcomes(seed: u64) -> Vec<bool> {
+                let mut handler = fault_handler(seed).with_rule(
+                    FaultRule::new(Fault::Status(StatusCode::BAD_GATEWAY)).with_probability(0.5),
+                );
+                let mut outcomes = Vec::new();
+
+                for _ in 0..32 {
+                    let res = handler
+                        .handle_request(&ctx(), request("http://example.com/"))
+                        .await;
+                    outcomes.push(matches!(res, RequestOrResponse::Response(_)));
+                }
+
+                outcomes
+            }
+
+            let first = outcomes(7).await;
+
+            assert_eq!(first, outcomes(7).await);
+            assert!(first.contains(&true));
+            assert!(first.contains(&false));
+        }
+    }
+
+    mod handle_response {
+        use super::*;
+
+        async fn faulty_response(fault: Fault) -> Response<Body> {
+            let mut handler = fault_handler(0).with_rule(FaultRule::new(fault));
+            let _ = handler
+                .handle_request(&ctx(), request("http://example.com/"))
+                .await;
+
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, 12)
+                .body(Body::from("hello, world"))
+                .unwrap();
+
+            handler.handle_response(&ctx(), res).await
+        }
+
+        #[tokio::test]
+        async fn delegates_to_http_handler() {
+            let mut handler = FaultHandler::new(Tagger, NoopHandler::new(), 0)
+                .with_rule(FaultRule::new(Fault::TruncateBody(5)));
+
+            match handler
+                .handle_request(&ctx(), request("http://example.com/"))
+                .await
+            {
+                RequestOrResponse::Request(req) => assert_eq!(req.headers()["x-tagged"], "1"),
+                RequestOrResponse::Response(_) => panic!("Expected a request"),
+            }
+
+            let res = handler
+                .handle_response(&ctx(), Response::new(Body::from("hello, world")))
+                .await;
+
+            assert_eq!(res.headers()["x-tagged"], "1");
+            assert_eq!(
+                &res.into_body().collect().await.unwrap().to_bytes()[..],
+                b"hello"
+            );
+        }
+
+        #[tokio::test]
+        async fn truncates_body() {
+            let res = faulty_response(Fault::TruncateBody(5)).await;
+
+            assert!(!res.headers().contains_key(CONTENT_LENGTH));
+            assert_eq!(
+                &res.into_body().collect().await.unwrap().to_bytes()[..],
+                b"hello"
+            );
+        }
+
+        #[tokio::test]
+        async fn resets_body() {
+            let res = faulty_response(Fault::ResetBody(5)).await;
+
+            assert!(res.headers().contains_key(CONTENT_LENGTH));
+            assert!(res.into_body().collect().await.is_err());
+        }
+
+        #[tokio::test]
+        async fn malforms_chunked_body() {
+            let res = faulty_response(Fault::MalformedChunked).await;
+
+            assert_eq!(res.headers()[TRANSFER_ENCODING], "chunked");
+            assert!(res.into_body().collect().await.is_err());
+        }
+    }
+
+    mod tls_handshake {
+        use super::*;
+        use hyper::{body::Incoming, service::service_fn};
+        use hyper_util::{rt::TokioExecutor, server::conn::auto::Builder};
+        use std::convert::Infallible;
+        use tokio::net::{TcpListener, TcpStream};
+
+        #[tokio::test]
+        async fn sends_handshake_failure() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let handler = fault_handler(0).with_rule(FaultRule::new(Fault::TlsHandshakeFailure));
+
+            tokio::spawn(async move {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let mut handler = handler.clone();
+
+                    async move {
+                        match handler.handle_request(&ctx(), req.map(Body::from)).await {
+                            RequestOrResponse::Response(res) => Ok::<_, Infallible>(res),
+                            RequestOrResponse::Request(_) => panic!("Expected a response"),
+                        }
+                    }
+                });
+
+                Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(tcp), service)
+                    .await
+                    .unwrap();
+            });
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 200"));
+
+            stream
+                .write_all(&[0x16, 0x03, 0x01, 0x00, 0x00])
+                .await
+                .unwrap();
+
+            let mut alert = Vec::new();
+            stream.read_to_end(&mut alert).await.unwrap();
+            assert_eq!(alert, HANDSHAKE_FAILURE_ALERT);
+        }
+    }
+
+    mod handle_websocket {
+        use super::*;
+        use futures::{SinkExt, channel::mpsc};
+
+        fn context(id: u64, method: Method, client_to_server: bool) -> WebSocketContext {
+            let client = "127.0.0.1:8080".parse().unwrap();
+            let server: Uri = "ws://example.com/".parse().unwrap();
+
+            if client_to_server {
+                WebSocketContext::ClientToServer {
+                    src: client,
+                    dst: server,
+                    id,
+                    method,
+                }
+            } else {
+                WebSocketContext::ServerToClient {
+                    src: server,
+                    dst: client,
+                    id,
+                    method,
+                }
+            }
+        }
+
+        async fn forward(
+            handler: &FaultHandler<NoopHandler, impl WebSocketHandler>,
+            ctx: WebSocketContext,
+            stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        ) -> Vec<Message> {
+            let (sink, forwarded) = mpsc::unbounded();
+
+            handler
+                .clone()
+                .handle_websocket(
+                    ctx,
+                    stream,
+                    sink.sink_map_err(|_| tungstenite::Error::ConnectionClosed),
+                )
+                .await;
+
+            forwarded.collect().await
+        }
+
+        fn messages(count: usize) -> Vec<Result<Message, tungstenite::Error>> {
+            (0..count)
+                .map(|i| Ok(Message::text(i.to_string())))
+                .collect()
+        }
+
+        #[tokio::test]
+        async fn closes_after_messages() {
+            let handler = fault_handler(0)
+                .with_rule(FaultRule::new(Fault::WebSocketClose { after_messages: 2 }));
+            let stream = futures::stream::iter(messages(5)).chain(futures::stream::pending());
+
+            let forwarded = forward(&handler, context(0, Method::GET, true), stream).await;
+
+            assert_eq!(forwarded.len(), 2);
+            assert!(!forwarded.iter().any(Message::is_close));
+            assert!(handler.websockets.lock().unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn delegates_to_websocket_handler() {
+            let handler = FaultHandler::new(NoopHandler::new(), Tagger, 0)
+                .with_rule(FaultRule::new(Fault::WebSocketClose { after_messages: 2 }));
+            let stream = futures::stream::iter(messages(5)).chain(futures::stream::pending());
+
+            let forwarded = forward(&handler, context(0, Method::GET, true), stream).await;
+
+            assert_eq!(forwarded, [Message::text("0!"), Message::text("1!")]);
+        }
+
+        #[tokio::test]
+        async fn matches_method() {
+            let handler = fault_handler(0).with_rule(
+                FaultRule::new(Fault::WebSocketClose { after_messages: 0 })
+                    .with_method(Method::POST),
+            );
+
+            let stream = futures::stream::iter(messages(3));
+            let forwarded = forward(&handler, context(0, Method::GET, true), stream).await;
+            assert_eq!(forwarded.len(), 3);
+
+            let stream = futures::stream::iter(messages(3)).chain(futures::stream::pending());
+            let forwarded = forward(&handler, context(1, Method::POST, true), stream).await;
+            assert!(forwarded.is_empty());
+        }
+
+        #[test]
+        fn tracks_connections_separately() {
+            let handler = fault_handler(0)
+                .with_rule(FaultRule::new(Fault::WebSocketClose { after_messages: 1 }));
+
+            let (first, first_direction) = handler.websocket_trip(&context(1, Method::GET, true));
+            let (second, second_direction) = handler.websocket_trip(&context(2, Method::GET, true));
+            let (first_reverse, first_reverse_direction) =
+                handler.websocket_trip(&context(1, Method::GET, false));
+
+            assert!(Arc::ptr_eq(
+                first.as_ref().unwrap(),
+                first_reverse.as_ref().unwrap()
+            ));
+            assert!(!Arc::ptr_eq(
+                first.as_ref().unwrap(),
+                second.as_ref().unwrap()
+            ));
+
+            drop(first_direction);
+            assert_eq!(handler.websockets.lock().unwrap().len(), 2);
+            drop(first_reverse_direction);
+            assert_eq!(handler.websockets.lock().unwrap().len(), 1);
+            drop(second_direction);
+            assert!(handler.websockets.lock().unwrap().is_empty());
+        }
+    }
+}
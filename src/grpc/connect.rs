@@ -0,0 +1,340 @@
+use super::{
+    GRPC_MESSAGE,
+    GRPC_STATUS,
+    GrpcContext,
+    GrpcError,
+    GrpcHandler,
+    GrpcStatus,
+    compress,
+    decompress,
+};
+use crate::{Body, Error};
+use bytes::BytesMut;
+use http_body_util::BodyExt;
+use hyper::{
+    StatusCode,
+    body::Bytes,
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue},
+    http::response,
+};
+use serde_json::{Map, Value};
+
+/// Flag of the message that ends a Connect streaming response.
+pub(super) const END_STREAM_FLAG: u8 = 0x02;
+
+/// Prefix of the headers that carry the trailers of a unary Connect response.
+const TRAILER_PREFIX: &str = "trailer-";
+
+/// Connect error codes, in the order of their gRPC status codes starting from
+/// 1, with the HTTP status used for unary responses.
+const CODES: [(&str, u16); 16] = [
+    ("canceled", 499),
+    ("unknown", 500),
+    ("invalid_argument", 400),
+    ("deadline_exceeded", 504),
+    ("not_found", 404),
+    ("already_exists", 409),
+    ("permission_denied", 403),
+    ("resource_exhausted", 429),
+    ("failed_precondition", 400),
+    ("aborted", 409),
+    ("out_of_range", 400),
+    ("unimplemented", 501),
+    ("internal", 500),
+    ("unavailable", 503),
+    ("data_loss", 500),
+    ("unauthenticated", 401),
+];
+
+fn code(status: u32) -> (&'static str, u16) {
+    status
+        .checked_sub(1)
+        .and_then(|i| CODES.get(i as usize))
+        .copied()
+        .unwrap_or(CODES[1])
+}
+
+/// Read the status of a Connect error object.
+fn error_status(error: Option<&Value>) -> GrpcStatus {
+    let Some(error) = error else {
+        return GrpcStatus::new(0, None);
+    };
+
+    let code = error
+        .get("code")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let code = CODES
+        .iter()
+        .position(|(name, _)| *name == code)
+        .map_or(2, |i| i as u32 + 1);
+    let message = error
+        .get("message")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+
+    GrpcStatus::new(code, message)
+}
+
+/// Build the error object for a status. The original error is kept when the
+/// status is unchanged, so that its details are preserved.
+fn encode_error(status: &GrpcStatus, original: Option<Value>) -> Option<Value> {
+    if status.code == 0 {
+        return None;
+    }
+
+    if let Some(original) = original.filter(|error| error_status(Some(error)) == *status) {
+        return Some(original);
+    }
+
+    let mut error = Map::new();
+    error.insert("code".to_owned(), code(status.code).0.into());
+
+    if let Some(message) = &status.message {
+        error.insert("message".to_owned(), message.clone().into());
+    }
+
+    Some(Value::Object(error))
+}
+
+/// Parse the message that ends a streaming response into trailers, along with
+/// the original error object.
+pub(super) fn decode_end_stream(message: &[u8]) -> Result<(HeaderMap, Option<Value>), GrpcError> {
+    let end: Value = serde_json::from_slice(message)?;
+    let mut trailers = HeaderMap::new();
+
+    if let Some(Value::Object(metadata)) = end.get("metadata") {
+        for (name, values) in metadata {
+            let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+                continue;
+            };
+
+            for value in values.as_array().into_iter().flatten() {
+                if let Some(Ok(value)) = value.as_str().map(HeaderValue::from_str) {
+                    trailers.append(name.clone(), value);
+                }
+            }
+        }
+    }
+
+    let error = end.get("error").filter(|error| !error.is_null()).cloned();
+    error_status(error.as_ref()).write_headers(&mut trailers);
+
+    Ok((trailers, error))
+}
+
+/// Serialize trailers into the message that ends a streaming response.
+pub(super) fn encode_end_stream(trailers: &HeaderMap, original: Option<Value>) -> Bytes {
+    let status = GrpcStatus::from_headers(trailers).unwrap_or(GrpcStatus::new(0, None));
+    let mut end = Map::new();
+
+    if let Some(error) = encode_error(&status, original) {
+        end.insert("error".to_owned(), error);
+    }
+
+    let mut metadata = Map::new();
+
+    for name in trailers.keys() {
+        if name == GRPC_STATUS || name == GRPC_MESSAGE {
+            continue;
+        }
+
+        let values = trailers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into())
+            .collect();
+        metadata.insert(name.as_str().to_owned(), Value::Array(values));
+    }
+
+    if !metadata.is_empty() {
+        end.insert("metadata".to_owned(), Value::Object(metadata));
+    }
+
+    Bytes::from(Value::Object(end).to_string())
+}
+
+fn content_encoding(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("identity")
+        .to_ascii_lowercase()
+}
+
+/// Read a whole unary body, which is a single message.
+async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, Error> {
+    let mut buf = BytesMut::new();
+
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            buf.extend_from_slice(&data);
+
+            if buf.len() > limit {
+                return Err(GrpcError::MessageTooLarge(buf.len()).into());
+            }
+        }
+    }
+
+    Ok(buf.freeze())
+}
+
+fn decompress_body(encoding: &str, data: &Bytes, limit: usize) -> Option<Result<Bytes, GrpcError>> {
+    match encoding {
+        "identity" => Some(Ok(data.clone())),
+        _ => decompress(encoding, data, limit),
+    }
+}
+
+async fn handle_message<G: GrpcHandler>(
+    handler: &mut G,
+    ctx: &GrpcContext,
+    encoding: &str,
+    data: Bytes,
+    limit: usize,
+) -> Result<Bytes, GrpcError> {
+    let Some(message) = decompress_body(encoding, &data, limit) else {
+        return Ok(data);
+    };
+
+    let message = handler
+        .handle_message(ctx, message?)
+        .await
+        .unwrap_or_default();
+
+    match encoding {
+        "identity" => Ok(message),
+        _ => compress(encoding, &message),
+    }
+}
+
+/// Pass the message of a unary request through a handler.
+pub(super) async fn intercept_request<G: GrpcHandler>(
+    handler: &mut G,
+    ctx: &GrpcContext,
+    headers: &mut HeaderMap,
+    body: Body,
+    limit: usize,
+) -> Result<Body, Error> {
+    let encoding = content_encoding(headers);
+    let data = read_body(body, limit).await?;
+    headers.remove(CONTENT_LENGTH);
+
+    Ok(Body::from(
+        handle_message(handler, ctx, &encoding, data, limit).await?,
+    ))
+}
+
+/// Pass the message and trailers of a unary response through a handler.
+pub(super) async fn intercept_response<G: GrpcHandler>(
+    handler: &mut G,
+    ctx: &GrpcContext,
+    parts: &mut response::Parts,
+    body: Body,
+    limit: usize,
+) -> Result<Body, Error> {
+    let encoding = content_encoding(&parts.headers);
+    let data = read_body(body, limit).await?;
+    parts.headers.remove(CONTENT_LENGTH);
+
+    let (data, error) = if parts.status.is_success() {
+        (
+            handle_message(handler, ctx, &encoding, data, limit).await?,
+            None,
+        )
+    } else {
+        let error = decompress_body(&encoding, &data, limit)
+            .and_then(Result::ok)
+            .and_then(|error| serde_json::from_slice::<Value>(&error).ok());
+        (data, error)
+    };
+
+    let mut trailers = HeaderMap::new();
+    let names = parts
+        .headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(TRAILER_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in names {
+        let Ok(trailer) = HeaderName::from_bytes(&name.as_str().as_bytes()[TRAILER_PREFIX.len()..])
+        else {
+            continue;
+        };
+
+        for value in parts.headers.get_all(&name) {
+            trailers.append(trailer.clone(), value.clone());
+        }
+
+        parts.headers.remove(&name);
+    }
+
+    let original = error_status(error.as_ref());
+    original.write_headers(&mut trailers);
+    let trailers = handler.handle_trailers(ctx, trailers).await;
+
+    for (name, value) in &trailers {
+        if name == GRPC_STATUS || name == GRPC_MESSAGE {
+            continue;
+        }
+
+        if let Ok(name) = HeaderName::from_bytes(format!("{TRAILER_PREFIX}{name}").as_bytes()) {
+            parts.headers.append(name, value.clone());
+        }
+    }
+
+    let status = GrpcStatus::from_headers(&trailers).unwrap_or(original.clone());
+
+    if status == original {
+        return Ok(Body::from(data));
+    }
+
+    match encode_error(&status, error) {
+        Some(error) => {
+            parts.status = StatusCode::from_u16(code(status.code).1)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            parts.headers.remove(CONTENT_ENCODING);
+            parts
+                .headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            Ok(Body::from(error.to_string()))
+        }
+        None => Ok(Body::from(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_end_stream() {
+        let message = br#"{"error":{"code":"not_found","message":"gone","details":[{"type":"x"}]},"metadata":{"x-a":["1","2"]}}"#;
+        let (trailers, error) = decode_end_stream(message).unwrap();
+
+        assert_eq!(
+            GrpcStatus::from_headers(&trailers),
+            Some(GrpcStatus::new(5, Some("gone".to_owned())))
+        );
+        assert_eq!(trailers.get_all("x-a").iter().count(), 2);
+
+        let end: Value =
+            serde_json::from_slice(&encode_end_stream(&trailers, error.clone())).unwrap();
+        assert_eq!(end, serde_json::from_slice::<Value>(message).unwrap());
+
+        let mut trailers = HeaderMap::new();
+        GrpcStatus::new(16, None).write_headers(&mut trailers);
+        assert_eq!(
+            &encode_end_stream(&trailers, error)[..],
+            br#"{"error":{"code":"unauthenticated"}}"#
+        );
+
+        let (trailers, error) = decode_end_stream(b"{}").unwrap();
+        assert_eq!(
+            GrpcStatus::from_headers(&trailers),
+            Some(GrpcStatus::new(0, None))
+        );
+        assert_eq!(&encode_end_stream(&trailers, error)[..], b"{}");
+    }
+}
@@ -0,0 +1,983 @@
+mod connect;
+#[cfg(feature = "grpc-reflect")]
+mod reflect;
+mod web;
+
+#[cfg(feature = "grpc-reflect")]
+pub use reflect::GrpcDescriptors;
+
+use crate::{Body, Error, HttpContext, HttpHandler, RequestOrResponse};
+use bytes::{Buf, BytesMut};
+use futures::{SinkExt, channel::mpsc, stream};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    Method,
+    Request,
+    Response,
+    Uri,
+    body::{Bytes, Frame},
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+};
+use thiserror::Error;
+use tracing::{Instrument, info_span};
+
+const GRPC_ENCODING: &str = "grpc-encoding";
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+/// Flag of a compressed message.
+const COMPRESSED_FLAG: u8 = 0x01;
+
+/// Characters that are percent-encoded in `grpc-message`.
+const MESSAGE_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%');
+
+/// Errors that occur while intercepting gRPC messages.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum GrpcError {
+    #[error("grpc message of {0} bytes exceeds the limit")]
+    MessageTooLarge(usize),
+    #[error("body ended in the middle of a grpc message")]
+    Incomplete,
+    #[error("failed to compress or decompress grpc message: {0}")]
+    Compression(#[from] io::Error),
+    #[error("invalid grpc-web-text body: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid connect end of stream message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "grpc-reflect")]
+    #[error("invalid descriptor set: {0}")]
+    Descriptor(#[from] prost_reflect::DescriptorError),
+    #[cfg(feature = "grpc-reflect")]
+    #[error("invalid protobuf message: {0}")]
+    Decode(#[from] prost_reflect::prost::DecodeError),
+    #[cfg(feature = "grpc-reflect")]
+    #[error("no descriptor for method {0}")]
+    UnknownMethod(String),
+}
+
+/// Direction of a gRPC message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GrpcDirection {
+    /// A message sent by the client.
+    Request,
+    /// A message sent by the server.
+    Response,
+}
+
+/// Protocol of a gRPC call.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum GrpcProtocol {
+    /// Native gRPC, with `application/grpc` bodies.
+    Grpc,
+    /// gRPC-Web, with binary `application/grpc-web` bodies.
+    GrpcWeb,
+    /// gRPC-Web, with base64 encoded `application/grpc-web-text` bodies.
+    GrpcWebText,
+    /// A Connect streaming call, with `application/connect` bodies.
+    ConnectStream,
+    /// A Connect unary call, where the body is a single message.
+    ConnectUnary,
+}
+
+impl GrpcProtocol {
+    fn detect(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers
+            .get(CONTENT_TYPE)?
+            .to_str()
+            .ok()?
+            .split(';')
+            .next()?
+            .trim()
+            .to_ascii_lowercase();
+        let is = |name: &str| {
+            content_type
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('+'))
+        };
+
+        if is("application/grpc") {
+            Some(Self::Grpc)
+        } else if is("application/grpc-web") {
+            Some(Self::GrpcWeb)
+        } else if is("application/grpc-web-text") {
+            Some(Self::GrpcWebText)
+        } else if content_type.starts_with("application/connect+") {
+            Some(Self::ConnectStream)
+        } else if headers.contains_key("connect-protocol-version")
+            && content_type.starts_with("application/")
+        {
+            Some(Self::ConnectUnary)
+        } else {
+            None
+        }
+    }
+
+    fn encoding_header(self) -> &'static str {
+        match self {
+            Self::ConnectStream => "connect-content-encoding",
+            Self::ConnectUnary => "content-encoding",
+            _ => GRPC_ENCODING,
+        }
+    }
+}
+
+/// Context for gRPC messages.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct GrpcContext {
+    /// Address of the client.
+    pub client_addr: SocketAddr,
+    /// URI of the call.
+    pub uri: Uri,
+    /// Fully qualified name of the service, such as `helloworld.Greeter`.
+    pub service: String,
+    /// Name of the method, such as `SayHello`.
+    pub method: String,
+    /// Direction of the message.
+    pub direction: GrpcDirection,
+    /// Protocol of the call.
+    pub protocol: GrpcProtocol,
+}
+
+impl GrpcContext {
+    fn new(ctx: &HttpContext, uri: &Uri, protocol: GrpcProtocol) -> Self {
+        let (service, method) = uri
+            .path()
+            .trim_start_matches('/')
+            .split_once('/')
+            .unwrap_or_default();
+
+        Self {
+            client_addr: ctx.client_addr,
+            uri: uri.clone(),
+            service: service.to_owned(),
+            method: method.to_owned(),
+            direction: GrpcDirection::Request,
+            protocol,
+        }
+    }
+}
+
+/// The status of a gRPC call, from the `grpc-status` and `grpc-message`
+/// trailers.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct GrpcStatus {
+    /// The status code, where 0 is `OK`.
+    pub code: u32,
+    /// The status message, percent-decoded.
+    pub message: Option<String>,
+}
+
+impl GrpcStatus {
+    /// Create a status with the given code and message.
+    pub fn new(code: u32, message: Option<String>) -> Self {
+        Self { code, message }
+    }
+
+    /// Read the status from trailers, or from the headers of a trailers-only
+    /// response.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let code = headers.get(GRPC_STATUS)?.to_str().ok()?.parse().ok()?;
+        let message = headers
+            .get(GRPC_MESSAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| percent_decode_str(value).decode_utf8_lossy().into_owned());
+
+        Some(Self { code, message })
+    }
+
+    /// Write the status into trailers, replacing any existing status.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(GRPC_STATUS, HeaderValue::from(self.code));
+        headers.remove(GRPC_MESSAGE);
+
+        if let Some(message) = &self.message {
+            let message = utf8_percent_encode(message, MESSAGE_ENCODE_SET).to_string();
+
+            if let Ok(message) = HeaderValue::from_str(&message) {
+                headers.insert(GRPC_MESSAGE, message);
+            }
+        }
+    }
+}
+
+/// Handler for gRPC messages.
+///
+/// Messages of the same call and direction are passed to the same instance of
+/// the handler. Handlers are applied with a [`GrpcLayer`].
+pub trait GrpcHandler: Clone + Send + Sync + 'static {
+    /// This handler will be called for each message, after it has been
+    /// decompressed. It can return an optional modified message, which is
+    /// compressed again if the original was. If None is returned the message
+    /// will not be forwarded.
+    fn handle_message(
+        &mut self,
+        _ctx: &GrpcContext,
+        message: Bytes,
+    ) -> impl Future<Output = Option<Bytes>> + Send {
+        async { Some(message) }
+    }
+
+    /// This handler will be called with the trailers at the end of each
+    /// direction of a call, and returns the trailers to forward. The status of
+    /// the call can be read with [`GrpcStatus::from_headers`].
+    fn handle_trailers(
+        &mut self,
+        _ctx: &GrpcContext,
+        trailers: HeaderMap,
+    ) -> impl Future<Output = HeaderMap> + Send {
+        async { trailers }
+    }
+}
+
+/// An [`HttpHandler`] that splits gRPC calls into messages for a
+/// [`GrpcHandler`], and passes everything else to another HTTP handler.
+///
+/// Native gRPC, gRPC-Web (binary and `-text`) and Connect calls are split into
+/// the same messages and trailers, and re-encoded in their own protocol after
+/// they have been handled. The trailers of gRPC-Web and Connect responses are
+/// translated to `grpc-status`, `grpc-message` and metadata entries.
+///
+/// Messages are decompressed according to the `grpc-encoding` header, or the
+/// `connect-content-encoding` and `content-encoding` headers for Connect, when
+/// it is `gzip`, `deflate` or `zstd`. Messages in other encodings are forwarded
+/// without being passed to the handler.
+///
+/// Unary Connect calls are buffered, and are only recognized by a POST request
+/// with a `connect-protocol-version` header. Dropping their message sends an
+/// empty message instead. Setting an error status in the trailers of a
+/// successful unary Connect response turns it into an error response.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{GrpcContext, GrpcHandler, GrpcLayer, NoopHandler, hyper::body::Bytes};
+///
+/// #[derive(Clone)]
+/// struct LogHandler;
+///
+/// impl GrpcHandler for LogHandler {
+///     async fn handle_message(&mut self, ctx: &GrpcContext, message: Bytes) -> Option<Bytes> {
+///         println!("{}/{}: {} bytes", ctx.service, ctx.method, message.len());
+///         Some(message)
+///     }
+/// }
+///
+/// let handler = GrpcLayer::new(NoopHandler::default(), LogHandler);
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcLayer<H, G> {
+    http_handler: H,
+    grpc_handler: G,
+    max_message_size: usize,
+    call: Option<GrpcContext>,
+}
+
+impl<H, G> GrpcLayer<H, G> {
+    /// The default limit for the size of a message.
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Create a layer that passes gRPC messages to `grpc_handler`, and
+    /// requests and responses to `http_handler`.
+    pub fn new(http_handler: H, grpc_handler: G) -> Self {
+        Self {
+            http_handler,
+            grpc_handler,
+            max_message_size: Self::DEFAULT_MAX_MESSAGE_SIZE,
+            call: None,
+        }
+    }
+
+    /// Set the limit for the size of a message, before and after it is
+    /// decompressed. Calls with larger messages fail.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+}
+
+/// A body that fails with an error, for calls that could not be intercepted.
+fn error_body(err: Error) -> Body {
+    Body::from(StreamBody::new(stream::iter([Err::<Frame<Bytes>, _>(err)])))
+}
+
+impl<H: HttpHandler, G: GrpcHandler> HttpHandler for GrpcLayer<H, G> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let req = match self.http_handler.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => req,
+            res => return res,
+        };
+
+        let protocol = match GrpcProtocol::detect(req.headers()) {
+            Some(GrpcProtocol::ConnectUnary) if req.method() != Method::POST => return req.into(),
+            Some(protocol) => protocol,
+            None => return req.into(),
+        };
+
+        let call = GrpcContext::new(ctx, req.uri(), protocol);
+        self.call = Some(call.clone());
+
+        let (mut parts, body) = req.into_parts();
+        let body = match protocol {
+            GrpcProtocol::ConnectUnary => connect::intercept_request(
+                &mut self.grpc_handler.clone(),
+                &call,
+                &mut parts.headers,
+                body,
+                self.max_message_size,
+            )
+            .await
+            .unwrap_or_else(error_body),
+            _ => {
+                parts.headers.remove(CONTENT_LENGTH);
+                intercept(
+                    self.grpc_handler.clone(),
+                    call,
+                    &parts.headers,
+                    body,
+                    self.max_message_size,
+                )
+            }
+        };
+
+        Request::from_parts(parts, body).into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.http_handler.handle_response(ctx, res).await;
+
+        let Some(mut call) = self.call.take() else {
+            return res;
+        };
+        call.direction = GrpcDirection::Response;
+
+        match call.protocol {
+            GrpcProtocol::ConnectUnary => {
+                let (mut parts, body) = res.into_parts();
+                let body = connect::intercept_response(
+                    &mut self.grpc_handler.clone(),
+                    &call,
+                    &mut parts,
+                    body,
+                    self.max_message_size,
+                )
+                .await
+                .unwrap_or_else(error_body);
+
+                Response::from_parts(parts, body)
+            }
+            protocol if GrpcProtocol::detect(res.headers()) == Some(protocol) => {
+                let (mut parts, body) = res.into_parts();
+                parts.headers.remove(CONTENT_LENGTH);
+                let body = intercept(
+                    self.grpc_handler.clone(),
+                    call,
+                    &parts.headers,
+                    body,
+                    self.max_message_size,
+                );
+
+                Response::from_parts(parts, body)
+            }
+            _ => res,
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.http_handler.handle_error(ctx, err).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.http_handler.should_intercept(ctx, req).await
+    }
+}
+
+/// Split a gRPC body into messages.
+#[derive(Debug)]
+pub(crate) struct MessageParser {
+    buf: BytesMut,
+    max_message_size: usize,
+}
+
+impl MessageParser {
+    pub(crate) fn new(max_message_size: usize) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            max_message_size,
+        }
+    }
+
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Get the next complete message and its flags.
+    pub(crate) fn next_message(&mut self) -> Result<Option<(u8, Bytes)>, GrpcError> {
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+
+        if len > self.max_message_size {
+            return Err(GrpcError::MessageTooLarge(len));
+        }
+
+        if self.buf.len() < 5 + len {
+            return Ok(None);
+        }
+
+        let flags = self.buf[0];
+        self.buf.advance(5);
+        Ok(Some((flags, self.buf.split_to(len).freeze())))
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), GrpcError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(GrpcError::Incomplete)
+        }
+    }
+}
+
+/// Add the flags and length prefix to a message.
+pub(crate) fn encode_message(flags: u8, message: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(5 + message.len());
+    buf.extend_from_slice(&[flags]);
+    buf.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    buf.extend_from_slice(message);
+    buf.freeze()
+}
+
+/// Decompress a message, or return `None` if the encoding isn't supported.
+pub(crate) fn decompress(
+    encoding: &str,
+    message: &[u8],
+    limit: usize,
+) -> Option<Result<Bytes, GrpcError>> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        "gzip" => Box::new(flate2::read::GzDecoder::new(message)),
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(message)),
+        "zstd" => match zstd::stream::read::Decoder::new(message) {
+            Ok(decoder) => Box::new(decoder),
+            Err(e) => return Some(Err(e.into())),
+        },
+        _ => return None,
+    };
+
+    let mut buf = Vec::new();
+
+    Some(match reader.take(limit as u64 + 1).read_to_end(&mut buf) {
+        Ok(len) if len > limit => Err(GrpcError::MessageTooLarge(len)),
+        Ok(_) => Ok(Bytes::from(buf)),
+        Err(e) => Err(e.into()),
+    })
+}
+
+pub(crate) fn compress(encoding: &str, message: &[u8]) -> Result<Bytes, GrpcError> {
+    let buf = match encoding {
+        "gzip" => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(message)?;
+            encoder.finish()?
+        }
+        "deflate" => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(message)?;
+            encoder.finish()?
+        }
+        "zstd" => zstd::stream::encode_all(message, 0)?,
+        _ => message.to_vec(),
+    };
+
+    Ok(Bytes::from(buf))
+}
+
+/// Pass the messages and trailers of a streaming body through a handler, while
+/// the call stays open.
+fn intercept<G: GrpcHandler>(
+    mut handler: G,
+    ctx: GrpcContext,
+    headers: &HeaderMap,
+    body: Body,
+    max_message_size: usize,
+) -> Body {
+    let encoding = headers
+        .get(ctx.protocol.encoding_header())
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("identity")
+        .to_ascii_lowercase();
+
+    let (mut tx, rx) = mpsc::channel::<Result<Frame<Bytes>, Error>>(16);
+
+    let fut = async move {
+        let mut body = body;
+        let mut parser = MessageParser::new(max_message_size);
+        let mut text = (ctx.protocol == GrpcProtocol::GrpcWebText).then(web::TextDecoder::default);
+
+        let result: Result<(), Error> = async {
+            while let Some(frame) = body.frame().await {
+                let frame = match frame?.into_data() {
+                    Ok(data) => {
+                        match &mut text {
+                            Some(text) => parser.push(&text.push(&data)?),
+                            None => parser.push(&data),
+                        }
+
+                        while let Some((flags, message)) = parser.next_message()? {
+                            let Some(message) = handle_frame(
+                                &mut handler,
+                                &ctx,
+                                &encoding,
+                                flags,
+                                message,
+                                max_message_size,
+                            )
+                            .await?
+                            else {
+                                continue;
+                            };
+
+                            let message = match text {
+                                Some(_) => web::encode_text(&message),
+                                None => message,
+                            };
+
+                            if tx.send(Ok(Frame::data(message))).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+
+                        continue;
+                    }
+                    Err(frame) => match frame.into_trailers() {
+                        Ok(trailers) => {
+                            parser.finish()?;
+                            Frame::trailers(handler.handle_trailers(&ctx, trailers).await)
+                        }
+                        Err(_) => continue,
+                    },
+                };
+
+                if tx.send(Ok(frame)).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            parser.finish()?;
+
+            if let Some(text) = &text {
+                text.finish()?;
+            }
+
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = tx.send(Err(e)).await;
+        }
+    };
+
+    tokio::spawn(fut.instrument(info_span!("grpc")));
+    Body::from(StreamBody::new(rx))
+}
+
+/// Handle a framed message, or the trailers that gRPC-Web and Connect send as
+/// the last message of a response.
+async fn handle_frame<G: GrpcHandler>(
+    handler: &mut G,
+    ctx: &GrpcContext,
+    encoding: &str,
+    flags: u8,
+    message: Bytes,
+    max_message_size: usize,
+) -> Result<Option<Bytes>, GrpcError> {
+    match ctx.protocol {
+        GrpcProtocol::GrpcWeb | GrpcProtocol::GrpcWebText if flags & web::TRAILERS_FLAG != 0 => {
+            let trailers = web::decode_trailers(&message);
+            let trailers = handler.handle_trailers(ctx, trailers).await;
+
+            Ok(Some(encode_message(
+                web::TRAILERS_FLAG,
+                &web::encode_trailers(&trailers),
+            )))
+        }
+        GrpcProtocol::ConnectStream if flags & connect::END_STREAM_FLAG != 0 => {
+            let message = if flags & COMPRESSED_FLAG != 0 {
+                match decompress(encoding, &message, max_message_size) {
+                    Some(message) => message?,
+                    None => return Ok(Some(encode_message(flags, &message))),
+                }
+            } else {
+                message
+            };
+
+            let (trailers, error) = connect::decode_end_stream(&message)?;
+            let trailers = handler.handle_trailers(ctx, trailers).await;
+
+            Ok(Some(encode_message(
+                connect::END_STREAM_FLAG,
+                &connect::encode_end_stream(&trailers, error),
+            )))
+        }
+        _ => handle_message(handler, ctx, encoding, flags, message, max_message_size).await,
+    }
+}
+
+async fn handle_message<G: GrpcHandler>(
+    handler: &mut G,
+    ctx: &GrpcContext,
+    encoding: &str,
+    flags: u8,
+    message: Bytes,
+    max_message_size: usize,
+) -> Result<Option<Bytes>, GrpcError> {
+    if flags & COMPRESSED_FLAG == 0 {
+        return Ok(handler
+            .handle_message(ctx, message)
+            .await
+            .map(|message| encode_message(flags, &message)));
+    }
+
+    let Some(decompressed) = decompress(encoding, &message, max_message_size) else {
+        return Ok(Some(encode_message(flags, &message)));
+    };
+
+    match handler.handle_message(ctx, decompressed?).await {
+        Some(message) => Ok(Some(encode_message(flags, &compress(encoding, &message)?))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoopHandler;
+    use futures::stream;
+
+    fn body(frames: Vec<Frame<Bytes>>) -> Body {
+        Body::from(StreamBody::new(stream::iter(
+            frames.into_iter().map(Ok::<_, Error>),
+        )))
+    }
+
+    fn ctx() -> HttpContext {
+        HttpContext::from_request(
+            &Request::new(Body::empty()),
+            "127.0.0.1:8080".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn splits_messages() {
+        let mut parser = MessageParser::new(8);
+        let data = [encode_message(0, b"ab"), encode_message(1, b"")].concat();
+
+        parser.push(&data[..3]);
+        assert_eq!(parser.next_message().unwrap(), None);
+        parser.push(&data[3..]);
+        assert_eq!(
+            parser.next_message().unwrap(),
+            Some((0, Bytes::from_static(b"ab")))
+        );
+        assert_eq!(parser.next_message().unwrap(), Some((1, Bytes::new())));
+        assert!(parser.finish().is_ok());
+
+        parser.push(&encode_message(0, b"too long!"));
+        assert!(matches!(
+            parser.next_message(),
+            Err(GrpcError::MessageTooLarge(9))
+        ));
+    }
+
+    #[test]
+    fn round_trips_compression() {
+        for encoding in ["gzip", "deflate", "zstd"] {
+            let compressed = compress(encoding, b"hello").unwrap();
+
+            assert_eq!(
+                &decompress(encoding, &compressed, 5).unwrap().unwrap()[..],
+                b"hello"
+            );
+            assert!(matches!(
+                decompress(encoding, &compressed, 4),
+                Some(Err(GrpcError::MessageTooLarge(5)))
+            ));
+        }
+
+        assert!(decompress("snappy", b"", 1).is_none());
+    }
+
+    #[test]
+    fn reads_status() {
+        let mut headers = HeaderMap::new();
+        GrpcStatus::new(5, Some("not found: 100%\n".to_owned())).write_headers(&mut headers);
+
+        assert_eq!(headers[GRPC_MESSAGE], "not found: 100%25%0A");
+        assert_eq!(
+            GrpcStatus::from_headers(&headers),
+            Some(GrpcStatus::new(5, Some("not found: 100%\n".to_owned())))
+        );
+    }
+
+    #[derive(Clone)]
+    struct Handler;
+
+    impl GrpcHandler for Handler {
+        async fn handle_message(&mut self, ctx: &GrpcContext, message: Bytes) -> Option<Bytes> {
+            assert_eq!(ctx.service, "test.Echo");
+            assert_eq!(ctx.method, "Say");
+
+            match &message[..] {
+                b"drop" => None,
+                _ => Some(Bytes::from([&message[..], b"!"].concat())),
+            }
+        }
+
+        async fn handle_trailers(
+            &mut self,
+            _ctx: &GrpcContext,
+            mut trailers: HeaderMap,
+        ) -> HeaderMap {
+            GrpcStatus::new(0, None).write_headers(&mut trailers);
+            trailers
+        }
+    }
+
+    #[tokio::test]
+    async fn intercepts_calls() {
+        let mut layer = GrpcLayer::new(NoopHandler::default(), Handler);
+
+        let req = Request::builder()
+            .uri("http://example.com/test.Echo/Say")
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(body(vec![Frame::data(encode_message(0, b"hi"))]))
+            .unwrap();
+
+        let RequestOrResponse::Request(req) = layer.handle_request(&ctx(), req).await else {
+            panic!("Expected request");
+        };
+
+        assert_eq!(
+            &req.into_body().collect().await.unwrap().to_bytes()[..],
+            &encode_message(0, b"hi!")[..]
+        );
+
+        let mut trailers = HeaderMap::new();
+        GrpcStatus::new(13, Some("internal".to_owned())).write_headers(&mut trailers);
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/grpc+proto")
+            .header(GRPC_ENCODING, "gzip")
+            .body(body(vec![
+                Frame::data(encode_message(1, &compress("gzip", b"ok").unwrap())),
+                Frame::data(encode_message(0, b"drop")),
+                Frame::trailers(trailers),
+            ]))
+            .unwrap();
+
+        let collected = layer
+            .handle_response(&ctx(), res)
+            .await
+            .into_body()
+            .collect()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            GrpcStatus::from_headers(collected.trailers().unwrap()),
+            Some(GrpcStatus::new(0, None))
+        );
+
+        let mut parser = MessageParser::new(1024);
+        parser.push(&collected.to_bytes());
+        let (flags, message) = parser.next_message().unwrap().unwrap();
+
+        assert_eq!(flags, COMPRESSED_FLAG);
+        assert_eq!(
+            &decompress("gzip", &message, 1024).unwrap().unwrap()[..],
+            b"ok!"
+        );
+        assert_eq!(parser.next_message().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn fails_incomplete_messages() {
+        let mut layer = GrpcLayer::new(NoopHandler::default(), Handler);
+
+        let req = Request::builder()
+            .uri("http://example.com/test.Echo/Say")
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(body(vec![Frame::data(Bytes::from_static(
+                b"\0\0\0\0\x05hi",
+            ))]))
+            .unwrap();
+
+        let RequestOrResponse::Request(req) = layer.handle_request(&ctx(), req).await else {
+            panic!("Expected request");
+        };
+
+        assert!(matches!(
+            req.into_body().collect().await,
+            Err(Error::Grpc(GrpcError::Incomplete))
+        ));
+    }
+
+    #[tokio::test]
+    async fn intercepts_grpc_web_text() {
+        let mut layer = GrpcLayer::new(NoopHandler::default(), Handler);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("http://example.com/test.Echo/Say")
+            .header(CONTENT_TYPE, "application/grpc-web-text")
+            .body(Body::from(web::encode_text(&encode_message(0, b"hi"))))
+            .unwrap();
+        let _ = layer.handle_request(&ctx(), req).await;
+
+        let body = [
+            web::encode_text(&encode_message(0, b"ok")),
+            web::encode_text(&encode_message(
+                web::TRAILERS_FLAG,
+                b"grpc-status: 13\r\nx-a: 1\r\n",
+            )),
+        ]
+        .concat();
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/grpc-web-text+proto")
+            .body(Body::from(Bytes::from(body)))
+            .unwrap();
+
+        let body = layer
+            .handle_response(&ctx(), res)
+            .await
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+
+        let mut parser = MessageParser::new(1024);
+        parser.push(&web::TextDecoder::default().push(&body).unwrap());
+
+        assert_eq!(
+            parser.next_message().unwrap(),
+            Some((0, Bytes::from_static(b"ok!")))
+        );
+
+        let (flags, trailers) = parser.next_message().unwrap().unwrap();
+        let trailers = web::decode_trailers(&trailers);
+
+        assert_eq!(flags, web::TRAILERS_FLAG);
+        assert_eq!(trailers["x-a"], "1");
+        assert_eq!(
+            GrpcStatus::from_headers(&trailers),
+            Some(GrpcStatus::new(0, None))
+        );
+    }
+
+    #[tokio::test]
+    async fn intercepts_connect_streams() {
+        let mut layer = GrpcLayer::new(NoopHandler::default(), Handler);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("http://example.com/test.Echo/Say")
+            .header(CONTENT_TYPE, "application/connect+proto")
+            .header("connect-content-encoding", "gzip")
+            .body(Body::from(encode_message(
+                1,
+                &compress("gzip", b"hi").unwrap(),
+            )))
+            .unwrap();
+        let _ = layer.handle_request(&ctx(), req).await;
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/connect+proto")
+            .body(Body::from(Bytes::from(
+                [
+                    encode_message(0, b"ok"),
+                    encode_message(
+                        connect::END_STREAM_FLAG,
+                        br#"{"error":{"code":"internal"}}"#,
+                    ),
+                ]
+                .concat(),
+            )))
+            .unwrap();
+
+        let body = layer
+            .handle_response(&ctx(), res)
+            .await
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+
+        let mut parser = MessageParser::new(1024);
+        parser.push(&body);
+
+        assert_eq!(
+            parser.next_message().unwrap(),
+            Some((0, Bytes::from_static(b"ok!")))
+        );
+        assert_eq!(
+            parser.next_message().unwrap(),
+            Some((connect::END_STREAM_FLAG, Bytes::from_static(b"{}")))
+        );
+    }
+
+    #[tokio::test]
+    async fn intercepts_connect_unary_calls() {
+        let mut layer = GrpcLayer::new(NoopHandler::default(), Handler);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("http://example.com/test.Echo/Say")
+            .header(CONTENT_TYPE, "application/proto")
+            .header(CONTENT_LENGTH, 2)
+            .header("connect-protocol-version", "1")
+            .body(Body::from("hi"))
+            .unwrap();
+
+        let RequestOrResponse::Request(req) = layer.handle_request(&ctx(), req).await else {
+            panic!("Expected request");
+        };
+
+        assert_eq!(
+            &req.into_body().collect().await.unwrap().to_bytes()[..],
+            b"hi!"
+        );
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/proto")
+            .header("trailer-x-a", "1")
+            .body(Body::from("ok"))
+            .unwrap();
+
+        let res = layer.handle_response(&ctx(), res).await;
+
+        assert_eq!(res.headers()["trailer-x-a"], "1");
+        assert!(!res.headers().contains_key("trailer-grpc-status"));
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"ok!"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use super::{GrpcContext, GrpcDirection, GrpcError};
+use crate::Error;
+use hyper::body::Bytes;
+use prost_reflect::{DescriptorPool, DynamicMessage, MethodDescriptor, prost::Message};
+
+/// Descriptors of gRPC services, used to decode messages as dynamic protobuf
+/// messages.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{GrpcContext, GrpcDescriptors, GrpcHandler, hyper::body::Bytes};
+///
+/// #[derive(Clone)]
+/// struct LogHandler {
+///     descriptors: GrpcDescriptors,
+/// }
+///
+/// impl GrpcHandler for LogHandler {
+///     async fn handle_message(&mut self, ctx: &GrpcContext, message: Bytes) -> Option<Bytes> {
+///         if let Ok(decoded) = self.descriptors.decode_message(ctx, &message) {
+///             println!("{}/{}: {:?}", ctx.service, ctx.method, decoded);
+///         }
+///
+///         Some(message)
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcDescriptors {
+    pool: DescriptorPool,
+}
+
+impl GrpcDescriptors {
+    /// Create descriptors from an encoded `FileDescriptorSet`, such as the
+    /// output of `protoc --descriptor_set_out --include_imports`.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the descriptor set is invalid.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let pool = DescriptorPool::decode(bytes).map_err(GrpcError::from)?;
+        Ok(Self { pool })
+    }
+
+    /// Create descriptors from an existing pool.
+    pub fn from_pool(pool: DescriptorPool) -> Self {
+        Self { pool }
+    }
+
+    /// Get the descriptor of the method of a call.
+    pub fn method(&self, ctx: &GrpcContext) -> Option<MethodDescriptor> {
+        self.pool
+            .get_service_by_name(&ctx.service)?
+            .methods()
+            .find(|method| method.name() == ctx.method)
+    }
+
+    /// Decode a message of a call, as the input or output type of the method
+    /// depending on its direction.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the method is unknown, or if the message
+    /// does not match its type.
+    pub fn decode_message(
+        &self,
+        ctx: &GrpcContext,
+        message: &[u8],
+    ) -> Result<DynamicMessage, Error> {
+        let method = self
+            .method(ctx)
+            .ok_or_else(|| GrpcError::UnknownMethod(format!("{}/{}", ctx.service, ctx.method)))?;
+
+        let desc = match ctx.direction {
+            GrpcDirection::Request => method.input(),
+            GrpcDirection::Response => method.output(),
+        };
+
+        Ok(DynamicMessage::decode(desc, message).map_err(GrpcError::from)?)
+    }
+
+    /// Encode a message, such as a modified result of
+    /// [`decode_message`](Self::decode_message).
+    pub fn encode_message(&self, message: &DynamicMessage) -> Bytes {
+        Bytes::from(message.encode_to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, GrpcProtocol, HttpContext};
+    use hyper::{Request, Uri};
+    use prost_reflect::{
+        ReflectMessage,
+        Value,
+        prost_types::{
+            DescriptorProto,
+            FieldDescriptorProto,
+            FileDescriptorProto,
+            FileDescriptorSet,
+            MethodDescriptorProto,
+            ServiceDescriptorProto,
+            field_descriptor_proto::Type,
+        },
+    };
+
+    fn descriptors() -> GrpcDescriptors {
+        let message = |name: &str, field: &str| DescriptorProto {
+            name: Some(name.to_owned()),
+            field: vec![FieldDescriptorProto {
+                name: Some(field.to_owned()),
+                number: Some(1),
+                r#type: Some(Type::String as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("echo.proto".to_owned()),
+                package: Some("test".to_owned()),
+                message_type: vec![message("Ping", "text"), message("Pong", "reply")],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Echo".to_owned()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Say".to_owned()),
+                        input_type: Some(".test.Ping".to_owned()),
+                        output_type: Some(".test.Pong".to_owned()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+
+        GrpcDescriptors::decode(&set.encode_to_vec()).unwrap()
+    }
+
+    fn ctx(path: &str, direction: GrpcDirection) -> GrpcContext {
+        let mut ctx = GrpcContext::new(
+            &HttpContext::from_request(
+                &Request::new(Body::empty()),
+                "127.0.0.1:8080".parse().unwrap(),
+            ),
+            &Uri::try_from(path).unwrap(),
+            GrpcProtocol::Grpc,
+        );
+        ctx.direction = direction;
+        ctx
+    }
+
+    #[test]
+    fn decodes_messages() {
+        let descriptors = descriptors();
+
+        let ping = descriptors
+            .decode_message(
+                &ctx("/test.Echo/Say", GrpcDirection::Request),
+                b"\x0a\x02hi",
+            )
+            .unwrap();
+        assert_eq!(ping.descriptor().full_name(), "test.Ping");
+        assert_eq!(
+            *ping.get_field_by_name("text").unwrap(),
+            Value::String("hi".to_owned())
+        );
+
+        let mut pong = descriptors
+            .decode_message(&ctx("/test.Echo/Say", GrpcDirection::Response), b"")
+            .unwrap();
+        pong.set_field_by_name("reply", Value::String("ok".to_owned()));
+        assert_eq!(&descriptors.encode_message(&pong)[..], b"\x0a\x02ok");
+
+        assert!(matches!(
+            descriptors.decode_message(&ctx("/test.Echo/Shout", GrpcDirection::Request), b""),
+            Err(Error::Grpc(GrpcError::UnknownMethod(_)))
+        ));
+        assert!(matches!(
+            descriptors.decode_message(&ctx("/test.Echo/Say", GrpcDirection::Request), b"\x0a\x05"),
+            Err(Error::Grpc(GrpcError::Decode(_)))
+        ));
+    }
+}
@@ -0,0 +1,127 @@
+use super::GrpcError;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hyper::{
+    body::Bytes,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+
+/// Flag of the frame that holds the trailers of a gRPC-Web response.
+pub(super) const TRAILERS_FLAG: u8 = 0x80;
+
+/// Decoder for `grpc-web-text` bodies, which may be a concatenation of
+/// separately padded base64 strings.
+#[derive(Debug, Default)]
+pub(super) struct TextDecoder {
+    buf: Vec<u8>,
+}
+
+impl TextDecoder {
+    /// Decode a chunk of the body, keeping back an incomplete quantum.
+    pub(super) fn push(&mut self, data: &[u8]) -> Result<Bytes, GrpcError> {
+        self.buf
+            .extend(data.iter().filter(|b| !b.is_ascii_whitespace()));
+
+        let end = self.buf.len() - self.buf.len() % 4;
+        let mut out = Vec::with_capacity(end / 4 * 3);
+        let mut start = 0;
+
+        for i in (0..end).step_by(4) {
+            if self.buf[i + 3] == b'=' {
+                STANDARD.decode_vec(&self.buf[start..i + 4], &mut out)?;
+                start = i + 4;
+            }
+        }
+
+        STANDARD.decode_vec(&self.buf[start..end], &mut out)?;
+        self.buf.drain(..end);
+
+        Ok(Bytes::from(out))
+    }
+
+    pub(super) fn finish(&self) -> Result<(), GrpcError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(GrpcError::Incomplete)
+        }
+    }
+}
+
+/// Encode a chunk of a `grpc-web-text` body.
+pub(super) fn encode_text(data: &[u8]) -> Bytes {
+    Bytes::from(STANDARD.encode(data))
+}
+
+/// Parse the header block of a trailers frame.
+pub(super) fn decode_trailers(block: &[u8]) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+
+    for line in block.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            continue;
+        };
+
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(line[..colon].trim_ascii()),
+            HeaderValue::from_bytes(line[colon + 1..].trim_ascii()),
+        ) {
+            trailers.append(name, value);
+        }
+    }
+
+    trailers
+}
+
+/// Serialize trailers into the header block of a trailers frame.
+pub(super) fn encode_trailers(trailers: &HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b": ");
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_concatenated_text() {
+        let body = [encode_text(b"ab"), encode_text(b"cdef"), encode_text(b"g")].concat();
+
+        for size in 1..=body.len() {
+            let mut decoder = TextDecoder::default();
+            let mut out = Vec::new();
+
+            for chunk in body.chunks(size) {
+                out.extend_from_slice(&decoder.push(chunk).unwrap());
+            }
+
+            assert!(decoder.finish().is_ok());
+            assert_eq!(out, b"abcdefg");
+        }
+
+        let mut decoder = TextDecoder::default();
+        assert!(decoder.push(b"YW").unwrap().is_empty());
+        assert!(matches!(decoder.finish(), Err(GrpcError::Incomplete)));
+        assert!(matches!(decoder.push(b"!!"), Err(GrpcError::Base64(_))));
+    }
+
+    #[test]
+    fn round_trips_trailers() {
+        let trailers =
+            decode_trailers(b"Grpc-Status: 0\r\ngrpc-message:  ok \r\nx-a: 1\r\nx-a: 2\r\n");
+
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "ok");
+        assert_eq!(trailers.get_all("x-a").iter().count(), 2);
+        assert_eq!(decode_trailers(&encode_trailers(&trailers)), trailers);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::rewind::Rewind;
+use hyper::{
+    Request,
+    Version,
+    header::{
+        CONNECTION,
+        HOST,
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+        TE,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    },
+    http::uri::Scheme,
+};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Connection preface sent by HTTP/2 clients.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+/// Headers that are specific to an HTTP/1.x connection, and are not allowed in
+/// HTTP/2.
+const CONNECTION_HEADERS: [&str; 4] = [
+    "keep-alive",
+    "proxy-connection",
+    "http2-settings",
+    "upgrade",
+];
+
+const FRAME_HEADER_LEN: usize = 9;
+const MAX_FRAME_SIZE: usize = 16_384;
+
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Whether the request offers to upgrade a cleartext connection to HTTP/2.
+pub(crate) fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    req.version() == Version::HTTP_11
+        && req
+            .uri()
+            .scheme()
+            .is_none_or(|scheme| *scheme == Scheme::HTTP)
+        && req.uri().authority().is_some()
+        && req.headers().contains_key(HTTP2_SETTINGS)
+        && has_token(req.headers(), UPGRADE, "h2c")
+        && has_token(req.headers(), CONNECTION, "upgrade")
+}
+
+/// Remove the offer to upgrade to HTTP/2, so that the request is handled as an
+/// HTTP/1.1 request.
+pub(crate) fn remove_upgrade<B>(mut req: Request<B>) -> Request<B> {
+    let headers = req.headers_mut();
+    headers.remove(UPGRADE);
+    headers.remove(HTTP2_SETTINGS);
+
+    let tokens = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|t| {
+            !t.eq_ignore_ascii_case("upgrade") && !t.eq_ignore_ascii_case(HTTP2_SETTINGS.as_str())
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match HeaderValue::from_str(&tokens) {
+        Ok(value) if !tokens.is_empty() => {
+            headers.insert(CONNECTION, value);
+        }
+        _ => {
+            headers.remove(CONNECTION);
+        }
+    }
+
+    req
+}
+
+fn encode_int(buf: &mut Vec<u8>, mut value: usize, prefix: u32) {
+    let max = (1 << prefix) - 1;
+
+    if value < max {
+        buf.push(value as u8);
+        return;
+    }
+
+    buf.push(max as u8);
+    value -= max;
+
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn encode_field(buf: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    // Literal header field without indexing, with a literal name.
+    buf.push(0);
+    encode_int(buf, name.len(), 7);
+    buf.extend_from_slice(name);
+    encode_int(buf, value.len(), 7);
+    buf.extend_from_slice(value);
+}
+
+/// Encode the request that offered the upgrade as the frames that open stream
+/// 1 of the HTTP/2 connection.
+pub(crate) fn encode_request<B>(req: &Request<B>) -> Vec<u8> {
+    let uri = req.uri();
+    let mut block = Vec::new();
+
+    encode_field(&mut block, b":method", req.method().as_str().as_bytes());
+    encode_field(&mut block, b":scheme", b"http");
+    if let Some(authority) = uri.authority() {
+        encode_field(&mut block, b":authority", authority.as_str().as_bytes());
+    }
+    encode_field(
+        &mut block,
+        b":path",
+        uri.path_and_query().map_or("/", |p| p.as_str()).as_bytes(),
+    );
+
+    for (name, value) in req.headers() {
+        if name == CONNECTION
+            || name == HOST
+            || name == TRANSFER_ENCODING
+            || (name == TE && value != "trailers")
+            || CONNECTION_HEADERS.contains(&name.as_str())
+            || has_token(req.headers(), CONNECTION, name.as_str())
+        {
+            continue;
+        }
+
+        encode_field(&mut block, name.as_str().as_bytes(), value.as_bytes());
+    }
+
+    let mut frames = Vec::with_capacity(block.len() + FRAME_HEADER_LEN);
+    let chunks = block.chunks(MAX_FRAME_SIZE).count();
+
+    for (i, chunk) in block.chunks(MAX_FRAME_SIZE).enumerate() {
+        let (kind, mut flags) = match i {
+            0 => (HEADERS, END_STREAM),
+            _ => (CONTINUATION, 0),
+        };
+
+        if i + 1 == chunks {
+            flags |= END_HEADERS;
+        }
+
+        frames.extend_from_slice(&(chunk.len() as u32).to_be_bytes()[1..]);
+        frames.extend_from_slice(&[kind, flags]);
+        frames.extend_from_slice(&1u32.to_be_bytes());
+        frames.extend_from_slice(chunk);
+    }
+
+    frames
+}
+
+/// Read the connection preface of a client that upgraded to HTTP/2, and insert
+/// the frames of the upgrade request after its initial SETTINGS frame.
+pub(crate) async fn replay_request<I>(mut io: I, frames: Vec<u8>) -> io::Result<Rewind<I>>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+
+    let len = loop {
+        if buf.len() >= PREFACE.len() + FRAME_HEADER_LEN {
+            let header = &buf[PREFACE.len()..];
+
+            if !buf.starts_with(PREFACE) || header[3] != SETTINGS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid HTTP/2 connection preface",
+                ));
+            }
+
+            let len = PREFACE.len()
+                + FRAME_HEADER_LEN
+                + u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+
+            if buf.len() >= len {
+                break len;
+            }
+        }
+
+        if io.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    };
+
+    let mut pre = Vec::with_capacity(buf.len() + frames.len());
+    pre.extend_from_slice(&buf[..len]);
+    pre.extend_from_slice(&frames);
+    pre.extend_from_slice(&buf[len..]);
+
+    Ok(Rewind::new(io, pre.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Body;
+    use hyper::{Response, body::Incoming, service::service_fn};
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto::Builder as ServerBuilder,
+    };
+    use std::convert::Infallible;
+    use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+    fn upgrade_request() -> Request<()> {
+        Request::builder()
+            .uri("http://example.com/foo?bar")
+            .header(HOST, "example.com")
+            .header(CONNECTION, "Upgrade, HTTP2-Settings, x-hop")
+            .header(UPGRADE, "h2c")
+            .header(HTTP2_SETTINGS, "AAMAAABkAAQCAAAAAAIAAAAA")
+            .header("x-hop", "1")
+            .header("x-long", "a".repeat(1_000))
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let req = upgrade_request();
+        assert!(is_upgrade_request(&req));
+
+        let req = remove_upgrade(req);
+        assert!(!is_upgrade_request(&req));
+        assert_eq!(req.headers()[CONNECTION], "x-hop");
+        assert!(!req.headers().contains_key(UPGRADE));
+        assert!(!req.headers().contains_key(HTTP2_SETTINGS));
+
+        let (mut parts, ()) = upgrade_request().into_parts();
+        parts.uri = "https://example.com/".parse().unwrap();
+        assert!(!is_upgrade_request(&Request::from_parts(parts, ())));
+    }
+
+    #[tokio::test]
+    async fn replays_request_as_stream_1() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let io = replay_request(server, encode_request(&upgrade_request()))
+                .await
+                .unwrap();
+
+            let service = service_fn(move |req: Request<Incoming>| {
+                tx.send(req.map(|_| ())).unwrap();
+                async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+            });
+
+            ServerBuilder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(io), service)
+                .await
+        });
+
+        client.write_all(PREFACE).await.unwrap();
+        client
+            .write_all(&[0, 0, 0, SETTINGS, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.version(), Version::HTTP_2);
+        assert_eq!(req.uri(), "http://example.com/foo?bar");
+        assert_eq!(req.headers()["x-long"].len(), 1_000);
+        assert!(!req.headers().contains_key("x-hop"));
+        assert!(!req.headers().contains_key(UPGRADE));
+    }
+}
@@ -0,0 +1,642 @@
+use crate::{Body, Error, builder};
+use bytes::Buf;
+use futures::{SinkExt, channel::mpsc};
+use h3::{client::SendRequest, error::Code};
+use h3_quinn::OpenStreams;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    Request,
+    Response,
+    StatusCode,
+    Uri,
+    Version,
+    body::{Bytes, Frame},
+    header::{
+        CONNECTION,
+        HOST,
+        HeaderMap,
+        HeaderName,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    },
+    http::uri::Scheme,
+};
+use quinn::{Endpoint, crypto::rustls::QuicClientConfig};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tokio_rustls::rustls::{ClientConfig, crypto::CryptoProvider, version::TLS13};
+use tracing::{debug, error};
+
+const ALT_SVC: HeaderName = HeaderName::from_static("alt-svc");
+
+/// How long an alternative advertised without a `ma` parameter stays fresh.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long HTTP/3 is not tried again for an origin after a QUIC connection to
+/// it failed.
+const BROKEN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Settings for speaking HTTP/3 with upstream servers.
+///
+/// Requests are sent over HTTP/3 to hosts added with
+/// [`with_host`](Self::with_host), and to origins that advertised HTTP/3 with
+/// an `alt-svc` header in an earlier response. When a QUIC connection cannot be
+/// established, the request falls back to TCP and HTTP/3 is not tried again for
+/// that origin for a while. Clients keep talking HTTP/1.1 or HTTP/2 to the
+/// proxy either way.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Http3Config, rustls::crypto::aws_lc_rs};
+/// use std::time::Duration;
+///
+/// let config = Http3Config::new(aws_lc_rs::default_provider())
+///     .with_host("cloudflare-quic.com")
+///     .with_connect_timeout(Duration::from_secs(1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Http3Config {
+    provider: Arc<CryptoProvider>,
+    tls_config: Option<ClientConfig>,
+    hosts: HashSet<String>,
+    alt_svc: bool,
+    connect_timeout: Duration,
+}
+
+impl Http3Config {
+    /// Default time to wait for a QUIC connection to be established.
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+    /// Create settings that verify upstream servers against the Mozilla root
+    /// certificates, using the given crypto provider.
+    pub fn new(provider: CryptoProvider) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            tls_config: None,
+            hosts: HashSet::new(),
+            alt_svc: true,
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Always try HTTP/3 first for a host, without waiting for it to be
+    /// advertised.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.insert(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Set whether HTTP/3 alternatives advertised with `alt-svc` headers are
+    /// used. This is enabled by default.
+    pub fn with_alt_svc(mut self, enabled: bool) -> Self {
+        self.alt_svc = enabled;
+        self
+    }
+
+    /// Set the time to wait for a QUIC connection to be established before
+    /// falling back to TCP.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Use a custom TLS configuration for QUIC connections. Its ALPN protocols
+    /// are replaced with `h3`.
+    pub fn with_tls_config(mut self, config: ClientConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    fn quic_config(self) -> Result<quinn::ClientConfig, builder::Error> {
+        use hyper_rustls::ConfigBuilderExt;
+
+        let mut tls_config = match self.tls_config {
+            Some(config) => config,
+            None => ClientConfig::builder_with_provider(self.provider)
+                .with_protocol_versions(&[&TLS13])?
+                .with_webpki_roots()
+                .with_no_client_auth(),
+        };
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+        Ok(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls_config)
+                .map_err(|e| builder::Error::Quic(Box::new(e)))?,
+        )))
+    }
+}
+
+/// An HTTP/3 alternative for an origin.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Alternative {
+    host: String,
+    port: u16,
+}
+
+/// Origins are identified by their host and port, as only `https` origins can
+/// use HTTP/3.
+type Origin = (String, u16);
+
+fn origin(uri: &Uri) -> Option<Origin> {
+    if uri.scheme() != Some(&Scheme::HTTPS) {
+        return None;
+    }
+
+    let host = uri.host()?.to_ascii_lowercase();
+    Some((host, uri.port_u16().unwrap_or(443)))
+}
+
+/// Parse an `alt-svc` header, returning the first HTTP/3 alternative along
+/// with how long it stays fresh. `None` is returned for `clear`.
+fn parse_alt_svc(origin: &Origin, value: &str) -> Option<(Alternative, Duration)> {
+    if value.trim() == "clear" {
+        return None;
+    }
+
+    value.split(',').find_map(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        let (protocol, authority) = params.next()?.split_once('=')?;
+
+        if protocol != "h3" {
+            return None;
+        }
+
+        let (host, port) = authority.trim_matches('"').rsplit_once(':')?;
+        let host = match host {
+            "" => origin.0.clone(),
+            host => host.trim_matches(['[', ']']).to_ascii_lowercase(),
+        };
+
+        let max_age = params
+            .filter_map(|param| param.strip_prefix("ma="))
+            .find_map(|ma| ma.parse().ok())
+            .map_or(DEFAULT_MAX_AGE, Duration::from_secs);
+
+        Some((
+            Alternative {
+                host,
+                port: port.parse().ok()?,
+            },
+            max_age,
+        ))
+    })
+}
+
+/// Remove headers that are specific to a single HTTP/1.x connection.
+fn remove_connection_headers(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in named {
+        headers.remove(name);
+    }
+
+    for name in [
+        CONNECTION,
+        HOST,
+        PROXY_AUTHORIZATION,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+
+    if headers.get(TE).is_some_and(|te| te != "trailers") {
+        headers.remove(TE);
+    }
+}
+
+/// Client that sends requests to upstream servers over HTTP/3.
+pub(crate) struct Http3Client {
+    endpoint: Endpoint,
+    quic_config: quinn::ClientConfig,
+    hosts: HashSet<String>,
+    alt_svc: bool,
+    connect_timeout: Duration,
+    alternatives: Mutex<HashMap<Origin, (Alternative, Instant)>>,
+    broken: Mutex<HashMap<Origin, Instant>>,
+    connections: Mutex<HashMap<Alternative, SendRequest<OpenStreams, Bytes>>>,
+}
+
+impl Http3Client {
+    pub(crate) fn new(config: Http3Config) -> Result<Self, Error> {
+        let hosts = config.hosts.clone();
+        let alt_svc = config.alt_svc;
+        let connect_timeout = config.connect_timeout;
+        let quic_config = config.quic_config()?;
+        let endpoint = Endpoint::client(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+            .or_else(|_| Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0))))?;
+
+        Ok(Self {
+            endpoint,
+            quic_config,
+            hosts,
+            alt_svc,
+            connect_timeout,
+            alternatives: Mutex::default(),
+            broken: Mutex::default(),
+            connections: Mutex::default(),
+        })
+    }
+
+    /// Get the alternative to use for an origin, if HTTP/3 should be tried.
+    fn alternative(&self, origin: &Origin) -> Option<Alternative> {
+        let now = Instant::now();
+
+        {
+            let mut broken = self.broken.lock().unwrap();
+            match broken.get(origin) {
+                Some(until) if *until > now => return None,
+                Some(_) => {
+                    broken.remove(origin);
+                }
+                None => {}
+            }
+        }
+
+        if self.hosts.contains(&origin.0) {
+            return Some(Alternative {
+                host: origin.0.clone(),
+                port: origin.1,
+            });
+        }
+
+        let mut alternatives = self.alternatives.lock().unwrap();
+        match alternatives.get(origin) {
+            Some((alternative, until)) if *until > now => Some(alternative.clone()),
+            Some(_) => {
+                alternatives.remove(origin);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remember the HTTP/3 alternative advertised in a response.
+    pub(crate) fn record_alt_svc(&self, uri: &Uri, headers: &HeaderMap) {
+        if !self.alt_svc {
+            return;
+        }
+
+        let (Some(origin), Some(value)) = (origin(uri), headers.get(ALT_SVC)) else {
+            return;
+        };
+
+        let Ok(value) = value.to_str() else {
+            return;
+        };
+
+        let mut alternatives = self.alternatives.lock().unwrap();
+        match parse_alt_svc(&origin, value) {
+            Some((alternative, max_age)) => {
+                alternatives.insert(origin, (alternative, Instant::now() + max_age));
+            }
+            None => {
+                alternatives.remove(&origin);
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        origin: &Origin,
+        alternative: &Alternative,
+    ) -> Result<SendRequest<OpenStreams, Bytes>, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(send_request) = self.connections.lock().unwrap().get(alternative) {
+            return Ok(send_request.clone());
+        }
+
+        let addr = tokio::net::lookup_host((alternative.host.as_str(), alternative.port))
+            .await?
+            .next()
+            .ok_or("no addresses found")?;
+
+        // The certificate is verified for the origin, even when the alternative
+        // is on another host.
+        let connecting = self
+            .endpoint
+            .connect_with(self.quic_config.clone(), addr, &origin.0)?;
+        let connection = tokio::time::timeout(self.connect_timeout, connecting).await??;
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tokio::spawn(async move {
+            let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+            debug!("HTTP/3 connection closed: {}", e);
+        });
+
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(alternative.clone(), send_request.clone());
+
+        Ok(send_request)
+    }
+
+    /// Send a request over HTTP/3 if it should be. The request is given back
+    /// when it should be sent over TCP instead, either because HTTP/3 is not
+    /// used for its origin or because a connection could not be established.
+    pub(crate) async fn send(&self, req: Request<Body>) -> Result<Response<Body>, Request<Body>> {
+        let Some(origin) = origin(req.uri()) else {
+            return Err(req);
+        };
+
+        let Some(alternative) = self.alternative(&origin) else {
+            return Err(req);
+        };
+
+        let mut send_request = match self.connect(&origin, &alternative).await {
+            Ok(send_request) => send_request,
+            Err(e) => {
+                debug!(
+                    "Falling back to TCP for {}:{}, HTTP/3 connection failed: {}",
+                    origin.0, origin.1, e
+                );
+                self.broken
+                    .lock()
+                    .unwrap()
+                    .insert(origin, Instant::now() + BROKEN_TIMEOUT);
+                return Err(req);
+            }
+        };
+
+        let (parts, body) = req.into_parts();
+        let mut head = Request::from_parts(parts.clone(), ());
+        *head.version_mut() = Version::HTTP_3;
+        remove_connection_headers(head.headers_mut());
+
+        let stream = match send_request.send_request(head).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Falling back to TCP, HTTP/3 request failed: {}", e);
+                self.connections.lock().unwrap().remove(&alternative);
+                return Err(Request::from_parts(parts, body));
+            }
+        };
+
+        let (mut send, mut recv) = stream.split();
+
+        tokio::spawn(async move {
+            let mut body = body;
+
+            while let Some(frame) = body.frame().await {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("Failed to read request body: {}", e);
+                        send.stop_stream(Code::H3_REQUEST_CANCELLED);
+                        return;
+                    }
+                };
+
+                let sent = match frame.into_data() {
+                    Ok(data) => send.send_data(data).await,
+                    Err(frame) => match frame.into_trailers() {
+                        Ok(trailers) => return drop(send.send_trailers(trailers).await),
+                        Err(_) => Ok(()),
+                    },
+                };
+
+                if sent.is_err() {
+                    return;
+                }
+            }
+
+            let _ = send.finish().await;
+        });
+
+        let res = match recv.recv_response().await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("HTTP/3 request failed: {}", e);
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .expect("Failed to build response"));
+            }
+        };
+
+        let (mut tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            loop {
+                let frame = match recv.recv_data().await {
+                    Ok(Some(mut data)) => Frame::data(data.copy_to_bytes(data.remaining())),
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(Error::Http3(Box::new(e)))).await;
+                        return;
+                    }
+                };
+
+                if tx.send(Ok(frame)).await.is_err() {
+                    recv.stop_sending(Code::H3_REQUEST_CANCELLED);
+                    return;
+                }
+            }
+
+            match recv.recv_trailers().await {
+                Ok(Some(trailers)) => {
+                    let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = tx.send(Err(Error::Http3(Box::new(e)))).await;
+                }
+            }
+        });
+
+        Ok(res.map(|()| Body::from(StreamBody::new(rx))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> Origin {
+        ("example.com".to_owned(), 443)
+    }
+
+    #[test]
+    fn parses_alt_svc() {
+        assert_eq!(
+            parse_alt_svc(&origin(), r#"h3-29=":443", h3=":8443"; ma=60; persist=1"#),
+            Some((
+                Alternative {
+                    host: "example.com".to_owned(),
+                    port: 8443,
+                },
+                Duration::from_secs(60)
+            ))
+        );
+        assert_eq!(
+            parse_alt_svc(&origin(), r#"h3="Alt.example.com:443""#),
+            Some((
+                Alternative {
+                    host: "alt.example.com".to_owned(),
+                    port: 443,
+                },
+                DEFAULT_MAX_AGE
+            ))
+        );
+        assert_eq!(parse_alt_svc(&origin(), r#"h2=":443""#), None);
+        assert_eq!(parse_alt_svc(&origin(), "clear"), None);
+    }
+
+    #[cfg(feature = "rcgen-ca")]
+    mod server {
+        use super::*;
+        use quinn::crypto::rustls::QuicServerConfig;
+        use tokio_rustls::rustls::{
+            RootCertStore,
+            ServerConfig,
+            crypto::aws_lc_rs,
+            pki_types::PrivateKeyDer,
+        };
+
+        /// Start an HTTP/3 server that echoes the request body, returning its
+        /// address along with a client configuration that trusts it.
+        fn start_server() -> (SocketAddr, ClientConfig) {
+            let key = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+            let cert = key.cert.der().clone();
+            let private_key = PrivateKeyDer::try_from(key.signing_key.serialize_der()).unwrap();
+
+            let mut server_config =
+                ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                    .with_protocol_versions(&[&TLS13])
+                    .unwrap()
+                    .with_no_client_auth()
+                    .with_single_cert(vec![cert.clone()], private_key)
+                    .unwrap();
+            server_config.alpn_protocols = vec![b"h3".to_vec()];
+
+            let endpoint = Endpoint::server(
+                quinn::ServerConfig::with_crypto(Arc::new(
+                    QuicServerConfig::try_from(server_config).unwrap(),
+                )),
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+            )
+            .unwrap();
+            let addr = endpoint.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    let connection = incoming.await.unwrap();
+                    let mut connection = h3::server::Connection::<_, Bytes>::new(
+                        h3_quinn::Connection::new(connection),
+                    )
+                    .await
+                    .unwrap();
+
+                    tokio::spawn(async move {
+                        while let Ok(Some(resolver)) = connection.accept().await {
+                            let (req, mut stream) = resolver.resolve_request().await.unwrap();
+                            let mut body = Vec::new();
+
+                            while let Some(mut data) = stream.recv_data().await.unwrap() {
+                                body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+                            }
+
+                            let res = Response::builder()
+                                .header("x-path", req.uri().path())
+                                .body(())
+                                .unwrap();
+                            stream.send_response(res).await.unwrap();
+                            stream.send_data(Bytes::from(body)).await.unwrap();
+                            stream.finish().await.unwrap();
+                        }
+                    });
+                }
+            });
+
+            let mut roots = RootCertStore::empty();
+            roots.add(cert).unwrap();
+
+            let client_config =
+                ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                    .with_protocol_versions(&[&TLS13])
+                    .unwrap()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+
+            (addr, client_config)
+        }
+
+        fn client(tls_config: ClientConfig) -> Http3Client {
+            Http3Client::new(
+                Http3Config::new(aws_lc_rs::default_provider())
+                    .with_tls_config(tls_config)
+                    .with_connect_timeout(Duration::from_millis(500)),
+            )
+            .unwrap()
+        }
+
+        #[tokio::test]
+        async fn sends_requests_to_advertised_origins() {
+            let (addr, tls_config) = start_server();
+            let client = client(tls_config);
+            let uri: Uri = format!("https://localhost:{}/echo", addr.port())
+                .parse()
+                .unwrap();
+
+            let req = Request::post(uri.clone())
+                .body(Body::from("hello"))
+                .unwrap();
+            let req = client.send(req).await.unwrap_err();
+
+            let mut headers = HeaderMap::new();
+            headers.insert(ALT_SVC, format!("h3=\":{}\"", addr.port()).parse().unwrap());
+            client.record_alt_svc(&uri, &headers);
+
+            let res = client.send(req).await.unwrap();
+            assert_eq!(res.version(), Version::HTTP_3);
+            assert_eq!(res.headers()["x-path"], "/echo");
+
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "hello");
+        }
+
+        #[tokio::test]
+        async fn falls_back_when_connection_fails() {
+            let (_, tls_config) = start_server();
+            let client = client(tls_config);
+
+            // Nothing is listening on this port.
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = socket.local_addr().unwrap().port();
+            drop(socket);
+
+            let uri: Uri = format!("https://localhost:{port}/").parse().unwrap();
+            let mut headers = HeaderMap::new();
+            headers.insert(ALT_SVC, format!("h3=\":{port}\"").parse().unwrap());
+            client.record_alt_svc(&uri, &headers);
+
+            let req = Request::get(uri.clone()).body(Body::empty()).unwrap();
+            let req = client.send(req).await.unwrap_err();
+            assert_eq!(req.uri(), &uri);
+            assert_eq!(
+                client.alternative(&("localhost".to_owned(), port)),
+                None,
+                "origin should be marked as broken"
+            );
+        }
+    }
+}
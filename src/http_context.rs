@@ -1,10 +1,10 @@
-use http::{Method, Request, Uri};
+use http::{Method, Request, Uri, Version};
 use hyper::header::{
-    HeaderMap, HeaderValue, 
+    HeaderMap, 
     RANGE, IF_RANGE, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_MATCH, IF_UNMODIFIED_SINCE,
     ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, USER_AGENT, REFERER, HOST, 
     CONNECTION, CACHE_CONTROL, COOKIE, AUTHORIZATION, 
-    CONTENT_TYPE, CONTENT_LENGTH, ORIGIN, ETAG, LAST_MODIFIED
+    CONTENT_TYPE, CONTENT_LENGTH, ORIGIN
 };
 use chrono::{DateTime, ParseResult, Utc, format::{Parsed, StrftimeItems}};
 use std::{collections::HashMap, net::SocketAddr};
@@ -16,9 +16,18 @@ pub struct HttpContext {
     pub client_addr: SocketAddr,
     pub method: Method,
     pub uri: Uri,
+    /// The protocol version used by the client.
+    pub version: Version,
+    /// The protocol version negotiated with the upstream server. This is set
+    /// once a response has been received, before
+    /// [`HttpHandler::handle_response`](crate::HttpHandler::handle_response)
+    /// is called.
+    pub upstream_version: Option<Version>,
     pub host: Option<String>,
     pub user_agent: Option<String>,
     pub accept: Option<String>,
+    /// The client's `accept-encoding` header, before it is rewritten by an
+    /// accept-encoding policy.
     pub accept_encoding: Option<String>,
     pub accept_language: Option<String>,
     pub connection: Option<String>,
@@ -65,6 +74,8 @@ pub enum IfRangeHeader {
 impl std::fmt::Debug for HttpContext {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("HttpContext")
+            .field("version", &self.version)
+            .field("upstream_version", &self.upstream_version)
             .field("host", &self.host)
             .field("user_agent", &self.user_agent)
             .field("accept", &self.accept)
@@ -122,7 +133,9 @@ impl std::fmt::Debug for RangeHeader {
 impl HttpContext {
     pub fn from_request<T>(req: &Request<T>, client_addr: SocketAddr) -> Self 
     {
-        Self::from_headers(req.headers(), client_addr, req.method().clone(), req.uri().clone())
+        let mut ctx = Self::from_headers(req.headers(), client_addr, req.method().clone(), req.uri().clone());
+        ctx.version = req.version();
+        ctx
     }
     
     pub fn from_headers(headers: &HeaderMap, client_addr: SocketAddr, method: Method, uri: Uri) -> Self {
@@ -130,6 +143,8 @@ impl HttpContext {
             client_addr,
             method,
             uri,
+            version: Version::HTTP_11,
+            upstream_version: None,
             host: get_header_str(headers, HOST),
             user_agent: get_header_str(headers, USER_AGENT),
             accept: get_header_str(headers, ACCEPT),
@@ -313,7 +328,7 @@ fn parse_if_range_header(headers: &HeaderMap) -> Option<IfRangeHeader> {
 fn parse_date_header(headers: &HeaderMap, header_name: HeaderName) -> Option<DateTime<Utc>> {
     headers.get(header_name)
         .and_then(|v| v.to_str().ok())
-        .and_then(|s| parse_http_date(s))
+        .and_then(parse_http_date)
 }
 // Парсинг HTTP дат в форматах RFC 7231 (Section 7.1.1.1)
 pub fn parse_http_date(date_str: &str) -> Option<DateTime<Utc>> {
@@ -371,11 +386,6 @@ fn parse_http_date_manual(date_str: &str) -> Option<DateTime<Utc>> {
     None
 }
 
-// Форматирование даты в HTTP формат
-pub fn format_http_date(dt: &DateTime<Utc>) -> String {
-    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
-}
-
 fn parse_etag_list(headers: &HeaderMap, header_name: HeaderName) -> Option<Vec<String>> {
     let header_value = headers.get(header_name)?;
     let header_str = header_value.to_str().ok()?;
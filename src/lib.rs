@@ -5,17 +5,30 @@
 //! - Modify HTTP/S requests
 //! - Modify HTTP/S responses
 //! - Modify WebSocket messages
+//! - Observe and modify raw TCP tunnels
+//! - Learn which hosts pin certificates and tunnel them untouched
+//! - Emulate network conditions such as limited bandwidth, latency and
+//!   connection drops
+//! - Inject faults for chaos testing
 //!
 //! ## Features
 //!
-//! - `decoder`: Enables [`decode_request`] and [`decode_response`] helpers
-//!   (enabled by default).
+//! - `decoder`: Enables [`decode_request`], [`decode_response`],
+//!   [`encode_request`] and [`encode_response`] helpers (enabled by default).
 //! - `full`: Enables all features.
-//! - `http2`: Enables HTTP/2 support.
+//! - `grpc`: Enables [`GrpcLayer`] for intercepting individual gRPC, gRPC-Web
+//!   and Connect messages.
+//! - `grpc-reflect`: Enables [`GrpcDescriptors`] for decoding gRPC messages as
+//!   dynamic protobuf messages.
+//! - `http2`: Enables HTTP/2 support, including cleartext HTTP/2 (h2c).
+//! - `http3`: Enables [`Http3Config`] for speaking HTTP/3 with upstream
+//!   servers.
 //! - `native-tls-client`: Enables
 //!   [`ProxyBuilder::with_native_tls_connector`](builder::ProxyBuilder::with_native_tls_connector).
 //! - `openssl-ca`: Enables
 //!   [`OpensslAuthority`](certificate_authority::OpensslAuthority).
+//! - `payload`: Enables [`Payload`] parsers and builders for JSON, form and
+//!   multipart bodies, and streaming multipart, HTML and regex rewriting.
 //! - `rcgen-ca`: Enables
 //!   [`RcgenAuthority`](certificate_authority::RcgenAuthority) (enabled by
 //!   default).
@@ -23,20 +36,36 @@
 //!   [`ProxyBuilder::with_rustls_connector`](builder::ProxyBuilder::with_rustls_connector)
 //!   (enabled by default).
 
+mod alt_svc;
 mod body;
+mod client_hello;
 #[cfg(feature = "decoder")]
 mod decoder;
 mod error;
+mod fault;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http2")]
+mod h2c;
+#[cfg(feature = "http3")]
+mod http3;
+mod network_conditions;
 mod noop;
+mod passthrough;
+#[cfg(feature = "payload")]
+mod payload;
 mod proxy;
 mod rewind;
+mod sniff;
+mod sse;
+mod tap;
+mod tunnel;
 
 pub mod certificate_authority;
 mod http_context;
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use http::{Method};
-use hyper::{Request, Response, StatusCode, Uri};
+use hyper::{Method, Request, Response, StatusCode, Uri, body::Bytes};
 use std::net::SocketAddr;
 use tokio_tungstenite::tungstenite::{self, Message};
 use tracing::error;
@@ -46,17 +75,67 @@ pub use hyper;
 pub use hyper_util;
 #[cfg(feature = "openssl-ca")]
 pub use openssl;
+#[cfg(feature = "grpc-reflect")]
+pub use prost_reflect;
 #[cfg(feature = "rcgen-ca")]
 pub use rcgen;
+#[cfg(feature = "payload")]
+pub use serde_json;
 pub use tokio_rustls::rustls;
 pub use tokio_tungstenite;
 
-pub use body::Body;
+pub use alt_svc::{AltSvcAction, AltSvcPolicy};
+pub use body::{Body, LengthLimitError};
+pub use client_hello::ClientHello;
 #[cfg(feature = "decoder")]
-pub use decoder::{decode_request, decode_response};
+pub use decoder::{
+    AcceptEncodingPolicy,
+    DecodeLimitError,
+    DecodeLimits,
+    DictionaryStore,
+    Encoding,
+    decode_request,
+    decode_request_with_limits,
+    decode_response,
+    decode_response_with_limits,
+    encode_request,
+    encode_response,
+    reencode_request,
+    reencode_response,
+};
 pub use error::Error;
+pub use fault::{Fault, FaultHandler, FaultRule};
+#[cfg(feature = "grpc-reflect")]
+pub use grpc::GrpcDescriptors;
+#[cfg(feature = "grpc")]
+pub use grpc::{
+    GrpcContext, GrpcDirection, GrpcError, GrpcHandler, GrpcLayer, GrpcProtocol, GrpcStatus,
+};
+#[cfg(feature = "http3")]
+pub use http3::Http3Config;
+pub use network_conditions::{NetworkConditions, NetworkProfile};
 pub use noop::*;
+pub use passthrough::{AdaptivePassthrough, PassthroughHost};
+#[cfg(feature = "payload")]
+pub use payload::{
+    BodyReplacer,
+    CspAction,
+    HtmlRewriter,
+    Multipart,
+    MultipartForm,
+    MultipartRewriter,
+    NewPart,
+    Part,
+    PartAction,
+    Payload,
+    PayloadError,
+    PayloadLimits,
+};
 pub use proxy::*;
+pub use sniff::{Detection, Protocol, ProtocolDetector, ProtocolSniffer};
+pub use sse::SseEvent;
+pub use tap::{BodyObserver, TapSummary};
+pub use tunnel::{TlsStreamMode, TunnelAction, TunnelContext, TunnelDirection};
 pub use crate::http_context::HttpContext;
 
 /// Enum representing either an HTTP request or response.
@@ -102,6 +181,10 @@ pub enum WebSocketContext {
         src: SocketAddr,
         /// URI of the server.
         dst: Uri,
+        /// Identifier of the connection, shared by both directions.
+        id: u64,
+        /// Method of the request that opened the connection.
+        method: Method,
     },
     #[non_exhaustive]
     ServerToClient {
@@ -109,6 +192,10 @@ pub enum WebSocketContext {
         src: Uri,
         /// Address of the client.
         dst: SocketAddr,
+        /// Identifier of the connection, shared by both directions.
+        id: u64,
+        /// Method of the request that opened the connection.
+        method: Method,
     },
 }
 
@@ -130,6 +217,10 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
 
     /// This handler will be called for each HTTP response. It can modify a
     /// response before it is forwarded to the client.
+    ///
+    /// [`HttpContext::upstream_version`] holds the protocol version used with
+    /// the upstream server, while [`HttpContext::version`] is the one used by
+    /// the client.
     fn handle_response(
         &mut self,
         _ctx: &HttpContext,
@@ -156,6 +247,9 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
 
     /// Whether a CONNECT request should be intercepted. Defaults to `true` for
     /// all requests.
+    ///
+    /// The [`Protocol`] detected at the start of the tunnel is available in the
+    /// extensions of the request, along with the [`ClientHello`] for TLS.
     fn should_intercept(
         &mut self,
         _ctx: &HttpContext,
@@ -219,3 +313,79 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
         async { Some(message) }
     }
 }
+
+/// Handler for server-sent events.
+///
+/// Events of the same `text/event-stream` response are passed to the same
+/// instance of the handler, while the stream stays open.
+pub trait SseHandler: Clone + Send + Sync + 'static {
+    /// Whether the events of the given response should be passed to the
+    /// handler. If it returns false, the response will be forwarded as is.
+    fn should_intercept(
+        &mut self,
+        _ctx: &HttpContext,
+        _res: &Response<Body>,
+    ) -> impl Future<Output = bool> + Send {
+        async { true }
+    }
+
+    /// This handler will be called for each event. The returned events will
+    /// be forwarded in its place, so it can be dropped by returning no events,
+    /// or followed by injected events.
+    fn handle_event(
+        &mut self,
+        _ctx: &HttpContext,
+        event: SseEvent,
+    ) -> impl Future<Output = Vec<SseEvent>> + Send {
+        async { vec![event] }
+    }
+}
+
+/// Handler for raw tunnels.
+///
+/// This is used for CONNECT tunnels that are not intercepted, either because
+/// [`HttpHandler::should_intercept`] returned false or because the protocol
+/// spoken inside them is not understood by the proxy, and for decrypted TLS
+/// streams that are not HTTP. Chunks of both
+/// directions of the same tunnel are passed to the same instance of the
+/// handler, one at a time, as they are read.
+pub trait TunnelHandler: Clone + Send + Sync + 'static {
+    /// Whether the chunks of the given tunnel should be passed to the handler.
+    /// If it returns false, bytes will be copied between the client and the
+    /// server as is.
+    fn should_intercept(&mut self, _ctx: &TunnelContext) -> impl Future<Output = bool> + Send {
+        async { true }
+    }
+
+    /// How a TLS stream decrypted by the proxy should be handled. This is
+    /// called before the TLS handshake with the client, with the server name and
+    /// ALPN protocols it offered, and allows protocols such as IMAPS or MQTT
+    /// over TLS to be intercepted without being served as HTTP. Raw streams
+    /// negotiate the client's preferred ALPN protocol.
+    ///
+    /// [`NoopHandler`] always returns [`TlsStreamMode::Http`], so streams are
+    /// only bridged when a tunnel handler is configured.
+    fn tls_stream_mode(
+        &mut self,
+        _ctx: &TunnelContext,
+    ) -> impl Future<Output = TlsStreamMode> + Send {
+        async { TlsStreamMode::Detect }
+    }
+
+    /// This handler will be called for each chunk read from either side of the
+    /// tunnel. It can forward the chunk, modified or not, or close the tunnel.
+    fn handle_chunk(
+        &mut self,
+        _ctx: &TunnelContext,
+        _direction: TunnelDirection,
+        chunk: Bytes,
+    ) -> impl Future<Output = TunnelAction> + Send {
+        async { TunnelAction::Forward(chunk) }
+    }
+
+    /// This handler will be called once the tunnel is closed, when the byte
+    /// counters of the context hold their final values.
+    fn handle_close(&mut self, _ctx: &TunnelContext) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
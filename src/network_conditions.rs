@@ -0,0 +1,732 @@
+use crate::Error;
+use futures::Stream;
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Sleep, sleep},
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Characteristics of an emulated network link.
+///
+/// Bandwidths are expressed in bytes per second, a value of `None` leaves that
+/// direction unthrottled.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::NetworkProfile;
+/// use std::time::Duration;
+///
+/// let profile = NetworkProfile::new()
+///     .with_downstream_bandwidth(64 * 1024)
+///     .with_request_latency(Duration::from_millis(300));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkProfile {
+    /// Maximum throughput from the server to the client.
+    pub downstream_bandwidth: Option<u64>,
+    /// Maximum throughput from the client to the server.
+    pub upstream_bandwidth: Option<u64>,
+    /// Latency added before each request is forwarded.
+    pub request_latency: Duration,
+    /// Latency added before each chunk of data is delivered.
+    pub chunk_latency: Duration,
+    /// Probability, between `0.0` and `1.0`, that the connection is dropped
+    /// instead of delivering any given chunk.
+    pub drop_probability: f64,
+}
+
+impl NetworkProfile {
+    /// Create a profile that leaves traffic unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A typical 3G connection: 1.6 Mbit/s down, 750 kbit/s up and 150 ms of
+    /// request latency.
+    pub fn mobile_3g() -> Self {
+        Self::new()
+            .with_downstream_bandwidth(200_000)
+            .with_upstream_bandwidth(93_750)
+            .with_request_latency(Duration::from_millis(150))
+    }
+
+    /// A slow 3G connection: 400 kbit/s in both directions and 400 ms of
+    /// request latency.
+    pub fn slow_3g() -> Self {
+        Self::new()
+            .with_downstream_bandwidth(50_000)
+            .with_upstream_bandwidth(50_000)
+            .with_request_latency(Duration::from_millis(400))
+    }
+
+    /// An unreliable Wi-Fi connection: 5 Mbit/s down, 1 Mbit/s up, added
+    /// latency on every chunk and occasional connection drops.
+    pub fn flaky_wifi() -> Self {
+        Self::new()
+            .with_downstream_bandwidth(625_000)
+            .with_upstream_bandwidth(125_000)
+            .with_request_latency(Duration::from_millis(80))
+            .with_chunk_latency(Duration::from_millis(20))
+            .with_drop_probability(0.01)
+    }
+
+    /// Set the downstream bandwidth in bytes per second.
+    pub fn with_downstream_bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.downstream_bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Set the upstream bandwidth in bytes per second.
+    pub fn with_upstream_bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.upstream_bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Set the latency added before each request.
+    pub fn with_request_latency(mut self, latency: Duration) -> Self {
+        self.request_latency = latency;
+        self
+    }
+
+    /// Set the latency added before each chunk of data.
+    pub fn with_chunk_latency(mut self, latency: Duration) -> Self {
+        self.chunk_latency = latency;
+        self
+    }
+
+    /// Set the probability that the connection is dropped instead of
+    /// delivering a chunk.
+    pub fn with_drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+}
+
+/// Selects the [`NetworkProfile`] applied to proxied traffic.
+///
+/// Profiles registered for a client address take precedence over profiles
+/// registered for a host, which take precedence over the default profile.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{NetworkConditions, NetworkProfile};
+///
+/// let conditions = NetworkConditions::new()
+///     .with_default_profile(NetworkProfile::mobile_3g())
+///     .with_host_profile("example.com", NetworkProfile::flaky_wifi());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConditions {
+    default: Option<NetworkProfile>,
+    hosts: HashMap<String, NetworkProfile>,
+    clients: HashMap<IpAddr, NetworkProfile>,
+}
+
+impl NetworkConditions {
+    /// Create an empty set of network conditions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the profile used when no more specific profile matches.
+    pub fn with_default_profile(mut self, profile: NetworkProfile) -> Self {
+        self.default = Some(profile);
+        self
+    }
+
+    /// Set the profile used for traffic to the given host.
+    pub fn with_host_profile(mut self, host: impl Into<String>, profile: NetworkProfile) -> Self {
+        self.hosts.insert(host.into().to_ascii_lowercase(), profile);
+        self
+    }
+
+    /// Set the profile used for traffic from the given client.
+    pub fn with_client_profile(mut self, client: IpAddr, profile: NetworkProfile) -> Self {
+        self.clients.insert(client, profile);
+        self
+    }
+
+    /// Find the profile that applies to traffic between a client and a host.
+    pub fn profile_for(
+        &self,
+        client_addr: &SocketAddr,
+        host: Option<&str>,
+    ) -> Option<&NetworkProfile> {
+        self.clients
+            .get(&client_addr.ip())
+            .or_else(|| host.and_then(|host| self.hosts.get(&host.to_ascii_lowercase())))
+            .or(self.default.as_ref())
+    }
+}
+
+fn dropped() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "connection dropped by network emulation",
+    )
+}
+
+/// Largest chunk of data read or written by [`ThrottledIo`] per delay.
+const MAX_CHUNK_LEN: usize = 16 * 1024;
+
+/// Paces a single direction of traffic.
+#[derive(Debug, Default)]
+struct Pacer {
+    bandwidth: Option<u64>,
+    chunk_latency: Duration,
+    drop_probability: f64,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacer {
+    fn new(bandwidth: Option<u64>, profile: Option<&NetworkProfile>) -> Self {
+        match profile {
+            Some(profile) => Self {
+                bandwidth: bandwidth.filter(|bandwidth| *bandwidth > 0),
+                chunk_latency: profile.chunk_latency,
+                drop_probability: profile.drop_probability,
+                delay: None,
+            },
+            None => Self::default(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.bandwidth.is_some() || !self.chunk_latency.is_zero() || self.drop_probability > 0.0
+    }
+
+    /// Wait out the delay of a chunk before it is released, returning `false`
+    /// if the connection should be dropped instead.
+    ///
+    /// The chunk must be held by the caller until this returns ready. Chunks
+    /// without data, such as trailers, are released right away.
+    fn poll_release(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<bool> {
+        if len == 0 {
+            return Poll::Ready(true);
+        }
+
+        if self.delay.is_none() {
+            let mut delay = self.chunk_latency;
+
+            if let Some(bandwidth) = self.bandwidth {
+                delay += Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            }
+
+            if !delay.is_zero() {
+                self.delay = Some(Box::pin(sleep(delay)));
+            }
+        }
+
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        Poll::Ready(!(self.drop_probability > 0.0 && rand::random::<f64>() < self.drop_probability))
+    }
+}
+
+/// Applies a [`NetworkProfile`] to a body.
+#[derive(Debug)]
+pub(crate) struct Throttled<T> {
+    inner: T,
+    pacer: Pacer,
+    held: Option<Frame<Bytes>>,
+    dropped: bool,
+}
+
+impl<T> Throttled<T> {
+    /// Throttle traffic flowing from the client to the server.
+    pub(crate) fn upstream(inner: T, profile: Option<&NetworkProfile>) -> Self {
+        Self {
+            inner,
+            pacer: Pacer::new(profile.and_then(|p| p.upstream_bandwidth), profile),
+            held: None,
+            dropped: false,
+        }
+    }
+
+    /// Throttle traffic flowing from the server to the client.
+    pub(crate) fn downstream(inner: T, profile: Option<&NetworkProfile>) -> Self {
+        Self {
+            inner,
+            pacer: Pacer::new(profile.and_then(|p| p.downstream_bandwidth), profile),
+            held: None,
+            dropped: false,
+        }
+    }
+}
+
+impl<T> HttpBody for Throttled<T>
+where
+    T: HttpBody<Data = Bytes, Error = Error> + Unpin,
+{
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        if this.dropped {
+            return Poll::Ready(None);
+        }
+
+        let frame = match this.held.take() {
+            Some(frame) => frame,
+            None => match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                other => return Poll::Ready(other),
+            },
+        };
+
+        let len = frame.data_ref().map_or(0, Bytes::len);
+
+        match this.pacer.poll_release(cx, len) {
+            Poll::Ready(true) => Poll::Ready(Some(Ok(frame))),
+            Poll::Ready(false) => {
+                this.dropped = true;
+                Poll::Ready(Some(Err(Error::Io(dropped()))))
+            }
+            Poll::Pending => {
+                this.held = Some(frame);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.dropped || (self.held.is_none() && self.inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = self.inner.size_hint();
+        let held = self
+            .held
+            .as_ref()
+            .and_then(Frame::data_ref)
+            .map_or(0, |data| data.len() as u64);
+
+        if let Some(upper) = hint.upper() {
+            hint.set_upper(upper + held);
+        }
+
+        hint.set_lower(hint.lower() + held);
+        hint
+    }
+}
+
+/// Applies a [`NetworkProfile`] to a WebSocket message stream.
+#[derive(Debug)]
+pub(crate) struct ThrottledStream<T> {
+    inner: T,
+    pacer: Pacer,
+    held: Option<Message>,
+    dropped: bool,
+}
+
+impl<T> ThrottledStream<T> {
+    /// Throttle messages flowing from the client to the server.
+    pub(crate) fn upstream(inner: T, profile: Option<&NetworkProfile>) -> Self {
+        Self {
+            inner,
+            pacer: Pacer::new(profile.and_then(|p| p.upstream_bandwidth), profile),
+            held: None,
+            dropped: false,
+        }
+    }
+
+    /// Throttle messages flowing from the server to the client.
+    pub(crate) fn downstream(inner: T, profile: Option<&NetworkProfile>) -> Self {
+        Self {
+            inner,
+            pacer: Pacer::new(profile.and_then(|p| p.downstream_bandwidth), profile),
+            held: None,
+            dropped: false,
+        }
+    }
+}
+
+impl<T> Stream for ThrottledStream<T>
+where
+    T: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    type Item = Result<Message, tungstenite::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.dropped {
+            return Poll::Ready(None);
+        }
+
+        let message = match this.held.take() {
+            Some(message) => message,
+            None => match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                other => return Poll::Ready(other),
+            },
+        };
+
+        match this.pacer.poll_release(cx, message.len()) {
+            Poll::Ready(true) => Poll::Ready(Some(Ok(message))),
+            Poll::Ready(false) => {
+                this.dropped = true;
+                Poll::Ready(Some(Err(tungstenite::Error::Io(dropped()))))
+            }
+            Poll::Pending => {
+                this.held = Some(message);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Applies a [`NetworkProfile`] to a client connection.
+///
+/// Reads are treated as upstream traffic and writes as downstream traffic.
+#[derive(Debug)]
+pub(crate) struct ThrottledIo<T> {
+    inner: T,
+    read: Pacer,
+    write: Pacer,
+    /// Data read from the inner connection that is waiting to be released.
+    read_held: Option<Bytes>,
+    /// Data that has been released but not yet returned to the reader.
+    readable: Bytes,
+    /// Number of bytes whose delay has been waited out but that have not
+    /// been written yet.
+    write_released: usize,
+}
+
+impl<T> ThrottledIo<T> {
+    pub(crate) fn new(inner: T, profile: Option<&NetworkProfile>) -> Self {
+        Self {
+            inner,
+            read: Pacer::new(profile.and_then(|p| p.upstream_bandwidth), profile),
+            write: Pacer::new(profile.and_then(|p| p.downstream_bandwidth), profile),
+            read_held: None,
+            readable: Bytes::new(),
+            write_released: 0,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ThrottledIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read.is_active() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        if this.readable.is_empty() {
+            let data = match this.read_held.take() {
+                Some(data) => data,
+                None => {
+                    let mut data = vec![0; buf.remaining().min(MAX_CHUNK_LEN)];
+                    let mut read = ReadBuf::new(&mut data);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+
+                    let len = read.filled().len();
+
+                    if len == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    data.truncate(len);
+                    Bytes::from(data)
+                }
+            };
+
+            match this.read.poll_release(cx, data.len()) {
+                Poll::Ready(true) => this.readable = data,
+                Poll::Ready(false) => return Poll::Ready(Err(dropped())),
+                Poll::Pending => {
+                    this.read_held = Some(data);
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let len = this.readable.len().min(buf.remaining());
+        buf.put_slice(&this.readable.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ThrottledIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if !this.write.is_active() || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.write_released == 0 {
+            let len = buf.len().min(MAX_CHUNK_LEN);
+
+            if !ready!(this.write.poll_release(cx, len)) {
+                return Poll::Ready(Err(dropped()));
+            }
+
+            this.write_released = len;
+        }
+
+        let len = this.write_released.min(buf.len());
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.write_released -= written.min(this.write_released);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Body;
+    use http::{HeaderMap, HeaderValue};
+    use http_body_util::BodyExt;
+    use std::time::Instant;
+
+    mod profile_for {
+        use super::*;
+
+        fn client() -> SocketAddr {
+            "127.0.0.1:8080".parse().unwrap()
+        }
+
+        #[test]
+        fn no_profiles() {
+            let conditions = NetworkConditions::new();
+
+            assert_eq!(conditions.profile_for(&client(), Some("example.com")), None);
+        }
+
+        #[test]
+        fn falls_back_to_default() {
+            let conditions =
+                NetworkConditions::new().with_default_profile(NetworkProfile::slow_3g());
+
+            assert_eq!(
+                conditions.profile_for(&client(), Some("example.com")),
+                Some(&NetworkProfile::slow_3g())
+            );
+        }
+
+        #[test]
+        fn host_is_case_insensitive() {
+            let conditions = NetworkConditions::new()
+                .with_default_profile(NetworkProfile::slow_3g())
+                .with_host_profile("Example.com", NetworkProfile::mobile_3g());
+
+            assert_eq!(
+                conditions.profile_for(&client(), Some("EXAMPLE.COM")),
+                Some(&NetworkProfile::mobile_3g())
+            );
+        }
+
+        #[test]
+        fn client_takes_precedence() {
+            let conditions = NetworkConditions::new()
+                .with_host_profile("example.com", NetworkProfile::mobile_3g())
+                .with_client_profile(client().ip(), NetworkProfile::flaky_wifi());
+
+            assert_eq!(
+                conditions.profile_for(&client(), Some("example.com")),
+                Some(&NetworkProfile::flaky_wifi())
+            );
+        }
+    }
+
+    mod throttled_body {
+        use super::*;
+
+        #[tokio::test]
+        async fn limits_bandwidth() {
+            let profile = NetworkProfile::new().with_downstream_bandwidth(1_000);
+            let body = Throttled::downstream(
+                Body::from_stream(futures::stream::iter(vec![
+                    Ok::<_, Error>(Bytes::from_static(&[0; 50])),
+                    Ok(Bytes::from_static(&[0; 50])),
+                ])),
+                Some(&profile),
+            );
+
+            let start = Instant::now();
+            let bytes = body.collect().await.unwrap().to_bytes();
+
+            assert_eq!(bytes.len(), 100);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+
+        #[tokio::test]
+        async fn delays_last_frame() {
+            let profile = NetworkProfile::new().with_downstream_bandwidth(1_000);
+            let mut body = Throttled::downstream(Body::from(&[0; 100][..]), Some(&profile));
+
+            // Stop at `is_end_stream` like hyper does, rather than waiting for
+            // `None`.
+            let start = Instant::now();
+            let mut len = 0;
+            while !body.is_end_stream() {
+                let frame = body.frame().await.unwrap().unwrap();
+                len += frame.into_data().unwrap().len();
+            }
+
+            assert_eq!(len, 100);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+
+        #[tokio::test]
+        async fn drops_connection() {
+            let profile = NetworkProfile::new().with_drop_probability(1.0);
+            let body = Throttled::upstream(Body::from("hello, world"), Some(&profile));
+
+            assert!(body.collect().await.is_err());
+        }
+
+        #[tokio::test]
+        async fn releases_trailers_right_away() {
+            let profile = NetworkProfile::new()
+                .with_chunk_latency(Duration::from_secs(1))
+                .with_drop_probability(1.0);
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            let body = Throttled::downstream(
+                Body::empty().with_trailers(trailers.clone()),
+                Some(&profile),
+            );
+
+            let start = Instant::now();
+            let collected = body.collect().await.unwrap();
+
+            assert_eq!(collected.trailers(), Some(&trailers));
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+
+        #[tokio::test]
+        async fn passes_through_without_profile() {
+            let body = Throttled::upstream(Body::from("hello, world"), None);
+
+            assert_eq!(
+                &body.collect().await.unwrap().to_bytes()[..],
+                b"hello, world"
+            );
+        }
+    }
+
+    mod throttled_stream {
+        use super::*;
+        use futures::StreamExt;
+
+        #[tokio::test]
+        async fn limits_bandwidth() {
+            let profile = NetworkProfile::new().with_upstream_bandwidth(1_000);
+            let stream = ThrottledStream::upstream(
+                futures::stream::iter(vec![Ok(Message::binary(vec![0; 100]))]),
+                Some(&profile),
+            );
+
+            let start = Instant::now();
+            let messages = stream.collect::<Vec<_>>().await;
+
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].as_ref().unwrap().len(), 100);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+
+        #[tokio::test]
+        async fn drops_connection() {
+            let profile = NetworkProfile::new().with_drop_probability(1.0);
+            let mut stream = ThrottledStream::downstream(
+                futures::stream::iter(vec![Ok(Message::text("hello")), Ok(Message::text("world"))]),
+                Some(&profile),
+            );
+
+            assert!(matches!(
+                stream.next().await,
+                Some(Err(tungstenite::Error::Io(_)))
+            ));
+            assert!(stream.next().await.is_none());
+        }
+    }
+
+    mod throttled_io {
+        use super::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        #[tokio::test]
+        async fn limits_read_bandwidth() {
+            let profile = NetworkProfile::new().with_upstream_bandwidth(1_000);
+            let (client, mut peer) = tokio::io::duplex(1024);
+            let mut io = ThrottledIo::new(client, Some(&profile));
+
+            peer.write_all(&[0; 100]).await.unwrap();
+            drop(peer);
+
+            let start = Instant::now();
+            let mut data = Vec::new();
+            io.read_to_end(&mut data).await.unwrap();
+
+            assert_eq!(data.len(), 100);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+
+        #[tokio::test]
+        async fn limits_write_bandwidth() {
+            let profile = NetworkProfile::new().with_downstream_bandwidth(1_000);
+            let (client, mut peer) = tokio::io::duplex(1024);
+            let mut io = ThrottledIo::new(client, Some(&profile));
+
+            let start = Instant::now();
+            io.write_all(&[0; 100]).await.unwrap();
+
+            assert!(start.elapsed() >= Duration::from_millis(100));
+
+            drop(io);
+            let mut data = Vec::new();
+            peer.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.len(), 100);
+        }
+
+        #[tokio::test]
+        async fn drops_connection() {
+            let profile = NetworkProfile::new().with_drop_probability(1.0);
+            let (client, _peer) = tokio::io::duplex(1024);
+            let mut io = ThrottledIo::new(client, Some(&profile));
+
+            let err = io.write_all(b"hello").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        }
+    }
+}
@@ -1,9 +1,20 @@
-use crate::{HttpHandler, WebSocketHandler};
+use crate::{
+    Body,
+    HttpContext,
+    HttpHandler,
+    SseHandler,
+    TlsStreamMode,
+    TunnelContext,
+    TunnelHandler,
+    WebSocketHandler,
+};
+use hyper::Response;
 
 /// A No-op handler.
 ///
-/// When using this handler, HTTP requests and responses and WebSocket messages
-/// will not be modified.
+/// When using this handler, HTTP requests and responses, WebSocket messages,
+/// server-sent events and tunnels will not be modified. Decrypted TLS streams
+/// are always served as HTTP.
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub struct NoopHandler(());
 
@@ -15,3 +26,19 @@ impl NoopHandler {
 
 impl HttpHandler for NoopHandler {}
 impl WebSocketHandler for NoopHandler {}
+
+impl SseHandler for NoopHandler {
+    async fn should_intercept(&mut self, _ctx: &HttpContext, _res: &Response<Body>) -> bool {
+        false
+    }
+}
+
+impl TunnelHandler for NoopHandler {
+    async fn should_intercept(&mut self, _ctx: &TunnelContext) -> bool {
+        false
+    }
+
+    async fn tls_stream_mode(&mut self, _ctx: &TunnelContext) -> TlsStreamMode {
+        TlsStreamMode::Http
+    }
+}
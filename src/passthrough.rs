@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio_rustls::rustls::{AlertDescription, Error as TlsError};
+
+/// A host that is tunneled without being intercepted.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PassthroughHost {
+    /// Name of the host, from the SNI of the client or the CONNECT request.
+    pub host: String,
+    /// Client the entry applies to, or `None` if it applies to all clients.
+    pub client: Option<IpAddr>,
+}
+
+impl PassthroughHost {
+    fn new(host: &str, client: Option<IpAddr>) -> Self {
+        Self {
+            host: host.to_ascii_lowercase(),
+            client,
+        }
+    }
+}
+
+struct State {
+    threshold: u32,
+    per_client: bool,
+    count_aborts: bool,
+    ttl: Option<Duration>,
+    failures: HashMap<PassthroughHost, u32>,
+    /// Passed through hosts, with the instant they expire at.
+    hosts: HashMap<PassthroughHost, Option<Instant>>,
+}
+
+impl State {
+    /// Whether the host is passed through, forgetting it if it expired.
+    fn contains(&mut self, host: &PassthroughHost) -> bool {
+        match self.hosts.get(host) {
+            Some(expiry) if is_live(*expiry) => true,
+            Some(_) => {
+                self.hosts.remove(host);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Learns which hosts reject the certificates generated by the proxy, and
+/// stops intercepting them.
+///
+/// Clients that pin certificates fail the TLS handshake with the proxy, and
+/// keep failing on every connection. Once a client has rejected the
+/// certificate for a host the configured number of times in a row, tunnels to
+/// that host are no longer intercepted, as if
+/// [`HttpHandler::should_intercept`](crate::HttpHandler::should_intercept)
+/// returned false.
+///
+/// Handshakes that the client aborts with a certificate related alert are
+/// counted. Handshakes where the client closes or resets the connection after
+/// sending its ClientHello without an alert, as some pinned clients do, can
+/// also be counted with
+/// [`with_count_aborted_handshakes`](Self::with_count_aborted_handshakes).
+/// Hosts are identified by the server name in the ClientHello when
+/// there is one, and by the authority of the CONNECT request otherwise.
+///
+/// Learned hosts are intercepted again once
+/// [`DEFAULT_TTL`](Self::DEFAULT_TTL) has elapsed, so that a client which
+/// keeps failing for another reason does not disable interception for good.
+///
+/// The policy can be cloned and kept around to inspect or edit the learned
+/// hosts while the proxy is running.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{AdaptivePassthrough, PassthroughHost};
+///
+/// let passthrough = AdaptivePassthrough::new()
+///     .with_threshold(2)
+///     .with_per_client(true);
+///
+/// passthrough.insert(PassthroughHost {
+///     host: "pinned.example.com".to_owned(),
+///     client: None,
+/// });
+///
+/// assert_eq!(passthrough.hosts().len(), 1);
+/// ```
+#[derive(Clone)]
+pub struct AdaptivePassthrough {
+    inner: Arc<Mutex<State>>,
+}
+
+impl AdaptivePassthrough {
+    /// Number of rejections after which a host is passed through by default.
+    pub const DEFAULT_THRESHOLD: u32 = 3;
+
+    /// How long learned hosts are passed through by default.
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Create a policy that passes hosts through for all clients once they
+    /// have been rejected [`DEFAULT_THRESHOLD`](Self::DEFAULT_THRESHOLD) times.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(State {
+                threshold: Self::DEFAULT_THRESHOLD,
+                per_client: false,
+                count_aborts: false,
+                ttl: Some(Self::DEFAULT_TTL),
+                failures: HashMap::new(),
+                hosts: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Set the number of rejections in a row after which a host is passed
+    /// through.
+    pub fn with_threshold(self, threshold: u32) -> Self {
+        self.lock().threshold = threshold.max(1);
+        self
+    }
+
+    /// Set whether hosts are learned separately for each client IP address,
+    /// so that a pinned app on one device does not affect other devices.
+    pub fn with_per_client(self, per_client: bool) -> Self {
+        self.lock().per_client = per_client;
+        self
+    }
+
+    /// Set whether handshakes that the client aborts without an alert, by
+    /// closing or resetting the connection after its ClientHello, are counted
+    /// as rejections. Disabled by default.
+    ///
+    /// Clients also abort handshakes when a page load is cancelled or when
+    /// racing connections, so this is best combined with
+    /// [`with_per_client`](Self::with_per_client), to keep a single client
+    /// from disabling interception of a host for everyone.
+    pub fn with_count_aborted_handshakes(self, count_aborts: bool) -> Self {
+        self.lock().count_aborts = count_aborts;
+        self
+    }
+
+    /// Set how long learned hosts are passed through before being intercepted
+    /// again, or `None` to pass them through until they are removed. Hosts
+    /// added with [`insert`](Self::insert) do not expire.
+    pub fn with_ttl(self, ttl: Option<Duration>) -> Self {
+        self.lock().ttl = ttl;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The hosts that are passed through.
+    pub fn hosts(&self) -> Vec<PassthroughHost> {
+        self.lock()
+            .hosts
+            .iter()
+            .filter(|(_, expiry)| is_live(**expiry))
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+
+    /// Pass a host through, for example to restore hosts learned in a previous
+    /// run.
+    pub fn insert(&self, host: PassthroughHost) {
+        let host = PassthroughHost::new(&host.host, host.client);
+        self.lock().hosts.insert(host, None);
+    }
+
+    /// Intercept a host again, returning whether it was passed through.
+    pub fn remove(&self, host: &PassthroughHost) -> bool {
+        let host = PassthroughHost::new(&host.host, host.client);
+        let mut state = self.lock();
+        state.failures.remove(&host);
+        state.hosts.remove(&host).is_some_and(is_live)
+    }
+
+    /// Forget all learned hosts and rejections.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.failures.clear();
+        state.hosts.clear();
+    }
+
+    /// Whether tunnels from the client to the host should be passed through.
+    pub(crate) fn is_passthrough(&self, client: IpAddr, host: &str) -> bool {
+        let mut state = self.lock();
+
+        state.contains(&PassthroughHost::new(host, None))
+            || state.contains(&PassthroughHost::new(host, Some(client)))
+    }
+
+    fn key(state: &State, client: IpAddr, host: &str) -> PassthroughHost {
+        PassthroughHost::new(host, state.per_client.then_some(client))
+    }
+
+    /// Record a failed TLS handshake, returning whether the host is now passed
+    /// through. `hello` is whether a ClientHello was read from the client.
+    pub(crate) fn record_failure(
+        &self,
+        client: IpAddr,
+        host: &str,
+        hello: bool,
+        error: &io::Error,
+    ) -> bool {
+        let mut state = self.lock();
+
+        let rejected = is_certificate_rejection(error)
+            || (state.count_aborts && hello && is_aborted_handshake(error));
+
+        if !rejected {
+            return false;
+        }
+
+        let key = Self::key(&state, client, host);
+        let failures = state.failures.entry(key.clone()).or_default();
+        *failures += 1;
+
+        if *failures < state.threshold {
+            return false;
+        }
+
+        state.failures.remove(&key);
+        let expiry = state.ttl.map(|ttl| Instant::now() + ttl);
+        !state.hosts.insert(key, expiry).is_some_and(is_live)
+    }
+
+    /// Record a successful TLS handshake, resetting the rejections of the host.
+    pub(crate) fn record_success(&self, client: IpAddr, host: &str) {
+        let mut state = self.lock();
+        let key = Self::key(&state, client, host);
+        state.failures.remove(&key);
+    }
+}
+
+impl Default for AdaptivePassthrough {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for AdaptivePassthrough {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.lock();
+
+        f.debug_struct("AdaptivePassthrough")
+            .field("threshold", &state.threshold)
+            .field("per_client", &state.per_client)
+            .field("count_aborts", &state.count_aborts)
+            .field("ttl", &state.ttl)
+            .field("hosts", &state.hosts)
+            .finish()
+    }
+}
+
+/// Whether a passed through host has not expired yet.
+fn is_live(expiry: Option<Instant>) -> bool {
+    expiry.is_none_or(|expiry| expiry > Instant::now())
+}
+
+/// Whether the handshake failed because the client did not accept the
+/// certificate.
+fn is_certificate_rejection(error: &io::Error) -> bool {
+    let Some(error) = error.get_ref().and_then(|e| e.downcast_ref::<TlsError>()) else {
+        return false;
+    };
+
+    matches!(
+        error,
+        TlsError::AlertReceived(
+            AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA
+        )
+    )
+}
+
+/// Whether the client closed or reset the connection during the handshake.
+fn is_aborted_handshake(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            TlsError::AlertReceived(AlertDescription::UnknownCA),
+        )
+    }
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn learns_hosts_after_threshold() {
+        let passthrough = AdaptivePassthrough::new().with_threshold(2);
+
+        assert!(!passthrough.record_failure(client(1), "Pinned.example.com", true, &rejection()));
+        assert!(!passthrough.is_passthrough(client(1), "pinned.example.com"));
+        assert!(passthrough.record_failure(client(2), "pinned.example.com", true, &rejection()));
+        assert!(passthrough.is_passthrough(client(3), "pinned.example.com"));
+
+        assert_eq!(
+            passthrough.hosts(),
+            [PassthroughHost {
+                host: "pinned.example.com".to_owned(),
+                client: None,
+            }]
+        );
+
+        assert!(passthrough.remove(&passthrough.hosts()[0]));
+        assert!(!passthrough.is_passthrough(client(3), "pinned.example.com"));
+    }
+
+    #[test]
+    fn learns_hosts_per_client() {
+        let passthrough = AdaptivePassthrough::new()
+            .with_threshold(1)
+            .with_per_client(true);
+
+        assert!(passthrough.record_failure(client(1), "pinned.example.com", true, &rejection()));
+        assert!(passthrough.is_passthrough(client(1), "pinned.example.com"));
+        assert!(!passthrough.is_passthrough(client(2), "pinned.example.com"));
+    }
+
+    #[test]
+    fn counts_aborted_handshakes() {
+        let passthrough = AdaptivePassthrough::new()
+            .with_threshold(2)
+            .with_count_aborted_handshakes(true);
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+
+        assert!(!passthrough.record_failure(client(1), "pinned.example.com", true, &eof));
+        assert!(passthrough.record_failure(client(1), "pinned.example.com", true, &reset));
+        assert!(passthrough.is_passthrough(client(1), "pinned.example.com"));
+    }
+
+    #[test]
+    fn ignores_aborted_handshakes_without_hello() {
+        let passthrough = AdaptivePassthrough::new()
+            .with_threshold(1)
+            .with_count_aborted_handshakes(true);
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+
+        assert!(!passthrough.record_failure(client(1), "example.com", false, &eof));
+        assert!(passthrough.hosts().is_empty());
+    }
+
+    #[test]
+    fn ignores_aborted_handshakes_by_default() {
+        let passthrough = AdaptivePassthrough::new().with_threshold(1);
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+
+        assert!(!passthrough.record_failure(client(1), "example.com", true, &eof));
+        assert!(passthrough.hosts().is_empty());
+    }
+
+    #[test]
+    fn expires_learned_hosts() {
+        let passthrough = AdaptivePassthrough::new()
+            .with_threshold(1)
+            .with_ttl(Some(Duration::ZERO));
+
+        passthrough.insert(PassthroughHost {
+            host: "inserted.example.com".to_owned(),
+            client: None,
+        });
+
+        assert!(passthrough.record_failure(client(1), "pinned.example.com", true, &rejection()));
+        assert!(!passthrough.is_passthrough(client(1), "pinned.example.com"));
+        assert!(passthrough.is_passthrough(client(1), "inserted.example.com"));
+        assert!(passthrough.record_failure(client(1), "pinned.example.com", true, &rejection()));
+    }
+
+    #[test]
+    fn ignores_other_failures() {
+        let passthrough = AdaptivePassthrough::new().with_threshold(2);
+        let failure = io::Error::new(
+            io::ErrorKind::InvalidData,
+            TlsError::AlertReceived(AlertDescription::HandshakeFailure),
+        );
+
+        assert!(!passthrough.record_failure(client(1), "example.com", true, &failure));
+        assert!(!passthrough.record_failure(client(1), "example.com", true, &rejection()));
+        passthrough.record_success(client(1), "example.com");
+        assert!(!passthrough.record_failure(client(1), "example.com", true, &rejection()));
+        assert!(passthrough.hosts().is_empty());
+    }
+}
@@ -0,0 +1,646 @@
+use super::{PayloadError, content_type, header_param};
+use crate::{Body, Error};
+use futures::Stream;
+use hyper::{
+    body::{Body as HttpBody, Bytes},
+    header::{
+        CONTENT_SECURITY_POLICY,
+        CONTENT_SECURITY_POLICY_REPORT_ONLY,
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+    },
+};
+use lol_html::{
+    AsciiCompatibleEncoding,
+    ElementContentHandlers,
+    RewriteStrSettings,
+    Selector,
+    html_content::{ContentType, Element},
+    rewrite_str,
+    send,
+};
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+};
+
+/// What to do with the `content-security-policy` of a rewritten page.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CspAction {
+    /// Leave the policy unchanged.
+    #[default]
+    Keep,
+    /// Remove the policy from the headers and from `<meta>` tags.
+    Remove,
+    /// Add a random nonce to the injected `<script>` and `<style>` elements,
+    /// and allow it in the policy. Directives that already allow any inline
+    /// content through `'unsafe-inline'` are left unchanged.
+    Nonce,
+}
+
+/// A streaming rewriter for HTML bodies.
+///
+/// Rewriters are applied with
+/// [`Payload::rewrite_html`](crate::Payload::rewrite_html). The body is
+/// decoded and rewritten as it is forwarded, in the charset from the
+/// `content-type` header or a `<meta>` tag.
+///
+/// Content is only injected into `<head>` and `<body>` elements that are
+/// present in the markup.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Body,
+///     CspAction,
+///     HtmlRewriter,
+///     HttpContext,
+///     HttpHandler,
+///     Payload,
+///     hyper::Response,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler {
+///     rewriter: HtmlRewriter,
+/// }
+///
+/// impl MyHandler {
+///     pub fn new() -> Self {
+///         let rewriter = HtmlRewriter::new()
+///             .with_head_end("<script src=\"https://debug.example/inject.js\"></script>")
+///             .with_body_start("<div id=\"proxy-banner\">Intercepted</div>")
+///             .with_attribute("a[target]", "target", "_self")
+///             .unwrap()
+///             .with_csp(CspAction::Nonce);
+///
+///         Self { rewriter }
+///     }
+/// }
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut res: Response<Body>,
+///     ) -> Response<Body> {
+///         let _ = res.rewrite_html(&self.rewriter);
+///         res
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HtmlRewriter {
+    head_end: String,
+    body_start: String,
+    attributes: Vec<(Selector, String, Option<String>)>,
+    csp: CspAction,
+}
+
+impl HtmlRewriter {
+    /// Create a rewriter that leaves pages unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert HTML before the closing `</head>` tag.
+    pub fn with_head_end(mut self, html: impl AsRef<str>) -> Self {
+        self.head_end.push_str(html.as_ref());
+        self
+    }
+
+    /// Insert HTML after the opening `<body>` tag.
+    pub fn with_body_start(mut self, html: impl AsRef<str>) -> Self {
+        self.body_start.push_str(html.as_ref());
+        self
+    }
+
+    /// Set an attribute on the elements matching a CSS selector.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the selector is not supported.
+    pub fn with_attribute(self, selector: &str, name: &str, value: &str) -> Result<Self, Error> {
+        self.attribute(selector, name, Some(value.to_owned()))
+    }
+
+    /// Remove an attribute from the elements matching a CSS selector.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the selector is not supported.
+    pub fn without_attribute(self, selector: &str, name: &str) -> Result<Self, Error> {
+        self.attribute(selector, name, None)
+    }
+
+    /// Set what to do with the `content-security-policy` of rewritten pages.
+    pub fn with_csp(mut self, csp: CspAction) -> Self {
+        self.csp = csp;
+        self
+    }
+
+    fn attribute(
+        mut self,
+        selector: &str,
+        name: &str,
+        value: Option<String>,
+    ) -> Result<Self, Error> {
+        let selector = selector.parse().map_err(PayloadError::from)?;
+        self.attributes.push((selector, name.to_owned(), value));
+        Ok(self)
+    }
+
+    /// Get the encoding of an HTML body, and whether it was declared in the
+    /// headers. Returns `None` if the body is not HTML, or its encoding cannot
+    /// be rewritten.
+    pub(super) fn encoding(headers: &HeaderMap) -> Option<(AsciiCompatibleEncoding, bool)> {
+        let value = content_type(headers)?;
+        let essence = value.split(';').next().unwrap_or_default().trim();
+
+        if !essence.eq_ignore_ascii_case("text/html") {
+            return None;
+        }
+
+        let declared = header_param(value, "charset")
+            .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()));
+        let encoding = AsciiCompatibleEncoding::new(declared.unwrap_or(encoding_rs::UTF_8))?;
+
+        Some((encoding, declared.is_some()))
+    }
+
+    /// Apply the CSP action to the policy headers, returning the nonce for the
+    /// injected content.
+    pub(super) fn rewrite_csp(&self, headers: &mut HeaderMap) -> Option<String> {
+        match self.csp {
+            CspAction::Keep => None,
+            CspAction::Remove => {
+                headers.remove(CONTENT_SECURITY_POLICY);
+                headers.remove(CONTENT_SECURITY_POLICY_REPORT_ONLY);
+                None
+            }
+            CspAction::Nonce => {
+                let nonce = format!("{:032x}", rand::random::<u128>());
+                add_header_nonce(headers, CONTENT_SECURITY_POLICY, &nonce);
+                add_header_nonce(headers, CONTENT_SECURITY_POLICY_REPORT_ONLY, &nonce);
+                Some(nonce)
+            }
+        }
+    }
+
+    pub(super) fn rewrite(
+        &self,
+        body: Body,
+        encoding: AsciiCompatibleEncoding,
+        declared: bool,
+        nonce: Option<String>,
+    ) -> Result<HtmlStream, Error> {
+        let (head_end, body_start) = match &nonce {
+            Some(nonce) => (
+                add_element_nonce(&self.head_end, nonce)?,
+                add_element_nonce(&self.body_start, nonce)?,
+            ),
+            None => (self.head_end.clone(), self.body_start.clone()),
+        };
+
+        let mut handlers = Vec::new();
+
+        if !head_end.is_empty() {
+            handlers.push((
+                Cow::Owned("head".parse().expect("Invalid selector")),
+                send::ElementContentHandlers::default().element(
+                    move |el: &mut send::Element<'_, '_>| {
+                        el.append(&head_end, ContentType::Html);
+                        Ok(())
+                    },
+                ),
+            ));
+        }
+
+        if !body_start.is_empty() {
+            handlers.push((
+                Cow::Owned("body".parse().expect("Invalid selector")),
+                send::ElementContentHandlers::default().element(
+                    move |el: &mut send::Element<'_, '_>| {
+                        el.prepend(&body_start, ContentType::Html);
+                        Ok(())
+                    },
+                ),
+            ));
+        }
+
+        for (selector, name, value) in &self.attributes {
+            let name = name.clone();
+            let value = value.clone();
+
+            handlers.push((
+                Cow::Borrowed(selector),
+                send::ElementContentHandlers::default().element(
+                    move |el: &mut send::Element<'_, '_>| {
+                        match &value {
+                            Some(value) => el.set_attribute(&name, value)?,
+                            None => el.remove_attribute(&name),
+                        }
+                        Ok(())
+                    },
+                ),
+            ));
+        }
+
+        if self.csp != CspAction::Keep {
+            handlers.push((
+                Cow::Owned("meta[http-equiv]".parse().expect("Invalid selector")),
+                send::ElementContentHandlers::default().element(
+                    move |el: &mut send::Element<'_, '_>| {
+                        let is_csp = el.get_attribute("http-equiv").is_some_and(|value| {
+                            value.eq_ignore_ascii_case(CONTENT_SECURITY_POLICY.as_str())
+                        });
+
+                        if is_csp {
+                            match &nonce {
+                                Some(nonce) => {
+                                    let policy = el.get_attribute("content").unwrap_or_default();
+                                    el.set_attribute("content", &add_nonce(&policy, nonce))?;
+                                }
+                                None => el.remove(),
+                            }
+                        }
+
+                        Ok(())
+                    },
+                ),
+            ));
+        }
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&output);
+        let sink: Sink = Box::new(move |chunk: &[u8]| {
+            sink.lock()
+                .expect("Failed to lock output")
+                .extend_from_slice(chunk)
+        });
+
+        let rewriter = send::HtmlRewriter::new(
+            send::Settings {
+                element_content_handlers: handlers,
+                encoding,
+                adjust_charset_on_meta_tag: !declared,
+                ..send::Settings::new_send()
+            },
+            sink,
+        );
+
+        Ok(HtmlStream {
+            body,
+            rewriter: Mutex::new(Some(rewriter)),
+            output,
+        })
+    }
+}
+
+/// Add a nonce to the `<script>` and `<style>` elements of an HTML fragment.
+fn add_element_nonce(html: &str, nonce: &str) -> Result<String, Error> {
+    if html.is_empty() {
+        return Ok(String::new());
+    }
+
+    let html = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![(
+                Cow::Owned("script, style".parse().expect("Invalid selector")),
+                ElementContentHandlers::default().element(|el: &mut Element<'_, '_>| {
+                    el.set_attribute("nonce", nonce)?;
+                    Ok(())
+                }),
+            )],
+            ..RewriteStrSettings::new()
+        },
+    )
+    .map_err(PayloadError::from)?;
+
+    Ok(html)
+}
+
+fn add_header_nonce(headers: &mut HeaderMap, name: HeaderName, nonce: &str) {
+    let values = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|policy| add_nonce(policy, nonce))
+        .filter_map(|policy| HeaderValue::from_str(&policy).ok())
+        .collect::<Vec<_>>();
+
+    headers.remove(&name);
+
+    for value in values {
+        headers.append(&name, value);
+    }
+}
+
+/// Allow a nonce for scripts and styles in a policy. Directives that fall back
+/// to `default-src` are only changed through it.
+///
+/// Directives that allow `'unsafe-inline'` without a nonce or hash are left
+/// unchanged, since they already allow the injected content and browsers
+/// ignore `'unsafe-inline'` once a nonce is present.
+fn add_nonce(policy: &str, nonce: &str) -> String {
+    let source = format!("'nonce-{nonce}'");
+    let directives = policy
+        .split(';')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .collect::<Vec<_>>();
+    let name = |directive: &str| {
+        directive
+            .split_ascii_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    };
+    let has = |wanted: &str| directives.iter().any(|directive| name(directive) == wanted);
+
+    directives
+        .iter()
+        .map(|directive| {
+            let allows = match name(directive).as_str() {
+                "script-src" | "script-src-elem" | "style-src" | "style-src-elem" => true,
+                "default-src" => !has("script-src") || !has("style-src"),
+                _ => false,
+            };
+
+            if !allows || allows_any_inline(directive) {
+                return directive.to_string();
+            }
+
+            let sources = directive
+                .split_ascii_whitespace()
+                .filter(|source| !source.eq_ignore_ascii_case("'none'"));
+
+            sources
+                .chain([source.as_str()])
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Whether a directive allows all inline content through `'unsafe-inline'`,
+/// which is only honoured when it has no nonce or hash.
+fn allows_any_inline(directive: &str) -> bool {
+    let mut unsafe_inline = false;
+
+    for source in directive.split_ascii_whitespace().skip(1) {
+        let source = source.to_ascii_lowercase();
+
+        if source == "'unsafe-inline'" {
+            unsafe_inline = true;
+        } else if ["'nonce-", "'sha256-", "'sha384-", "'sha512-"]
+            .iter()
+            .any(|prefix| source.starts_with(prefix))
+        {
+            return false;
+        }
+    }
+
+    unsafe_inline
+}
+
+type Sink = Box<dyn FnMut(&[u8]) + Send>;
+
+pub(super) struct HtmlStream {
+    body: Body,
+    // The rewriter isn't `Sync`, so it is only accessed through `get_mut`.
+    rewriter: Mutex<Option<send::HtmlRewriter<'static, Sink>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl HtmlStream {
+    fn take_output(&self) -> Option<Bytes> {
+        let mut output = self.output.lock().expect("Failed to lock output");
+
+        if output.is_empty() {
+            None
+        } else {
+            Some(Bytes::from(std::mem::take(&mut *output)))
+        }
+    }
+}
+
+impl Stream for HtmlStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(output) = this.take_output() {
+                return Poll::Ready(Some(Ok(output)));
+            }
+
+            let rewriter = this.rewriter.get_mut().expect("Failed to lock rewriter");
+
+            let Some(writer) = rewriter.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    if let Ok(data) = frame.into_data() {
+                        if let Err(err) = writer.write(&data) {
+                            *rewriter = None;
+                            return Poll::Ready(Some(Err(PayloadError::from(err).into())));
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    *rewriter = None;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => {
+                    if let Some(Err(err)) = rewriter.take().map(|writer| writer.end()) {
+                        return Poll::Ready(Some(Err(PayloadError::from(err).into())));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Payload;
+    use http_body_util::BodyExt;
+    use hyper::{Response, header::CONTENT_TYPE};
+
+    const PAGE: &str = "<html><head><title>t</title></head><body><a href=\"/\" \
+                        target=\"_blank\">x</a></body></html>";
+
+    fn page(body: &str) -> Body {
+        let chunks = body
+            .as_bytes()
+            .chunks(1)
+            .map(|chunk| Ok::<_, Error>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    async fn rewrite(mut res: Response<Body>, rewriter: &HtmlRewriter) -> (HeaderMap, String) {
+        res.rewrite_html(rewriter).unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (parts.headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn injects_content() {
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .body(page(PAGE))
+            .unwrap();
+        let rewriter = HtmlRewriter::new()
+            .with_head_end("<script>1</script>")
+            .with_body_start("<p>banner</p>")
+            .without_attribute("a", "target")
+            .unwrap();
+
+        let (_, body) = rewrite(res, &rewriter).await;
+
+        assert_eq!(
+            body,
+            "<html><head><title>t</title><script>1</script></head><body><p>banner</p><a \
+             href=\"/\">x</a></body></html>"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+        let mut res = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .body(page("<body></body>").with_trailers(trailers))
+            .unwrap();
+        let rewriter = HtmlRewriter::new().with_body_start("<p>banner</p>");
+
+        res.rewrite_html(&rewriter).unwrap();
+        let collected = res.into_body().collect().await.unwrap();
+
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc");
+        assert_eq!(&collected.to_bytes()[..], b"<body><p>banner</p></body>");
+    }
+
+    #[tokio::test]
+    async fn keeps_charset() {
+        let mut res = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=iso-8859-1")
+            .body(Body::from(&b"<body>caf\xe9</body>"[..]))
+            .unwrap();
+        let rewriter = HtmlRewriter::new().with_body_start("é");
+
+        res.rewrite_html(&rewriter).unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(&body[..], b"<body>\xe9caf\xe9</body>");
+    }
+
+    #[tokio::test]
+    async fn adds_nonce() {
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(
+                CONTENT_SECURITY_POLICY,
+                "default-src 'self'; script-src 'none'",
+            )
+            .body(page(
+                "<head><meta http-equiv=\"Content-Security-Policy\" content=\"default-src \
+                 'self'\"></head>",
+            ))
+            .unwrap();
+        let rewriter = HtmlRewriter::new()
+            .with_head_end("<script>1</script>")
+            .with_csp(CspAction::Nonce);
+
+        let (headers, body) = rewrite(res, &rewriter).await;
+        let policy = headers[CONTENT_SECURITY_POLICY].to_str().unwrap();
+        let nonce = policy
+            .split("'nonce-")
+            .nth(1)
+            .unwrap()
+            .split('\'')
+            .next()
+            .unwrap();
+
+        assert_eq!(
+            policy,
+            format!("default-src 'self' 'nonce-{nonce}'; script-src 'nonce-{nonce}'")
+        );
+        assert_eq!(
+            body,
+            format!(
+                "<head><meta http-equiv=\"Content-Security-Policy\" content=\"default-src 'self' \
+                 'nonce-{nonce}'\"><script nonce=\"{nonce}\">1</script></head>"
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_unsafe_inline() {
+        assert_eq!(
+            add_nonce(
+                "script-src 'self' 'unsafe-inline'; style-src 'unsafe-inline' 'sha256-abc'",
+                "n"
+            ),
+            "script-src 'self' 'unsafe-inline'; style-src 'unsafe-inline' 'sha256-abc' 'nonce-n'"
+        );
+    }
+
+    #[tokio::test]
+    async fn removes_csp() {
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_SECURITY_POLICY, "default-src 'self'")
+            .body(page(
+                "<head><meta http-equiv=\"content-security-policy\" content=\"x\"></head>",
+            ))
+            .unwrap();
+        let rewriter = HtmlRewriter::new().with_csp(CspAction::Remove);
+
+        let (headers, body) = rewrite(res, &rewriter).await;
+
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert_eq!(body, "<head></head>");
+    }
+
+    #[tokio::test]
+    async fn skips_other_content() {
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_SECURITY_POLICY, "default-src 'self'")
+            .body(Body::from("<body></body>"))
+            .unwrap();
+        let rewriter = HtmlRewriter::new()
+            .with_body_start("x")
+            .with_csp(CspAction::Remove);
+
+        let (headers, body) = rewrite(res, &rewriter).await;
+
+        assert!(headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert_eq!(body, "<body></body>");
+    }
+
+    #[test]
+    fn rejects_invalid_selector() {
+        assert!(matches!(
+            HtmlRewriter::new().with_attribute("a[", "b", "c"),
+            Err(Error::Payload(PayloadError::Selector(_)))
+        ));
+    }
+}
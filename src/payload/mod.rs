@@ -0,0 +1,610 @@
+mod html;
+mod multipart;
+mod replace;
+mod rewrite;
+
+pub use html::{CspAction, HtmlRewriter};
+pub use multipart::{Multipart, MultipartForm, Part};
+pub use replace::BodyReplacer;
+pub use rewrite::{MultipartRewriter, NewPart, PartAction};
+
+use rewrite::Rewrite;
+
+use crate::{
+    Body,
+    DictionaryStore,
+    Error,
+    decoder::{DecodeLimits, decode_body_in_place, extract_encodings, split_trailers},
+};
+use hyper::{
+    Request,
+    Response,
+    body::Bytes,
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue},
+    http::Extensions,
+};
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// Errors that occur while parsing a payload.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PayloadError {
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("body is not valid {0}")]
+    Charset(&'static str),
+    #[error("content type has no multipart boundary")]
+    MissingBoundary,
+    #[error("invalid multipart body: {0}")]
+    Multipart(&'static str),
+    #[error("invalid selector: {0}")]
+    Selector(#[from] lol_html::errors::SelectorError),
+    #[error("invalid regex: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("failed to rewrite html: {0}")]
+    Html(#[from] lol_html::errors::RewritingError),
+}
+
+/// Get a parameter of a header value such as `content-type`, removing any
+/// quotes around it.
+pub(crate) fn header_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+        })
+    })
+}
+
+fn content_type(headers: &HeaderMap) -> Option<&str> {
+    headers.get(CONTENT_TYPE)?.to_str().ok()
+}
+
+fn charset(headers: &HeaderMap) -> &'static encoding_rs::Encoding {
+    content_type(headers)
+        .and_then(|value| header_param(value, "charset"))
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8)
+}
+
+mod sealed {
+    use super::*;
+
+    pub trait Message {
+        fn headers(&self) -> &HeaderMap;
+        fn headers_mut(&mut self) -> &mut HeaderMap;
+        fn extensions(&self) -> &Extensions;
+        fn body_mut(&mut self) -> &mut Body;
+    }
+
+    impl Message for Request<Body> {
+        fn headers(&self) -> &HeaderMap {
+            self.headers()
+        }
+
+        fn headers_mut(&mut self) -> &mut HeaderMap {
+            self.headers_mut()
+        }
+
+        fn extensions(&self) -> &Extensions {
+            self.extensions()
+        }
+
+        fn body_mut(&mut self) -> &mut Body {
+            self.body_mut()
+        }
+    }
+
+    impl Message for Response<Body> {
+        fn headers(&self) -> &HeaderMap {
+            self.headers()
+        }
+
+        fn headers_mut(&mut self) -> &mut HeaderMap {
+            self.headers_mut()
+        }
+
+        fn extensions(&self) -> &Extensions {
+            self.extensions()
+        }
+
+        fn body_mut(&mut self) -> &mut Body {
+            self.body_mut()
+        }
+    }
+}
+
+/// Limits applied when a [`Payload`] reads a body.
+///
+/// The limits are taken from the extensions of the message, where the proxy
+/// puts the ones passed to
+/// [`ProxyBuilder::with_payload_limits`](crate::builder::ProxyBuilder::with_payload_limits).
+/// Messages without them use the defaults.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{DecodeLimits, PayloadLimits};
+///
+/// let limits = PayloadLimits::new()
+///     .with_max_size(1024 * 1024)
+///     .with_decode_limits(DecodeLimits::new().with_max_layers(1));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadLimits {
+    max_size: usize,
+    decode: DecodeLimits,
+}
+
+impl PayloadLimits {
+    /// Largest body read into memory by default.
+    pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Largest compression ratio of decoded bodies by default.
+    pub const DEFAULT_MAX_RATIO: u64 = 100;
+
+    /// Create limits that read bodies of up to
+    /// [`DEFAULT_MAX_SIZE`](Self::DEFAULT_MAX_SIZE) bytes into memory, and
+    /// decode bodies compressed up to
+    /// [`DEFAULT_MAX_RATIO`](Self::DEFAULT_MAX_RATIO) times.
+    pub fn new() -> Self {
+        Self {
+            max_size: Self::DEFAULT_MAX_SIZE,
+            decode: DecodeLimits::new().with_max_ratio(Self::DEFAULT_MAX_RATIO),
+        }
+    }
+
+    /// Set the largest body, after decoding, that is read into memory.
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Set the limits applied while decoding bodies. The decoded size of bodies
+    /// read into memory is always limited to the maximum size.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.decode = limits;
+        self
+    }
+
+    fn of<M: sealed::Message>(message: &M) -> Self {
+        message
+            .extensions()
+            .get::<Self>()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Limits for decoding a body that is read into memory.
+    fn buffered(&self) -> DecodeLimits {
+        self.decode.with_max_decoded_size(self.max_size as u64)
+    }
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Take the body of a message, decoded according to its `content-encoding`.
+///
+/// The message is left unchanged if the body cannot be decoded.
+fn take_decoded<M: sealed::Message>(message: &mut M, limits: &DecodeLimits) -> Result<Body, Error> {
+    let mut body = std::mem::replace(message.body_mut(), Body::empty());
+
+    if !message.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(body);
+    }
+
+    if let Err(e) = decode_body_in_place(
+        extract_encodings(message.headers()),
+        &mut body,
+        limits,
+        message.extensions().get::<DictionaryStore>(),
+    ) {
+        *message.body_mut() = body;
+        return Err(e);
+    }
+
+    let headers = message.headers_mut();
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    Ok(body)
+}
+
+fn set_body<M: sealed::Message>(message: &mut M, content_type: HeaderValue, body: Bytes) {
+    let headers = message.headers_mut();
+    headers.remove(CONTENT_ENCODING);
+    headers.insert(CONTENT_TYPE, content_type);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    *message.body_mut() = Body::from(body);
+}
+
+/// Parsers and builders for common request and response payloads.
+///
+/// Parsing decodes the body according to its `content-encoding` and reads it
+/// to the end, within the [`PayloadLimits`] of the message. The decoded body is
+/// put back into the message, so it can still be forwarded after it has been
+/// inspected, and a body that cannot be decoded or is too large is left in
+/// place. The `set_*` methods replace the
+/// body and update the `content-type` and `content-length` headers.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, HttpContext, HttpHandler, Payload, hyper::Response, serde_json::Value};
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut res: Response<Body>,
+///     ) -> Response<Body> {
+///         if let Ok(mut value) = res.json::<Value>().await {
+///             value["intercepted"] = Value::Bool(true);
+///             res.set_json(&value).unwrap();
+///         }
+///
+///         res
+///     }
+/// }
+/// ```
+pub trait Payload: sealed::Message + Send + Sized {
+    /// Read the body to the end, decoding its `content-encoding`.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be decoded, exceeds the
+    /// [`PayloadLimits`] of the message, or fails while it is read. The body
+    /// is left in place in the first two cases, but is lost if it fails.
+    fn bytes(&mut self) -> impl Future<Output = Result<Bytes, Error>> + Send {
+        async move {
+            let limits = PayloadLimits::of(self);
+            let body = take_decoded(self, &limits.buffered())?;
+
+            let bytes = match body.collect_bytes_limited(limits.max_size).await {
+                Ok(bytes) => bytes,
+                Err(Error::LengthLimit(mut e)) => {
+                    *self.body_mut() = e.take_body();
+                    return Err(Error::LengthLimit(e));
+                }
+                Err(e) => return Err(e),
+            };
+
+            self.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+            *self.body_mut() = Body::from(bytes.clone());
+            Ok(bytes)
+        }
+    }
+
+    /// Read the body as text, using the charset from the `content-type`
+    /// header and falling back to UTF-8. A byte order mark takes precedence
+    /// over the declared charset.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read, or is not valid
+    /// text in its charset.
+    fn text(&mut self) -> impl Future<Output = Result<String, Error>> + Send {
+        async move {
+            let bytes = self.bytes().await?;
+            let charset = charset(self.headers());
+            let (text, charset, malformed) = charset.decode(&bytes);
+
+            if malformed {
+                Err(PayloadError::Charset(charset.name()))?;
+            }
+
+            Ok(text.into_owned())
+        }
+    }
+
+    /// Parse the body as JSON.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read or is not valid
+    /// JSON for `T`.
+    fn json<T: DeserializeOwned>(&mut self) -> impl Future<Output = Result<T, Error>> + Send {
+        async move {
+            let text = self.text().await?;
+            Ok(serde_json::from_str(&text).map_err(PayloadError::from)?)
+        }
+    }
+
+    /// Parse the body as `application/x-www-form-urlencoded` pairs.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read.
+    fn form(&mut self) -> impl Future<Output = Result<Vec<(String, String)>, Error>> + Send {
+        async move {
+            let bytes = self.bytes().await?;
+            Ok(form_urlencoded::parse(&bytes).into_owned().collect())
+        }
+    }
+
+    /// Take the body as a streaming `multipart/form-data` reader.
+    ///
+    /// The body is moved into the returned reader and replaced with an empty
+    /// body, so a new body has to be set before the message is forwarded.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the `content-type` header has no boundary,
+    /// or if the body cannot be decoded.
+    fn multipart(&mut self) -> Result<Multipart, Error> {
+        let boundary = content_type(self.headers())
+            .and_then(|value| header_param(value, "boundary"))
+            .ok_or(PayloadError::MissingBoundary)?
+            .to_owned();
+
+        let limits = PayloadLimits::of(self).buffered();
+        Ok(Multipart::new(take_decoded(self, &limits)?, &boundary))
+    }
+
+    /// Rewrite a `multipart/form-data` body part by part as it is forwarded.
+    ///
+    /// The body is decoded and re-emitted with the original boundary, so the
+    /// `content-type` header is unchanged. The `content-length` header is
+    /// removed, since the length of the rewritten body is not known up front.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the `content-type` header has no boundary,
+    /// or if the body cannot be decoded.
+    fn rewrite_multipart(&mut self, rewriter: impl MultipartRewriter) -> Result<(), Error> {
+        let boundary = content_type(self.headers())
+            .and_then(|value| header_param(value, "boundary"))
+            .ok_or(PayloadError::MissingBoundary)?
+            .to_owned();
+
+        let limits = PayloadLimits::of(self).decode;
+        let body = take_decoded(self, &limits)?;
+        self.headers_mut().remove(CONTENT_LENGTH);
+        let (body, trailers) = split_trailers(body);
+        *self.body_mut() =
+            trailers.restore(Body::from_stream(Rewrite::new(body, &boundary, rewriter)));
+        Ok(())
+    }
+
+    /// Rewrite an HTML body as it is forwarded.
+    ///
+    /// Bodies without a `text/html` content type, or in a charset that is not
+    /// compatible with ASCII, are left unchanged. Otherwise the body is decoded
+    /// and the `content-length` header is removed, since the length of the
+    /// rewritten body is not known up front.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be decoded, or the
+    /// injected content cannot be parsed.
+    fn rewrite_html(&mut self, rewriter: &HtmlRewriter) -> Result<(), Error> {
+        let Some((encoding, declared)) = HtmlRewriter::encoding(self.headers()) else {
+            return Ok(());
+        };
+
+        let limits = PayloadLimits::of(self).decode;
+        let body = take_decoded(self, &limits)?;
+        let nonce = rewriter.rewrite_csp(self.headers_mut());
+        self.headers_mut().remove(CONTENT_LENGTH);
+        let (body, trailers) = split_trailers(body);
+        let stream = rewriter.rewrite(body, encoding, declared, nonce)?;
+        *self.body_mut() = trailers.restore(Body::from_stream(stream));
+        Ok(())
+    }
+
+    /// Apply regex substitutions to the body as it is forwarded.
+    ///
+    /// The body is decoded, and the `content-length` header is removed, so
+    /// the rewritten body is sent with chunked transfer encoding.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be decoded.
+    fn replace_all(&mut self, replacer: &BodyReplacer) -> Result<(), Error> {
+        let limits = PayloadLimits::of(self).decode;
+        let body = take_decoded(self, &limits)?;
+        self.headers_mut().remove(CONTENT_LENGTH);
+        *self.body_mut() = replacer.replace(body);
+        Ok(())
+    }
+
+    /// Replace the body with the given text.
+    fn set_text(&mut self, text: impl Into<String>) {
+        set_body(
+            self,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+            Bytes::from(text.into()),
+        );
+    }
+
+    /// Replace the body with the given value serialized as JSON.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the value cannot be serialized.
+    fn set_json<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let json = serde_json::to_vec(value).map_err(PayloadError::from)?;
+        set_body(
+            self,
+            HeaderValue::from_static("application/json"),
+            Bytes::from(json),
+        );
+        Ok(())
+    }
+
+    /// Replace the body with the given `application/x-www-form-urlencoded`
+    /// pairs.
+    fn set_form<K: AsRef<str>, V: AsRef<str>>(&mut self, pairs: impl IntoIterator<Item = (K, V)>) {
+        let form = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish();
+        set_body(
+            self,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+            Bytes::from(form),
+        );
+    }
+
+    /// Replace the body with the given `multipart/form-data` form.
+    fn set_multipart(&mut self, form: MultipartForm) {
+        set_body(self, form.content_type(), form.into_bytes());
+    }
+}
+
+impl Payload for Request<Body> {}
+
+impl Payload for Response<Body> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use serde_json::{Value, json};
+
+    #[test]
+    fn parses_header_params() {
+        let value = "multipart/form-data; charset=utf-8; boundary=\"abc def\"";
+
+        assert_eq!(header_param(value, "boundary"), Some("abc def"));
+        assert_eq!(header_param(value, "CHARSET"), Some("utf-8"));
+        assert_eq!(header_param(value, "name"), None);
+    }
+
+    #[tokio::test]
+    async fn parses_compressed_json() {
+        let mut res = crate::encode_response(
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"hello":"world"}"#))
+                .unwrap(),
+            crate::Encoding::Gzip,
+        );
+
+        let value: Value = res.json().await.unwrap();
+
+        assert_eq!(value, json!({ "hello": "world" }));
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(res.headers()[CONTENT_LENGTH], "17");
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            br#"{"hello":"world"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_undecodable_body() {
+        let mut res = Response::builder()
+            .header(CONTENT_ENCODING, "unknown")
+            .header(CONTENT_LENGTH, 5)
+            .body(Body::from("hello"))
+            .unwrap();
+
+        assert!(res.bytes().await.is_err());
+        assert_eq!(res.headers()[CONTENT_ENCODING], "unknown");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_oversized_body() {
+        let mut res = Response::new(Body::from("hello, world"));
+        res.extensions_mut()
+            .insert(PayloadLimits::new().with_max_size(5));
+
+        assert!(matches!(res.bytes().await, Err(Error::LengthLimit(_))));
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"hello, world"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_decoded_size() {
+        let mut res = crate::encode_response(
+            Response::new(Body::from(&[0; 1024][..])),
+            crate::Encoding::Gzip,
+        );
+        res.extensions_mut()
+            .insert(PayloadLimits::new().with_max_size(512));
+
+        assert!(res.bytes().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detects_charset() {
+        let mut res = Response::builder()
+            .header(CONTENT_TYPE, "text/plain; charset=iso-8859-1")
+            .body(Body::from(&b"caf\xe9"[..]))
+            .unwrap();
+
+        assert_eq!(res.text().await.unwrap(), "café");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_text() {
+        let mut res = Response::new(Body::from(&b"caf\xe9"[..]));
+
+        assert!(matches!(
+            res.text().await,
+            Err(Error::Payload(PayloadError::Charset("UTF-8")))
+        ));
+    }
+
+    #[tokio::test]
+    async fn round_trips_form() {
+        let mut req = Request::new(Body::empty());
+        req.set_form([("a", "1 2"), ("b", "é")]);
+
+        assert_eq!(
+            req.headers()[CONTENT_TYPE],
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(
+            req.form().await.unwrap(),
+            vec![
+                ("a".to_owned(), "1 2".to_owned()),
+                ("b".to_owned(), "é".to_owned())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_multipart() {
+        let mut req = Request::new(Body::empty());
+        req.set_multipart(MultipartForm::new().with_text("title", "hello"));
+
+        let mut multipart = req.multipart().unwrap();
+        let part = multipart.next_part().await.unwrap().unwrap();
+
+        assert_eq!(part.name(), Some("title"));
+        assert_eq!(&multipart.bytes().await.unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn requires_boundary() {
+        let mut req = Request::builder()
+            .header(CONTENT_TYPE, "multipart/form-data")
+            .body(Body::empty())
+            .unwrap();
+
+        assert!(matches!(
+            req.multipart(),
+            Err(Error::Payload(PayloadError::MissingBoundary))
+        ));
+    }
+}
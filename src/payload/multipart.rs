@@ -0,0 +1,559 @@
+use super::PayloadError;
+use crate::{Body, Error};
+use bstr::ByteSlice;
+use bytes::BytesMut;
+use http_body_util::BodyExt;
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue},
+};
+use std::collections::VecDeque;
+
+const MAX_HEADERS: usize = 32;
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Events produced while parsing a multipart body.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// The headers of a new part.
+    Headers(HeaderMap),
+    /// A chunk of the current part's body.
+    Data(Bytes),
+    /// The end of the current part.
+    PartEnd,
+    /// The closing delimiter.
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    Delimiter,
+    Headers,
+    Body,
+    Epilogue,
+}
+
+/// Incremental `multipart/*` parser that accepts the body in arbitrary chunks.
+pub(crate) struct Parser {
+    /// `--` followed by the boundary.
+    delimiter: Vec<u8>,
+    /// CRLF followed by the delimiter, which ends a part's body.
+    part_end: Vec<u8>,
+    buf: BytesMut,
+    state: State,
+    /// Whether any of the preamble has been discarded.
+    skipped: bool,
+}
+
+impl Parser {
+    pub(crate) fn new(boundary: &str) -> Self {
+        let delimiter = [b"--", boundary.as_bytes()].concat();
+        let part_end = [b"\r\n", &delimiter[..]].concat();
+
+        Self {
+            delimiter,
+            part_end,
+            buf: BytesMut::new(),
+            state: State::Preamble,
+            skipped: false,
+        }
+    }
+
+    /// Feed the next chunk of the body, returning the events it completes.
+    pub(crate) fn push(&mut self, data: &[u8], events: &mut VecDeque<Event>) -> Result<(), Error> {
+        if self.state != State::Epilogue {
+            self.buf.extend_from_slice(data);
+        }
+
+        loop {
+            match self.state {
+                State::Preamble => {
+                    // The first delimiter may be at the very start of the body,
+                    // otherwise it must start on a new line.
+                    if !self.skipped && self.buf.starts_with(&self.delimiter) {
+                        self.state = State::Delimiter;
+                    } else if let Some(i) = self.buf.find(&self.part_end) {
+                        let _ = self.buf.split_to(i + 2);
+                        self.state = State::Delimiter;
+                    } else {
+                        let keep = self.part_end.len().min(self.buf.len());
+                        if keep < self.buf.len() {
+                            let _ = self.buf.split_to(self.buf.len() - keep);
+                            self.skipped = true;
+                        }
+                        return Ok(());
+                    }
+                }
+                State::Delimiter => {
+                    let rest = &self.buf[self.delimiter.len().min(self.buf.len())..];
+
+                    if rest.starts_with(b"--") {
+                        events.push_back(Event::End);
+                        self.buf.clear();
+                        self.state = State::Epilogue;
+                        continue;
+                    }
+
+                    let Some(eol) = rest.find(b"\r\n") else {
+                        if rest.len() > MAX_HEADER_SIZE {
+                            Err(PayloadError::Multipart("invalid delimiter"))?;
+                        }
+                        return Ok(());
+                    };
+
+                    // Transport padding is allowed after the delimiter.
+                    if !rest[..eol].iter().all(|b| *b == b' ' || *b == b'\t') {
+                        Err(PayloadError::Multipart("invalid delimiter"))?;
+                    }
+
+                    let _ = self.buf.split_to(self.delimiter.len() + eol + 2);
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some(0)
+                    } else {
+                        self.buf.find(b"\r\n\r\n").map(|i| i + 2)
+                    };
+
+                    let Some(end) = end else {
+                        if self.buf.len() > MAX_HEADER_SIZE {
+                            Err(PayloadError::Multipart("part headers too large"))?;
+                        }
+                        return Ok(());
+                    };
+
+                    let block = self.buf.split_to(end + 2);
+                    events.push_back(Event::Headers(parse_headers(&block)?));
+                    self.state = State::Body;
+                }
+                State::Body => match self.buf.find(&self.part_end) {
+                    Some(i) => {
+                        let data = self.buf.split_to(i).freeze();
+                        if !data.is_empty() {
+                            events.push_back(Event::Data(data));
+                        }
+                        events.push_back(Event::PartEnd);
+                        let _ = self.buf.split_to(2);
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        // Hold back anything that could be the start of the
+                        // next delimiter.
+                        let keep = (self.part_end.len() - 1).min(self.buf.len());
+                        let data = self.buf.split_to(self.buf.len() - keep).freeze();
+                        if !data.is_empty() {
+                            events.push_back(Event::Data(data));
+                        }
+                        return Ok(());
+                    }
+                },
+                State::Epilogue => return Ok(()),
+            }
+        }
+    }
+
+    /// Signal the end of the body.
+    pub(crate) fn finish(&self) -> Result<(), Error> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            _ => Err(PayloadError::Multipart("incomplete multipart body").into()),
+        }
+    }
+}
+
+fn parse_headers(block: &[u8]) -> Result<HeaderMap, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+
+    let parsed = match httparse::parse_headers(block, &mut headers) {
+        Ok(httparse::Status::Complete((_, parsed))) => parsed,
+        _ => Err(PayloadError::Multipart("invalid part headers"))?,
+    };
+
+    let mut map = HeaderMap::with_capacity(parsed.len());
+
+    for header in parsed {
+        let name = HeaderName::from_bytes(header.name.as_bytes())
+            .map_err(|_| PayloadError::Multipart("invalid part headers"))?;
+        let value = HeaderValue::from_bytes(header.value)
+            .map_err(|_| PayloadError::Multipart("invalid part headers"))?;
+        map.append(name, value);
+    }
+
+    Ok(map)
+}
+
+/// A single part of a multipart body.
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub(super) headers: HeaderMap,
+}
+
+impl Part {
+    /// The headers of the part.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The `name` parameter of the part's `content-disposition` header.
+    pub fn name(&self) -> Option<&str> {
+        self.disposition_param("name")
+    }
+
+    /// The `filename` parameter of the part's `content-disposition` header.
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition_param("filename")
+    }
+
+    /// The content type of the part.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get(CONTENT_TYPE)?.to_str().ok()
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<&str> {
+        let value = self.headers.get(CONTENT_DISPOSITION)?.to_str().ok()?;
+        super::header_param(value, name)
+    }
+}
+
+/// A streaming reader over the parts of a `multipart/form-data` body.
+///
+/// Parts are read in order with [`Multipart::next_part`], and the body of the
+/// current part is read in chunks with [`Multipart::chunk`]. Any unread data is
+/// skipped when moving on to the next part.
+pub struct Multipart {
+    body: Body,
+    parser: Parser,
+    events: VecDeque<Event>,
+    in_part: bool,
+    done: bool,
+}
+
+impl Multipart {
+    pub(crate) fn new(body: Body, boundary: &str) -> Self {
+        Self {
+            body,
+            parser: Parser::new(boundary),
+            events: VecDeque::new(),
+            in_part: false,
+            done: false,
+        }
+    }
+
+    async fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            if self.done {
+                return Ok(None);
+            }
+
+            match self.body.frame().await.transpose()? {
+                Some(frame) => {
+                    if let Ok(data) = frame.into_data() {
+                        self.parser.push(&data, &mut self.events)?;
+                    }
+                }
+                None => {
+                    self.done = true;
+                    self.parser.finish()?;
+                }
+            }
+        }
+    }
+
+    /// Move on to the next part, returning `None` once all parts have been
+    /// read.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read or is not a valid
+    /// multipart body.
+    pub async fn next_part(&mut self) -> Result<Option<Part>, Error> {
+        while let Some(event) = self.next_event().await? {
+            match event {
+                Event::Headers(headers) => {
+                    self.in_part = true;
+                    return Ok(Some(Part { headers }));
+                }
+                Event::PartEnd => self.in_part = false,
+                Event::Data(_) => {}
+                Event::End => self.done = true,
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the next chunk of the current part's body, returning `None` at the
+    /// end of the part.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read or is not a valid
+    /// multipart body.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, Error> {
+        if !self.in_part {
+            return Ok(None);
+        }
+
+        match self.next_event().await? {
+            Some(Event::Data(data)) => Ok(Some(data)),
+            Some(Event::PartEnd) | None => {
+                self.in_part = false;
+                Ok(None)
+            }
+            Some(event) => {
+                self.events.push_front(event);
+                self.in_part = false;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the rest of the current part's body.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the body cannot be read or is not a valid
+    /// multipart body.
+    pub async fn bytes(&mut self) -> Result<Bytes, Error> {
+        let mut buf = BytesMut::new();
+
+        while let Some(chunk) = self.chunk().await? {
+            buf.extend_from_slice(&chunk);
+        }
+
+        Ok(buf.freeze())
+    }
+}
+
+/// Builder for a `multipart/form-data` body.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{MultipartForm, hyper::body::Bytes};
+///
+/// let form = MultipartForm::new().with_text("title", "hello").with_file(
+///     "upload",
+///     "hello.txt",
+///     "text/plain",
+///     Bytes::from("hello, world"),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct MultipartForm {
+    boundary: String,
+    parts: Vec<(HeaderMap, Bytes)>,
+}
+
+impl MultipartForm {
+    /// Create an empty form with a random boundary.
+    pub fn new() -> Self {
+        Self::with_boundary(format!("hudsucker-{:032x}", rand::random::<u128>()))
+    }
+
+    /// Create an empty form with the given boundary.
+    pub fn with_boundary(boundary: impl Into<String>) -> Self {
+        Self {
+            boundary: boundary.into(),
+            parts: Vec::new(),
+        }
+    }
+
+    /// The boundary that separates the parts of the form.
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// Add a part with the given headers and body.
+    pub fn with_part(mut self, headers: HeaderMap, body: impl Into<Bytes>) -> Self {
+        self.parts.push((headers, body.into()));
+        self
+    }
+
+    /// Add a text field.
+    pub fn with_text(self, name: &str, value: impl Into<Bytes>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, disposition(name, None));
+        self.with_part(headers, value)
+    }
+
+    /// Add a file field.
+    pub fn with_file(
+        self,
+        name: &str,
+        filename: &str,
+        content_type: &str,
+        body: impl Into<Bytes>,
+    ) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, disposition(name, Some(filename)));
+        if let Ok(content_type) = HeaderValue::from_str(content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        self.with_part(headers, body)
+    }
+
+    pub(crate) fn content_type(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!("multipart/form-data; boundary={}", self.boundary))
+            .expect("Invalid boundary")
+    }
+
+    pub(crate) fn into_bytes(self) -> Bytes {
+        let mut buf = Vec::new();
+
+        for (headers, body) in self.parts {
+            buf.extend_from_slice(b"--");
+            buf.extend_from_slice(self.boundary.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+            write_headers(&headers, &mut buf);
+            buf.extend_from_slice(b"\r\n");
+            buf.extend_from_slice(&body);
+            buf.extend_from_slice(b"\r\n");
+        }
+
+        buf.extend_from_slice(b"--");
+        buf.extend_from_slice(self.boundary.as_bytes());
+        buf.extend_from_slice(b"--\r\n");
+        Bytes::from(buf)
+    }
+}
+
+impl Default for MultipartForm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn write_headers(headers: &HeaderMap, buf: &mut Vec<u8>) {
+    for (name, value) in headers {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+pub(super) fn disposition(name: &str, filename: Option<&str>) -> HeaderValue {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let value = match filename {
+        Some(filename) => format!(
+            "form-data; name=\"{}\"; filename=\"{}\"",
+            escape(name),
+            escape(filename)
+        ),
+        None => format!("form-data; name=\"{}\"", escape(name)),
+    };
+
+    HeaderValue::from_str(&value).expect("Invalid content-disposition")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--abc\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n--abc  \r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\n--ab\r\n--abc--\r\nepilogue";
+
+    fn parse_in_chunks(size: usize) -> Vec<Event> {
+        let mut parser = Parser::new("abc");
+        let mut events = VecDeque::new();
+
+        for chunk in BODY.chunks(size) {
+            parser.push(chunk, &mut events).unwrap();
+        }
+        parser.finish().unwrap();
+
+        // Merge data chunks so results can be compared across chunk sizes.
+        let mut merged: Vec<Event> = Vec::new();
+        for event in events {
+            match (merged.last_mut(), event) {
+                (Some(Event::Data(prev)), Event::Data(data)) => {
+                    *prev = [&prev[..], &data[..]].concat().into();
+                }
+                (_, event) => merged.push(event),
+            }
+        }
+        merged
+    }
+
+    #[test]
+    fn parses_parts() {
+        let events = parse_in_chunks(BODY.len());
+
+        assert_eq!(events.len(), 7);
+        assert!(
+            matches!(&events[0], Event::Headers(h) if h[CONTENT_DISPOSITION] == "form-data; name=\"title\"")
+        );
+        assert_eq!(events[1], Event::Data(Bytes::from_static(b"hello")));
+        assert_eq!(events[2], Event::PartEnd);
+        assert!(matches!(&events[3], Event::Headers(h) if h[CONTENT_TYPE] == "text/plain"));
+        assert_eq!(
+            events[4],
+            Event::Data(Bytes::from_static(b"line one\r\n--ab"))
+        );
+        assert_eq!(events[5], Event::PartEnd);
+        assert_eq!(events[6], Event::End);
+    }
+
+    #[test]
+    fn parses_across_chunk_boundaries() {
+        let expected = parse_in_chunks(BODY.len());
+
+        for size in 1..BODY.len() {
+            assert_eq!(parse_in_chunks(size), expected, "chunk size {size}");
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_body() {
+        let mut parser = Parser::new("abc");
+        let mut events = VecDeque::new();
+
+        parser.push(&BODY[..40], &mut events).unwrap();
+
+        assert!(parser.finish().is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_parts() {
+        let mut multipart = Multipart::new(Body::from(BODY), "abc");
+
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), Some("title"));
+        assert_eq!(&multipart.bytes().await.unwrap()[..], b"hello");
+
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), Some("upload"));
+        assert_eq!(part.filename(), Some("a.txt"));
+        assert_eq!(part.content_type(), Some("text/plain"));
+
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn builds_form() {
+        let form = MultipartForm::with_boundary("xyz")
+            .with_text("title", "hello")
+            .with_file("upload", "a.txt", "text/plain", "contents");
+
+        assert_eq!(form.content_type(), "multipart/form-data; boundary=xyz");
+
+        let mut multipart = Multipart::new(Body::from(form.into_bytes()), "xyz");
+
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), Some("title"));
+        assert_eq!(&multipart.bytes().await.unwrap()[..], b"hello");
+
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.filename(), Some("a.txt"));
+        assert_eq!(&multipart.bytes().await.unwrap()[..], b"contents");
+
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,369 @@
+use super::PayloadError;
+use crate::{Body, Error, decoder::split_trailers};
+use futures::Stream;
+use hyper::body::{Body as HttpBody, Bytes};
+use regex::bytes::Regex;
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+/// Bytes kept before the unprocessed part of the buffer, so that assertions
+/// like `\b` and `^` see the preceding character.
+const CONTEXT: usize = 4;
+
+/// A streaming regex substitution over a body.
+///
+/// Substitutions are applied in order, each to the output of the previous one,
+/// like repeated calls to [`Regex::replace_all`]. Bytes are held back until no
+/// match that is shorter than the window could still include them, so matches
+/// spanning frame boundaries are found without buffering the whole body.
+/// Matches longer than the window may be missed, and empty matches are
+/// ignored.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Body, BodyReplacer, HttpContext, HttpHandler, Payload, hyper::Response};
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler {
+///     replacer: BodyReplacer,
+/// }
+///
+/// impl MyHandler {
+///     pub fn new() -> Self {
+///         let replacer = BodyReplacer::new()
+///             .with_replacement(r"https://api\.example\.com", "http://localhost:8080")
+///             .unwrap()
+///             .with_replacement(r"(?i)production", "staging")
+///             .unwrap();
+///
+///         Self { replacer }
+///     }
+/// }
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut res: Response<Body>,
+///     ) -> Response<Body> {
+///         let _ = res.replace_all(&self.replacer);
+///         res
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct BodyReplacer {
+    replacements: Vec<(Regex, Vec<u8>)>,
+    window: usize,
+}
+
+impl BodyReplacer {
+    /// The default length of the window that matches have to fit in.
+    pub const DEFAULT_WINDOW: usize = 8 * 1024;
+
+    /// Create a replacer without any substitutions.
+    pub fn new() -> Self {
+        Self {
+            replacements: Vec::new(),
+            window: Self::DEFAULT_WINDOW,
+        }
+    }
+
+    /// Add a substitution. The replacement can refer to capture groups, as in
+    /// [`Regex::replace_all`].
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the pattern is not a valid regex.
+    pub fn with_replacement(
+        self,
+        pattern: &str,
+        replacement: impl Into<Vec<u8>>,
+    ) -> Result<Self, Error> {
+        let regex = Regex::new(pattern).map_err(PayloadError::from)?;
+        Ok(self.with_regex(regex, replacement))
+    }
+
+    /// Add a substitution with a compiled regex.
+    pub fn with_regex(mut self, regex: Regex, replacement: impl Into<Vec<u8>>) -> Self {
+        self.replacements.push((regex, replacement.into()));
+        self
+    }
+
+    /// Set the length of the window that matches have to fit in. This is the
+    /// most that is held back from the output at any time, per substitution.
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Apply the substitutions to a body as it streams.
+    ///
+    /// The body is not decoded, so it should not have a `content-encoding`.
+    /// Use [`Payload::replace_all`](crate::Payload::replace_all) to rewrite a
+    /// request or response instead.
+    pub fn replace(&self, body: Body) -> Body {
+        if self.replacements.is_empty() {
+            return body;
+        }
+
+        let stages = self
+            .replacements
+            .iter()
+            .map(|(regex, replacement)| Stage {
+                regex: regex.clone(),
+                replacement: replacement.clone(),
+                window: self.window,
+                buf: Vec::new(),
+                emitted: 0,
+            })
+            .collect();
+
+        let (body, trailers) = split_trailers(body);
+
+        trailers.restore(Body::from_stream(ReplaceStream {
+            body,
+            stages,
+            done: false,
+        }))
+    }
+}
+
+impl Default for BodyReplacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Stage {
+    regex: Regex,
+    replacement: Vec<u8>,
+    window: usize,
+    buf: Vec<u8>,
+    // Everything in `buf` before this index has already been written out.
+    emitted: usize,
+}
+
+impl Stage {
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        let safe = self.buf.len().saturating_sub(self.window);
+        self.process(safe, out);
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.process(self.buf.len(), out);
+    }
+
+    /// Replace the matches that start before `safe`, and write out everything
+    /// up to `safe` or the end of the last match.
+    fn process(&mut self, safe: usize, out: &mut Vec<u8>) {
+        let mut last = self.emitted;
+        let mut pos = self.emitted;
+
+        while pos <= self.buf.len() {
+            let Some(caps) = self.regex.captures_at(&self.buf, pos) else {
+                break;
+            };
+            let m = caps.get(0).expect("Missing match");
+
+            if m.start() >= safe {
+                break;
+            }
+
+            if m.is_empty() {
+                pos = m.start() + 1;
+                continue;
+            }
+
+            out.extend_from_slice(&self.buf[last..m.start()]);
+            caps.expand(&self.replacement, out);
+            last = m.end();
+            pos = m.end();
+        }
+
+        let end = last.max(safe);
+        out.extend_from_slice(&self.buf[last..end]);
+
+        let drain = end.saturating_sub(CONTEXT);
+        self.buf.drain(..drain);
+        self.emitted = end - drain;
+    }
+}
+
+struct ReplaceStream {
+    body: Body,
+    stages: Vec<Stage>,
+    done: bool,
+}
+
+impl ReplaceStream {
+    fn push(&mut self, data: &[u8]) -> Bytes {
+        let mut data = data.to_vec();
+
+        for stage in &mut self.stages {
+            let mut out = Vec::with_capacity(data.len());
+            stage.push(&data, &mut out);
+            data = out;
+        }
+
+        Bytes::from(data)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        let mut data = Vec::new();
+
+        for stage in &mut self.stages {
+            let mut out = Vec::new();
+            stage.push(&data, &mut out);
+            stage.finish(&mut out);
+            data = out;
+        }
+
+        Bytes::from(data)
+    }
+}
+
+impl Stream for ReplaceStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            let data = match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => this.push(&data),
+                    Err(_) => continue,
+                },
+                Some(Err(err)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => {
+                    this.done = true;
+                    this.finish()
+                }
+            };
+
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(data)));
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Payload;
+    use http_body_util::BodyExt;
+    use hyper::{
+        Response,
+        header::{CONTENT_LENGTH, HeaderMap},
+    };
+
+    fn chunked(body: &str, size: usize) -> Body {
+        let chunks = body
+            .as_bytes()
+            .chunks(size)
+            .map(|chunk| Ok::<_, Error>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    async fn replace(replacer: &BodyReplacer, body: Body) -> String {
+        let body = replacer.replace(body).collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replaces_across_chunks() {
+        let input = "hello world, hello there. world hello!";
+        let replacer = BodyReplacer::new()
+            .with_replacement("hello", "bye")
+            .unwrap()
+            .with_replacement(r"(\w+) (\w+)!", "$2 $1!")
+            .unwrap()
+            .with_window(16);
+        let expected = "bye world, bye there. bye world!";
+
+        for size in 1..=input.len() {
+            assert_eq!(replace(&replacer, chunked(input, size)).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_assertions() {
+        let input = "cat concat cat";
+        let replacer = BodyReplacer::new()
+            .with_replacement(r"\bcat\b", "dog")
+            .unwrap()
+            .with_replacement("^dog", "DOG")
+            .unwrap()
+            .with_window(4);
+
+        for size in 1..=input.len() {
+            assert_eq!(
+                replace(&replacer, chunked(input, size)).await,
+                "DOG concat dog"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+        let replacer = BodyReplacer::new().with_replacement("a", "b").unwrap();
+
+        let body = replacer.replace(chunked("aaa", 1).with_trailers(trailers));
+        let collected = body.collect().await.unwrap();
+
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc");
+        assert_eq!(&collected.to_bytes()[..], b"bbb");
+    }
+
+    #[tokio::test]
+    async fn ignores_empty_matches() {
+        let replacer = BodyReplacer::new().with_replacement("x*", "-").unwrap();
+
+        assert_eq!(replace(&replacer, chunked("axxb", 1)).await, "a-b");
+    }
+
+    #[tokio::test]
+    async fn replaces_response_body() {
+        let mut res = crate::encode_response(
+            Response::builder()
+                .header(CONTENT_LENGTH, 11)
+                .body(Body::from("hello world"))
+                .unwrap(),
+            crate::Encoding::Gzip,
+        );
+        let replacer = BodyReplacer::new()
+            .with_replacement("world", "there")
+            .unwrap();
+
+        res.replace_all(&replacer).unwrap();
+
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"hello there"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regex() {
+        assert!(matches!(
+            BodyReplacer::new().with_replacement("(", ""),
+            Err(Error::Payload(PayloadError::Regex(_)))
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+use super::multipart::{Event, Parser, Part, disposition, write_headers};
+use crate::{Body, Error};
+use futures::Stream;
+use hyper::{
+    body::{Body as HttpBody, Bytes},
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+/// A part added to a multipart body by a [`MultipartRewriter`].
+///
+/// The body is streamed into the rewritten multipart body, and must not contain
+/// the multipart boundary.
+#[derive(Debug)]
+pub struct NewPart {
+    /// The headers of the part.
+    pub headers: HeaderMap,
+    /// The body of the part.
+    pub body: Body,
+}
+
+impl NewPart {
+    /// Create a part with the given headers and body.
+    pub fn new(headers: HeaderMap, body: impl Into<Body>) -> Self {
+        Self {
+            headers,
+            body: body.into(),
+        }
+    }
+
+    /// Create a text field.
+    pub fn text(name: &str, value: impl Into<Body>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, disposition(name, None));
+        Self::new(headers, value)
+    }
+
+    /// Create a file field.
+    pub fn file(name: &str, filename: &str, content_type: &str, body: impl Into<Body>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, disposition(name, Some(filename)));
+        if let Ok(content_type) = HeaderValue::from_str(content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        Self::new(headers, body)
+    }
+}
+
+/// What to do with a part of a multipart body that is being rewritten.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PartAction {
+    /// Keep the part, passing its body through [`MultipartRewriter::on_data`].
+    Keep,
+    /// Remove the part.
+    Drop,
+    /// Replace the part with the given parts.
+    Replace(Vec<NewPart>),
+    /// Insert the given parts before the part, and keep it.
+    Insert(Vec<NewPart>),
+}
+
+/// Rewrites the parts of a multipart body as it streams through the proxy.
+///
+/// Rewriters are applied with
+/// [`Payload::rewrite_multipart`](crate::Payload::rewrite_multipart). Parts
+/// are emitted with the original boundary, and only the current chunk of the
+/// upload is held in memory.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Body,
+///     HttpContext,
+///     HttpHandler,
+///     MultipartRewriter,
+///     NewPart,
+///     Part,
+///     PartAction,
+///     Payload,
+///     RequestOrResponse,
+///     hyper::Request,
+/// };
+///
+/// struct SwapImages;
+///
+/// impl MultipartRewriter for SwapImages {
+///     fn on_part(&mut self, part: &Part) -> PartAction {
+///         match (part.name(), part.content_type()) {
+///             (Some(name), Some("image/png")) => PartAction::Replace(vec![NewPart::file(
+///                 name,
+///                 "broken.png",
+///                 "image/png",
+///                 "not a png",
+///             )]),
+///             _ => PartAction::Keep,
+///         }
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         let _ = req.rewrite_multipart(SwapImages);
+///         req.into()
+///     }
+/// }
+/// ```
+pub trait MultipartRewriter: Send + Sync + 'static {
+    /// This will be called with the headers of each part, to decide what to
+    /// do with it.
+    fn on_part(&mut self, part: &Part) -> PartAction;
+
+    /// This will be called with each chunk of a kept part's body, and returns
+    /// the chunk to emit in its place.
+    fn on_data(&mut self, data: Bytes) -> Bytes {
+        data
+    }
+
+    /// This will be called at the end of the body, and returns parts to append
+    /// to it.
+    fn on_end(&mut self) -> Vec<NewPart> {
+        Vec::new()
+    }
+}
+
+enum Output {
+    Bytes(Bytes),
+    Body(Body),
+}
+
+pub(super) struct Rewrite<R> {
+    body: Body,
+    parser: Parser,
+    boundary: String,
+    rewriter: R,
+    events: VecDeque<Event>,
+    output: VecDeque<Output>,
+    keep: bool,
+    eof: bool,
+}
+
+impl<R: MultipartRewriter> Rewrite<R> {
+    pub(super) fn new(body: Body, boundary: &str, rewriter: R) -> Self {
+        Self {
+            body,
+            parser: Parser::new(boundary),
+            boundary: boundary.to_owned(),
+            rewriter,
+            events: VecDeque::new(),
+            output: VecDeque::new(),
+            keep: false,
+            eof: false,
+        }
+    }
+
+    fn part_head(&self, headers: &HeaderMap) -> Bytes {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"--");
+        buf.extend_from_slice(self.boundary.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+        write_headers(headers, &mut buf);
+        buf.extend_from_slice(b"\r\n");
+        Bytes::from(buf)
+    }
+
+    fn push_parts(&mut self, parts: Vec<NewPart>) {
+        for part in parts {
+            let head = self.part_head(&part.headers);
+            self.output.push_back(Output::Bytes(head));
+            self.output.push_back(Output::Body(part.body));
+            self.output
+                .push_back(Output::Bytes(Bytes::from_static(b"\r\n")));
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Headers(headers) => {
+                let part = Part { headers };
+
+                self.keep = match self.rewriter.on_part(&part) {
+                    PartAction::Keep => true,
+                    PartAction::Drop => false,
+                    PartAction::Replace(parts) => {
+                        self.push_parts(parts);
+                        false
+                    }
+                    PartAction::Insert(parts) => {
+                        self.push_parts(parts);
+                        true
+                    }
+                };
+
+                if self.keep {
+                    let head = self.part_head(&part.headers);
+                    self.output.push_back(Output::Bytes(head));
+                }
+            }
+            Event::Data(data) if self.keep => {
+                let data = self.rewriter.on_data(data);
+                self.output.push_back(Output::Bytes(data));
+            }
+            Event::Data(_) => {}
+            Event::PartEnd if self.keep => {
+                self.output
+                    .push_back(Output::Bytes(Bytes::from_static(b"\r\n")));
+                self.keep = false;
+            }
+            Event::PartEnd => {}
+            Event::End => {
+                let parts = self.rewriter.on_end();
+                self.push_parts(parts);
+                let end = format!("--{}--\r\n", self.boundary);
+                self.output.push_back(Output::Bytes(Bytes::from(end)));
+            }
+        }
+    }
+}
+
+// The rewriter is never pinned, so it doesn't need to be `Unpin` itself.
+impl<R> Unpin for Rewrite<R> {}
+
+impl<R: MultipartRewriter> Stream for Rewrite<R> {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.output.front_mut() {
+                Some(Output::Bytes(_)) => {
+                    let Some(Output::Bytes(bytes)) = this.output.pop_front() else {
+                        unreachable!()
+                    };
+                    if !bytes.is_empty() {
+                        return Poll::Ready(Some(Ok(bytes)));
+                    }
+                    continue;
+                }
+                Some(Output::Body(body)) => match ready!(Pin::new(body).poll_frame(cx)) {
+                    Some(Ok(frame)) => {
+                        if let Ok(data) = frame.into_data() {
+                            return Poll::Ready(Some(Ok(data)));
+                        }
+                        continue;
+                    }
+                    Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                    None => {
+                        this.output.pop_front();
+                        continue;
+                    }
+                },
+                None => {}
+            }
+
+            if let Some(event) = this.events.pop_front() {
+                this.handle(event);
+                continue;
+            }
+
+            if this.eof {
+                return Poll::Ready(None);
+            }
+
+            match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    if let Ok(data) = frame.into_data() {
+                        if let Err(err) = this.parser.push(&data, &mut this.events) {
+                            this.eof = true;
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    this.eof = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => {
+                    this.eof = true;
+                    if let Err(err) = this.parser.finish() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MultipartForm;
+    use http_body_util::BodyExt;
+    use std::sync::{Arc, Mutex};
+
+    fn form() -> Body {
+        Body::from(
+            MultipartForm::with_boundary("xyz")
+                .with_text("keep", "kept")
+                .with_file("upload", "a.png", "image/png", "original")
+                .with_text("secret", "dropped")
+                .into_bytes(),
+        )
+    }
+
+    #[derive(Default)]
+    struct Rewriter {
+        seen: Arc<Mutex<Vec<Bytes>>>,
+    }
+
+    impl MultipartRewriter for Rewriter {
+        fn on_part(&mut self, part: &Part) -> PartAction {
+            match part.name() {
+                Some("keep") => PartAction::Insert(vec![NewPart::text("before", "inserted")]),
+                Some("upload") => PartAction::Replace(vec![NewPart::file(
+                    "upload",
+                    "b.png",
+                    "image/png",
+                    "replaced",
+                )]),
+                _ => PartAction::Drop,
+            }
+        }
+
+        fn on_data(&mut self, data: Bytes) -> Bytes {
+            self.seen.lock().unwrap().push(data.clone());
+            data
+        }
+
+        fn on_end(&mut self) -> Vec<NewPart> {
+            vec![NewPart::text("after", "appended")]
+        }
+    }
+
+    #[tokio::test]
+    async fn rewrites_parts() {
+        let rewriter = Rewriter::default();
+        let seen = Arc::clone(&rewriter.seen);
+
+        let body = Body::from_stream(Rewrite::new(form(), "xyz", rewriter));
+        let body = body.collect().await.unwrap().to_bytes();
+
+        let expected = MultipartForm::with_boundary("xyz")
+            .with_text("before", "inserted")
+            .with_text("keep", "kept")
+            .with_file("upload", "b.png", "image/png", "replaced")
+            .with_text("after", "appended")
+            .into_bytes();
+
+        assert_eq!(body, expected);
+        assert_eq!(seen.lock().unwrap().concat(), b"kept");
+    }
+
+    #[tokio::test]
+    async fn rewrites_streamed_body() {
+        let chunks = form()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .chunks(3)
+            .map(|chunk| Ok::<_, Error>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+
+        struct KeepAll;
+
+        impl MultipartRewriter for KeepAll {
+            fn on_part(&mut self, _part: &Part) -> PartAction {
+                PartAction::Keep
+            }
+        }
+
+        let body = Body::from_stream(futures::stream::iter(chunks));
+        let body = Body::from_stream(Rewrite::new(body, "xyz", KeepAll));
+
+        assert_eq!(
+            body.collect().await.unwrap().to_bytes(),
+            form().collect().await.unwrap().to_bytes()
+        );
+    }
+}
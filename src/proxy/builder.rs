@@ -1,15 +1,31 @@
+#[cfg(feature = "http3")]
+use crate::Http3Config;
+#[cfg(feature = "payload")]
+use crate::PayloadLimits;
+#[cfg(feature = "decoder")]
+use crate::{AcceptEncodingPolicy, DictionaryStore};
 use crate::{
+    AdaptivePassthrough,
+    AltSvcPolicy,
     HttpHandler,
+    NetworkConditions,
     NoopHandler,
+    ProtocolSniffer,
     Proxy,
+    SseHandler,
+    TunnelHandler,
     WebSocketHandler,
     certificate_authority::CertificateAuthority,
 };
+#[cfg(feature = "http2")]
+use hyper::Version;
 use hyper_util::{
     client::legacy::{Builder as ClientBuilder, connect::Connect},
     rt::TokioExecutor,
     server::conn::auto::Builder as ServerBuilder,
 };
+#[cfg(feature = "http2")]
+use std::collections::HashMap;
 use std::{
     future::{Pending, pending},
     net::SocketAddr,
@@ -29,6 +45,9 @@ pub enum Error {
     #[cfg(feature = "rustls-client")]
     #[error("{0}")]
     Rustls(#[from] tokio_rustls::rustls::Error),
+    #[cfg(feature = "http3")]
+    #[error("{0}")]
+    Quic(Box<dyn std::error::Error + Send + Sync>),
 }
 
 /// A builder for creating a [`Proxy`].
@@ -129,8 +148,17 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
     pub fn with_rustls_connector(
         self,
         provider: CryptoProvider,
-    ) -> ProxyBuilder<WantsHandlers<CA, impl Connect + Clone, NoopHandler, NoopHandler, Pending<()>>>
-    {
+    ) -> ProxyBuilder<
+        WantsHandlers<
+            CA,
+            impl Connect + Clone,
+            NoopHandler,
+            NoopHandler,
+            NoopHandler,
+            NoopHandler,
+            Pending<()>,
+        >,
+    > {
         use hyper_rustls::ConfigBuilderExt;
 
         let rustls_config = match ClientConfig::builder_with_provider(Arc::new(provider))
@@ -145,8 +173,26 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
                     client: None,
                     http_handler: NoopHandler::new(),
                     websocket_handler: NoopHandler::new(),
+                    sse_handler: NoopHandler::new(),
+                    tunnel_handler: NoopHandler::new(),
                     websocket_connector: None,
                     server: None,
+                    network_conditions: None,
+                    protocol_sniffer: None,
+                    alt_svc_policy: None,
+                    adaptive_passthrough: None,
+                    #[cfg(feature = "decoder")]
+                    accept_encoding_policy: None,
+                    #[cfg(feature = "decoder")]
+                    dictionary_store: None,
+                    #[cfg(feature = "payload")]
+                    payload_limits: None,
+                    #[cfg(feature = "http2")]
+                    http1_connector: None,
+                    #[cfg(feature = "http2")]
+                    upstream_versions: HashMap::new(),
+                    #[cfg(feature = "http3")]
+                    http3: None,
                     graceful_shutdown: pending(),
                 });
             }
@@ -162,6 +208,15 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
 
         let https = https.build();
 
+        // Used for hosts that are forced to HTTP/1.x, so that h2 is not
+        // negotiated with them.
+        #[cfg(feature = "http2")]
+        let http1_connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(rustls_config.clone())
+            .https_or_http()
+            .enable_http1()
+            .build();
+
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
             ca: self.0.ca,
@@ -169,8 +224,26 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
             client: None,
             http_handler: NoopHandler::new(),
             websocket_handler: NoopHandler::new(),
+            sse_handler: NoopHandler::new(),
+            tunnel_handler: NoopHandler::new(),
             websocket_connector: Some(Connector::Rustls(Arc::new(rustls_config))),
             server: None,
+            network_conditions: None,
+            protocol_sniffer: None,
+            alt_svc_policy: None,
+            adaptive_passthrough: None,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: None,
+            #[cfg(feature = "decoder")]
+            dictionary_store: None,
+            #[cfg(feature = "payload")]
+            payload_limits: None,
+            #[cfg(feature = "http2")]
+            http1_connector: Some(http1_connector),
+            #[cfg(feature = "http2")]
+            upstream_versions: HashMap::new(),
+            #[cfg(feature = "http3")]
+            http3: None,
             graceful_shutdown: pending(),
         })
     }
@@ -179,8 +252,17 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
     #[cfg(feature = "native-tls-client")]
     pub fn with_native_tls_connector(
         self,
-    ) -> ProxyBuilder<WantsHandlers<CA, impl Connect + Clone, NoopHandler, NoopHandler, Pending<()>>>
-    {
+    ) -> ProxyBuilder<
+        WantsHandlers<
+            CA,
+            impl Connect + Clone,
+            NoopHandler,
+            NoopHandler,
+            NoopHandler,
+            NoopHandler,
+            Pending<()>,
+        >,
+    > {
         use hyper_util::client::legacy::connect::HttpConnector;
 
         let tls_connector = match hyper_tls::native_tls::TlsConnector::new() {
@@ -193,8 +275,26 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
                     client: None,
                     http_handler: NoopHandler::new(),
                     websocket_handler: NoopHandler::new(),
+                    sse_handler: NoopHandler::new(),
+                    tunnel_handler: NoopHandler::new(),
                     websocket_connector: None,
                     server: None,
+                    network_conditions: None,
+                    protocol_sniffer: None,
+                    alt_svc_policy: None,
+                    adaptive_passthrough: None,
+                    #[cfg(feature = "decoder")]
+                    accept_encoding_policy: None,
+                    #[cfg(feature = "decoder")]
+                    dictionary_store: None,
+                    #[cfg(feature = "payload")]
+                    payload_limits: None,
+                    #[cfg(feature = "http2")]
+                    http1_connector: None,
+                    #[cfg(feature = "http2")]
+                    upstream_versions: HashMap::new(),
+                    #[cfg(feature = "http3")]
+                    http3: None,
                     graceful_shutdown: pending(),
                 });
             }
@@ -210,17 +310,38 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
             client: None,
             http_handler: NoopHandler::new(),
             websocket_handler: NoopHandler::new(),
+            sse_handler: NoopHandler::new(),
+            tunnel_handler: NoopHandler::new(),
             websocket_connector: Some(Connector::NativeTls(tls_connector)),
             server: None,
+            network_conditions: None,
+            protocol_sniffer: None,
+            alt_svc_policy: None,
+            adaptive_passthrough: None,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: None,
+            #[cfg(feature = "decoder")]
+            dictionary_store: None,
+            #[cfg(feature = "payload")]
+            payload_limits: None,
+            #[cfg(feature = "http2")]
+            http1_connector: None,
+            #[cfg(feature = "http2")]
+            upstream_versions: HashMap::new(),
+            #[cfg(feature = "http3")]
+            http3: None,
             graceful_shutdown: pending(),
         })
     }
 
     /// Use a custom connector.
+    #[allow(clippy::type_complexity)]
     pub fn with_http_connector<C>(
         self,
         connector: C,
-    ) -> ProxyBuilder<WantsHandlers<CA, C, NoopHandler, NoopHandler, Pending<()>>>
+    ) -> ProxyBuilder<
+        WantsHandlers<CA, C, NoopHandler, NoopHandler, NoopHandler, NoopHandler, Pending<()>>,
+    >
     where
         C: Connect + Clone + Send + Sync + 'static,
     {
@@ -231,32 +352,68 @@ impl<CA> ProxyBuilder<WantsClient<CA>> {
             client: None,
             http_handler: NoopHandler::new(),
             websocket_handler: NoopHandler::new(),
+            sse_handler: NoopHandler::new(),
+            tunnel_handler: NoopHandler::new(),
             websocket_connector: None,
             server: None,
+            network_conditions: None,
+            protocol_sniffer: None,
+            alt_svc_policy: None,
+            adaptive_passthrough: None,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: None,
+            #[cfg(feature = "decoder")]
+            dictionary_store: None,
+            #[cfg(feature = "payload")]
+            payload_limits: None,
+            #[cfg(feature = "http2")]
+            http1_connector: None,
+            #[cfg(feature = "http2")]
+            upstream_versions: HashMap::new(),
+            #[cfg(feature = "http3")]
+            http3: None,
             graceful_shutdown: pending(),
         })
     }
 }
 
 /// Builder state that can take additional handlers.
-pub struct WantsHandlers<CA, C, H, W, F> {
+pub struct WantsHandlers<CA, C, H, W, S, T, F> {
     al: AddrOrListener,
     ca: CA,
     http_connector: Result<C, Error>,
     client: Option<ClientBuilder>,
     http_handler: H,
     websocket_handler: W,
+    sse_handler: S,
+    tunnel_handler: T,
     websocket_connector: Option<Connector>,
     server: Option<ServerBuilder<TokioExecutor>>,
+    network_conditions: Option<NetworkConditions>,
+    protocol_sniffer: Option<ProtocolSniffer>,
+    alt_svc_policy: Option<AltSvcPolicy>,
+    adaptive_passthrough: Option<AdaptivePassthrough>,
+    #[cfg(feature = "decoder")]
+    accept_encoding_policy: Option<AcceptEncodingPolicy>,
+    #[cfg(feature = "decoder")]
+    dictionary_store: Option<DictionaryStore>,
+    #[cfg(feature = "payload")]
+    payload_limits: Option<PayloadLimits>,
+    #[cfg(feature = "http2")]
+    http1_connector: Option<C>,
+    #[cfg(feature = "http2")]
+    upstream_versions: HashMap<String, Version>,
+    #[cfg(feature = "http3")]
+    http3: Option<Http3Config>,
     graceful_shutdown: F,
 }
 
-impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
+impl<CA, C, H, W, S, T, F> ProxyBuilder<WantsHandlers<CA, C, H, W, S, T, F>> {
     /// Set the HTTP handler.
     pub fn with_http_handler<H2: HttpHandler>(
         self,
         http_handler: H2,
-    ) -> ProxyBuilder<WantsHandlers<CA, C, H2, W, F>> {
+    ) -> ProxyBuilder<WantsHandlers<CA, C, H2, W, S, T, F>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
             ca: self.0.ca,
@@ -264,8 +421,26 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
             client: self.0.client,
             http_handler,
             websocket_handler: self.0.websocket_handler,
+            sse_handler: self.0.sse_handler,
+            tunnel_handler: self.0.tunnel_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            network_conditions: self.0.network_conditions,
+            protocol_sniffer: self.0.protocol_sniffer,
+            alt_svc_policy: self.0.alt_svc_policy,
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy,
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
             graceful_shutdown: self.0.graceful_shutdown,
         })
     }
@@ -274,7 +449,7 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
     pub fn with_websocket_handler<W2: WebSocketHandler>(
         self,
         websocket_handler: W2,
-    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W2, F>> {
+    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W2, S, T, F>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
             ca: self.0.ca,
@@ -282,8 +457,98 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
             client: self.0.client,
             http_handler: self.0.http_handler,
             websocket_handler,
+            sse_handler: self.0.sse_handler,
+            tunnel_handler: self.0.tunnel_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            network_conditions: self.0.network_conditions,
+            protocol_sniffer: self.0.protocol_sniffer,
+            alt_svc_policy: self.0.alt_svc_policy,
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy,
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
+            graceful_shutdown: self.0.graceful_shutdown,
+        })
+    }
+
+    /// Set the server-sent events handler.
+    pub fn with_sse_handler<S2: SseHandler>(
+        self,
+        sse_handler: S2,
+    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W, S2, T, F>> {
+        ProxyBuilder(WantsHandlers {
+            al: self.0.al,
+            ca: self.0.ca,
+            http_connector: self.0.http_connector,
+            client: self.0.client,
+            http_handler: self.0.http_handler,
+            websocket_handler: self.0.websocket_handler,
+            sse_handler,
+            tunnel_handler: self.0.tunnel_handler,
+            websocket_connector: self.0.websocket_connector,
+            server: self.0.server,
+            network_conditions: self.0.network_conditions,
+            protocol_sniffer: self.0.protocol_sniffer,
+            alt_svc_policy: self.0.alt_svc_policy,
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy,
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
+            graceful_shutdown: self.0.graceful_shutdown,
+        })
+    }
+
+    /// Set the handler for tunnels that are not intercepted as HTTP.
+    pub fn with_tunnel_handler<T2: TunnelHandler>(
+        self,
+        tunnel_handler: T2,
+    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W, S, T2, F>> {
+        ProxyBuilder(WantsHandlers {
+            al: self.0.al,
+            ca: self.0.ca,
+            http_connector: self.0.http_connector,
+            client: self.0.client,
+            http_handler: self.0.http_handler,
+            websocket_handler: self.0.websocket_handler,
+            sse_handler: self.0.sse_handler,
+            tunnel_handler,
+            websocket_connector: self.0.websocket_connector,
+            server: self.0.server,
+            network_conditions: self.0.network_conditions,
+            protocol_sniffer: self.0.protocol_sniffer,
+            alt_svc_policy: self.0.alt_svc_policy,
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy,
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
             graceful_shutdown: self.0.graceful_shutdown,
         })
     }
@@ -312,11 +577,104 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
         })
     }
 
+    /// Emulate the given network conditions for all proxied traffic.
+    pub fn with_network_conditions(self, network_conditions: NetworkConditions) -> Self {
+        ProxyBuilder(WantsHandlers {
+            network_conditions: Some(network_conditions),
+            ..self.0
+        })
+    }
+
+    /// Set the sniffer used to detect the protocol spoken inside CONNECT
+    /// tunnels.
+    pub fn with_protocol_sniffer(self, sniffer: ProtocolSniffer) -> Self {
+        ProxyBuilder(WantsHandlers {
+            protocol_sniffer: Some(sniffer),
+            ..self.0
+        })
+    }
+
+    /// Set the policy for alternative service hints in responses. By default
+    /// they are stripped from responses of intercepted hosts.
+    pub fn with_alt_svc_policy(self, policy: AltSvcPolicy) -> Self {
+        ProxyBuilder(WantsHandlers {
+            alt_svc_policy: Some(policy),
+            ..self.0
+        })
+    }
+
+    /// Stop intercepting hosts whose certificates are repeatedly rejected by
+    /// clients, such as apps that pin certificates. Keep a clone of the policy
+    /// to inspect the learned hosts.
+    pub fn with_adaptive_passthrough(self, passthrough: AdaptivePassthrough) -> Self {
+        ProxyBuilder(WantsHandlers {
+            adaptive_passthrough: Some(passthrough),
+            ..self.0
+        })
+    }
+
+    /// Rewrite the `accept-encoding` header of requests forwarded upstream
+    /// using the given policy.
+    #[cfg(feature = "decoder")]
+    pub fn with_accept_encoding_policy(self, policy: AcceptEncodingPolicy) -> Self {
+        ProxyBuilder(WantsHandlers {
+            accept_encoding_policy: Some(policy),
+            ..self.0
+        })
+    }
+
+    /// Capture compression dictionaries into the given store, and attach it to
+    /// requests and responses so that `dcb` and `dcz` bodies can be decoded.
+    #[cfg(feature = "decoder")]
+    pub fn with_dictionary_store(self, store: DictionaryStore) -> Self {
+        ProxyBuilder(WantsHandlers {
+            dictionary_store: Some(store),
+            ..self.0
+        })
+    }
+
+    /// Set the limits applied when handlers read bodies through
+    /// [`Payload`](crate::Payload). They are attached to requests and
+    /// responses, which otherwise use the default limits.
+    #[cfg(feature = "payload")]
+    pub fn with_payload_limits(self, limits: PayloadLimits) -> Self {
+        ProxyBuilder(WantsHandlers {
+            payload_limits: Some(limits),
+            ..self.0
+        })
+    }
+
+    /// Force the protocol version used with an upstream host, instead of
+    /// negotiating it with ALPN. HTTP/2 is spoken with prior knowledge when
+    /// the host is reached over plain HTTP.
+    ///
+    /// Forcing HTTP/1.x relies on the connector not offering `h2`, which is
+    /// handled by
+    /// [`with_rustls_connector`](ProxyBuilder::with_rustls_connector) but is
+    /// up to custom connectors.
+    #[cfg(feature = "http2")]
+    pub fn with_upstream_version(mut self, host: &str, version: Version) -> Self {
+        self.0
+            .upstream_versions
+            .insert(host.to_ascii_lowercase(), version);
+        self
+    }
+
+    /// Speak HTTP/3 with upstream servers that support it, falling back to TCP
+    /// when a QUIC connection cannot be established.
+    #[cfg(feature = "http3")]
+    pub fn with_http3(self, config: Http3Config) -> Self {
+        ProxyBuilder(WantsHandlers {
+            http3: Some(config),
+            ..self.0
+        })
+    }
+
     /// Set a future that when ready will gracefully shutdown the proxy server.
     pub fn with_graceful_shutdown<F2: Future<Output = ()> + Send + 'static>(
         self,
         graceful_shutdown: F2,
-    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W, F2>> {
+    ) -> ProxyBuilder<WantsHandlers<CA, C, H, W, S, T, F2>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
             ca: self.0.ca,
@@ -324,14 +682,33 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
             client: self.0.client,
             http_handler: self.0.http_handler,
             websocket_handler: self.0.websocket_handler,
+            sse_handler: self.0.sse_handler,
+            tunnel_handler: self.0.tunnel_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            network_conditions: self.0.network_conditions,
+            protocol_sniffer: self.0.protocol_sniffer,
+            alt_svc_policy: self.0.alt_svc_policy,
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy,
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
             graceful_shutdown,
         })
     }
 
     /// Build the proxy.
-    pub fn build(self) -> Result<Proxy<C, CA, H, W, F>, crate::Error>
+    #[allow(clippy::type_complexity)]
+    pub fn build(self) -> Result<Proxy<C, CA, H, W, S, T, F>, crate::Error>
     where
         C: Connect + Clone,
     {
@@ -342,8 +719,26 @@ impl<CA, C, H, W, F> ProxyBuilder<WantsHandlers<CA, C, H, W, F>> {
             client: self.0.client,
             http_handler: self.0.http_handler,
             websocket_handler: self.0.websocket_handler,
+            sse_handler: self.0.sse_handler,
+            tunnel_handler: self.0.tunnel_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            network_conditions: self.0.network_conditions.map(Arc::new),
+            protocol_sniffer: Arc::new(self.0.protocol_sniffer.unwrap_or_default()),
+            alt_svc_policy: Arc::new(self.0.alt_svc_policy.unwrap_or_default()),
+            adaptive_passthrough: self.0.adaptive_passthrough,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.0.accept_encoding_policy.map(Arc::new),
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.0.dictionary_store,
+            #[cfg(feature = "payload")]
+            payload_limits: self.0.payload_limits,
+            #[cfg(feature = "http2")]
+            http1_connector: self.0.http1_connector,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.0.upstream_versions,
+            #[cfg(feature = "http3")]
+            http3: self.0.http3,
             graceful_shutdown: self.0.graceful_shutdown,
         })
     }
@@ -1,15 +1,39 @@
+#[cfg(feature = "payload")]
+use crate::PayloadLimits;
+#[cfg(feature = "http2")]
+use crate::h2c;
+#[cfg(feature = "http3")]
+use crate::http3::Http3Client;
+#[cfg(feature = "decoder")]
+use crate::{AcceptEncodingPolicy, DictionaryStore};
 use crate::{
+    AdaptivePassthrough,
+    AltSvcPolicy,
     HttpContext,
     HttpHandler,
+    NetworkConditions,
+    NetworkProfile,
+    Protocol,
+    ProtocolSniffer,
     RequestOrResponse,
+    SseHandler,
+    TlsStreamMode,
+    TunnelContext,
+    TunnelHandler,
     WebSocketContext,
     WebSocketHandler,
     body::Body,
     certificate_authority::CertificateAuthority,
+    network_conditions::{Throttled, ThrottledIo, ThrottledStream},
     rewind::Rewind,
+    sse,
+    tunnel,
 };
 use futures::{Sink, Stream, StreamExt};
-use http::{header::{CONTENT_TYPE, ETAG, LAST_MODIFIED}, uri::{Authority, Scheme}};
+use http::uri::{Authority, Scheme};
+use http_body_util::combinators::BoxBody;
+#[cfg(any(feature = "http2", feature = "http3"))]
+use hyper::Version;
 use hyper::{
     Method,
     Request,
@@ -26,15 +50,31 @@ use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto::Builder as ServerBuilder,
 };
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
-use tokio::{io::AsyncReadExt, net::TcpStream, task::JoinHandle};
-use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "http2")]
+use std::collections::HashMap;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use tokio_rustls::{TlsAcceptor, server};
 use tokio_tungstenite::{
     Connector,
     WebSocketStream,
     tungstenite::{self, Message},
 };
-use tracing::{Instrument, Span, error, info_span, instrument, warn};
+use tracing::{Instrument, Span, error, info, info_span, instrument, warn};
+
+/// Identifier of the next WebSocket connection.
+static NEXT_WEBSOCKET_ID: AtomicU64 = AtomicU64::new(0);
 
 fn bad_request() -> Response<Body> {
     Response::builder()
@@ -50,21 +90,47 @@ fn spawn_with_trace<T: Send + Sync + 'static>(
     tokio::spawn(fut.instrument(span))
 }
 
-pub(crate) struct InternalProxy<C, CA, H, W> {
+/// Clients used for upstream hosts that are forced to a protocol version.
+#[cfg(feature = "http2")]
+pub(crate) struct UpstreamVersions<C> {
+    pub hosts: HashMap<String, Version>,
+    pub http1: Client<C, Body>,
+    pub http2: Client<C, Body>,
+}
+
+pub(crate) struct InternalProxy<C, CA, H, W, S, T> {
     pub ca: Arc<CA>,
     pub client: Client<C, Body>,
     pub server: ServerBuilder<TokioExecutor>,
     pub http_handler: H,
     pub websocket_handler: W,
+    pub sse_handler: S,
+    pub tunnel_handler: T,
     pub websocket_connector: Option<Connector>,
+    pub network_conditions: Option<Arc<NetworkConditions>>,
+    pub protocol_sniffer: Arc<ProtocolSniffer>,
+    pub alt_svc_policy: Arc<AltSvcPolicy>,
+    pub adaptive_passthrough: Option<AdaptivePassthrough>,
+    #[cfg(feature = "decoder")]
+    pub accept_encoding_policy: Option<Arc<AcceptEncodingPolicy>>,
+    #[cfg(feature = "decoder")]
+    pub dictionary_store: Option<DictionaryStore>,
+    #[cfg(feature = "payload")]
+    pub payload_limits: Option<PayloadLimits>,
+    #[cfg(feature = "http2")]
+    pub upstream_versions: Option<Arc<UpstreamVersions<C>>>,
+    #[cfg(feature = "http3")]
+    pub http3: Option<Arc<Http3Client>>,
     pub client_addr: SocketAddr,
 }
 
-impl<C, CA, H, W> Clone for InternalProxy<C, CA, H, W>
+impl<C, CA, H, W, S, T> Clone for InternalProxy<C, CA, H, W, S, T>
 where
     C: Clone,
     H: Clone,
     W: Clone,
+    S: Clone,
+    T: Clone,
 {
     fn clone(&self) -> Self {
         InternalProxy {
@@ -73,18 +139,36 @@ where
             server: self.server.clone(),
             http_handler: self.http_handler.clone(),
             websocket_handler: self.websocket_handler.clone(),
+            sse_handler: self.sse_handler.clone(),
+            tunnel_handler: self.tunnel_handler.clone(),
             websocket_connector: self.websocket_connector.clone(),
+            network_conditions: self.network_conditions.clone(),
+            protocol_sniffer: Arc::clone(&self.protocol_sniffer),
+            alt_svc_policy: Arc::clone(&self.alt_svc_policy),
+            adaptive_passthrough: self.adaptive_passthrough.clone(),
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: self.accept_encoding_policy.clone(),
+            #[cfg(feature = "decoder")]
+            dictionary_store: self.dictionary_store.clone(),
+            #[cfg(feature = "payload")]
+            payload_limits: self.payload_limits,
+            #[cfg(feature = "http2")]
+            upstream_versions: self.upstream_versions.clone(),
+            #[cfg(feature = "http3")]
+            http3: self.http3.clone(),
             client_addr: self.client_addr,
         }
     }
 }
 
-impl<C, CA, H, W> InternalProxy<C, CA, H, W>
+impl<C, CA, H, W, S, T> InternalProxy<C, CA, H, W, S, T>
 where
     C: Connect + Clone + Send + Sync + 'static,
     CA: CertificateAuthority,
     H: HttpHandler,
     W: WebSocketHandler,
+    S: SseHandler,
+    T: TunnelHandler,
 {
     fn context<B: hyper::body::Body>(&self, req: &Request<B>) -> HttpContext {
         HttpContext::from_request(req, self.client_addr)
@@ -112,6 +196,69 @@ where
         // }
     }
 
+    fn network_profile(&self, host: Option<&str>) -> Option<NetworkProfile> {
+        self.network_conditions
+            .as_ref()?
+            .profile_for(&self.client_addr, host)
+            .cloned()
+    }
+
+    /// Get the client to use for a request, setting the version of the request
+    /// if it is forced for its host.
+    #[cfg_attr(not(feature = "http2"), allow(unused_variables))]
+    fn upstream_client(&self, req: &mut Request<Body>) -> &Client<C, Body> {
+        #[cfg(feature = "http2")]
+        if let Some(versions) = &self.upstream_versions {
+            let version = req
+                .uri()
+                .host()
+                .and_then(|host| versions.hosts.get(&host.to_ascii_lowercase()));
+
+            if let Some(&version) = version {
+                *req.version_mut() = version;
+
+                return match version {
+                    Version::HTTP_2 => &versions.http2,
+                    _ => &versions.http1,
+                };
+            }
+        }
+
+        &self.client
+    }
+
+    /// Send a request to the upstream server, over HTTP/3 when it is used for
+    /// the origin and over TCP otherwise.
+    async fn send_upstream(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, hyper_util::client::legacy::Error> {
+        let mut req = normalize_request(req);
+
+        #[cfg(feature = "http3")]
+        let uri = req.uri().clone();
+
+        #[cfg(feature = "http3")]
+        if let Some(http3) = &self.http3 {
+            req = match http3.send(req).await {
+                Ok(res) => {
+                    http3.record_alt_svc(&uri, res.headers());
+                    return Ok(res);
+                }
+                Err(req) => req,
+            };
+        }
+
+        let res = self.upstream_client(&mut req).request(req).await?;
+
+        #[cfg(feature = "http3")]
+        if let Some(http3) = &self.http3 {
+            http3.record_alt_svc(&uri, res.headers());
+        }
+
+        Ok(res.map(Body::from))
+    }
+
     #[instrument(
         skip_all,
         fields(
@@ -125,11 +272,35 @@ where
         mut self,
         req: Request<Incoming>,
     ) -> Result<Response<Body>, Infallible> {
-        let ctx = self.context(&req);
+        #[cfg(feature = "http2")]
+        let req = if h2c::is_upgrade_request(&req) {
+            if hyper::body::Body::is_end_stream(req.body()) {
+                return Ok(self.upgrade_h2c(req));
+            }
+
+            h2c::remove_upgrade(req)
+        } else {
+            req
+        };
+
+        let mut ctx = self.context(&req);
+
+        #[allow(unused_mut)]
+        let mut req = req.map(Body::from);
+
+        #[cfg(feature = "decoder")]
+        if let Some(store) = &self.dictionary_store {
+            req.extensions_mut().insert(store.clone());
+        }
+
+        #[cfg(feature = "payload")]
+        if let Some(limits) = self.payload_limits {
+            req.extensions_mut().insert(limits);
+        }
 
         let req = match self
             .http_handler
-            .handle_request(&ctx, req.map(Body::from))
+            .handle_request(&ctx, req)
             .instrument(info_span!("handle_request"))
             .await
         {
@@ -142,18 +313,88 @@ where
         } else if hyper_tungstenite::is_upgrade_request(&req) {
             Ok(self.upgrade_websocket(req))
         } else {
+            #[cfg(feature = "decoder")]
+            let req = match &self.accept_encoding_policy {
+                Some(policy) => policy.rewrite_request(req),
+                None => req,
+            };
+
+            let profile = self.network_profile(req.uri().host());
+
+            let req = match &profile {
+                Some(profile) => {
+                    tokio::time::sleep(profile.request_latency).await;
+                    req.map(|body| {
+                        Body::from(BoxBody::new(Throttled::upstream(body, Some(profile))))
+                    })
+                }
+                None => req,
+            };
+
             let res = self
-                .client
-                .request(normalize_request(req))
+                .send_upstream(req)
                 .instrument(info_span!("proxy_request"))
                 .await;
 
             match res {
-                Ok(res) => Ok(self
-                    .http_handler
-                    .handle_response(&ctx, res.map(Body::from))
-                    .instrument(info_span!("handle_response"))
-                    .await),
+                Ok(res) => {
+                    ctx.upstream_version = Some(res.version());
+
+                    #[cfg(feature = "decoder")]
+                    let res = match &self.dictionary_store {
+                        Some(store) => {
+                            let mut res = store.capture_response(res);
+                            res.extensions_mut().insert(store.clone());
+                            res
+                        }
+                        None => res,
+                    };
+
+                    #[cfg(feature = "payload")]
+                    let res = match self.payload_limits {
+                        Some(limits) => {
+                            let mut res = res;
+                            res.extensions_mut().insert(limits);
+                            res
+                        }
+                        None => res,
+                    };
+
+                    let res = self
+                        .http_handler
+                        .handle_response(&ctx, res)
+                        .instrument(info_span!("handle_response"))
+                        .await;
+
+                    let res = self.alt_svc_policy.apply(&ctx, res).await;
+
+                    let res = sse::handle_sse(self.sse_handler.clone(), &ctx, res).await;
+
+                    #[cfg(feature = "decoder")]
+                    let res = match &self.accept_encoding_policy {
+                        Some(policy) => policy.recompress_response(&ctx, res),
+                        None => res,
+                    };
+
+                    // Clients talk HTTP/1.1 or HTTP/2 to the proxy, whichever
+                    // version was spoken upstream.
+                    #[cfg(feature = "http3")]
+                    let res = match res.version() {
+                        Version::HTTP_3 => {
+                            let (mut parts, body) = res.into_parts();
+                            parts.version = Version::HTTP_11;
+                            Response::from_parts(parts, body)
+                        }
+                        _ => res,
+                    };
+
+                    Ok(match &profile {
+                        Some(profile) => res.map(|body| {
+                            Body::from(BoxBody::new(Throttled::downstream(body, Some(profile))))
+                        }),
+                        None => res,
+                    })
+                }
                 Err(err) => Ok(self
                     .http_handler
                     .handle_error(&ctx, err)
@@ -171,76 +412,187 @@ where
                     match hyper::upgrade::on(&mut req).await {
                         Ok(upgraded) => {
                             let mut upgraded = TokioIo::new(upgraded);
-                            let mut buffer = [0; 4];
-                            let bytes_read = match upgraded.read(&mut buffer).await {
-                                Ok(bytes_read) => bytes_read,
-                                Err(e) => {
-                                    error!("Failed to read from upgraded connection: {}", e);
-                                    return;
-                                }
+                            let (protocol, mut data) =
+                                match self.protocol_sniffer.sniff(&mut upgraded).await {
+                                    Ok(sniffed) => sniffed,
+                                    Err(e) => {
+                                        error!("Failed to read from upgraded connection: {}", e);
+                                        return;
+                                    }
+                                };
+
+                            let hello = match protocol {
+                                Protocol::Tls => match self
+                                    .protocol_sniffer
+                                    .read_client_hello(&mut upgraded, &mut data)
+                                    .await
+                                {
+                                    Ok(hello) => hello,
+                                    Err(e) => {
+                                        error!("Failed to read from upgraded connection: {}", e);
+                                        return;
+                                    }
+                                },
+                                _ => None,
                             };
 
-                            let mut upgraded = Rewind::new(
-                                upgraded,
-                                Bytes::copy_from_slice(buffer[..bytes_read].as_ref()),
-                            );
+                            let data = Bytes::from(data);
+                            let upgraded = Rewind::new(upgraded, data.clone());
+                            req.extensions_mut().insert(protocol);
+
+                            if let Some(hello) = &hello {
+                                req.extensions_mut().insert(hello.clone());
+                            }
 
-                            if self
-                                .http_handler
-                                .should_intercept(&self.context(&req), &req)
-                                .await
+                            // Hosts that keep rejecting the generated certificate are
+                            // tunneled untouched.
+                            let passthrough_host = hello
+                                .as_ref()
+                                .and_then(|hello| hello.server_name.clone())
+                                .unwrap_or_else(|| authority.host().to_owned());
+                            let passthrough = matches!(protocol, Protocol::Tls)
+                                && self.adaptive_passthrough.as_ref().is_some_and(|p| {
+                                    p.is_passthrough(self.client_addr.ip(), &passthrough_host)
+                                });
+
+                            if !passthrough
+                                && self
+                                    .http_handler
+                                    .should_intercept(&self.context(&req), &req)
+                                    .await
                             {
-                                if buffer == *b"GET " {
-                                    if let Err(e) = self
-                                        .serve_stream(
-                                            TokioIo::new(upgraded),
-                                            Scheme::HTTP,
-                                            authority,
-                                        )
-                                        .await
-                                    {
-                                        error!("WebSocket connect error: {}", e);
+                                match protocol {
+                                    Protocol::Http1 => {
+                                        if let Err(e) = self
+                                            .serve_stream(
+                                                TokioIo::new(upgraded),
+                                                Scheme::HTTP,
+                                                authority,
+                                            )
+                                            .await
+                                        {
+                                            error!("WebSocket connect error: {}", e);
+                                        }
+
+                                        return;
                                     }
+                                    Protocol::Http2 if cfg!(feature = "http2") => {
+                                        if let Err(e) = self
+                                            .serve_stream(
+                                                TokioIo::new(upgraded),
+                                                Scheme::HTTP,
+                                                authority,
+                                            )
+                                            .await
+                                        {
+                                            error!("h2c connect error: {}", e);
+                                        }
 
-                                    return;
-                                } else if buffer[..2] == *b"\x16\x03" {
-                                    let server_config = self
-                                        .ca
-                                        .gen_server_config(&authority)
-                                        .instrument(info_span!("gen_server_config"))
-                                        .await;
-
-                                    let stream = match TlsAcceptor::from(server_config)
-                                        .accept(upgraded)
-                                        .await
-                                    {
-                                        Ok(stream) => TokioIo::new(stream),
-                                        Err(e) => {
-                                            error!("Failed to establish TLS connection: {}", e);
-                                            return;
+                                        return;
+                                    }
+                                    Protocol::Tls => {
+                                        // Clients connecting to an IP address expect a
+                                        // certificate for the name they sent.
+                                        let server_name = hello
+                                            .as_ref()
+                                            .and_then(|hello| hello.server_name.as_deref())
+                                            .and_then(|name| {
+                                                let port = authority.port_u16().unwrap_or(443);
+                                                Authority::try_from(format!("{name}:{port}")).ok()
+                                            })
+                                            .unwrap_or_else(|| authority.clone());
+
+                                        let mut ctx = TunnelContext::new(
+                                            self.client_addr,
+                                            authority.clone(),
+                                            Protocol::Tls,
+                                        );
+                                        ctx.decrypted = true;
+
+                                        if let Some(hello) = &hello {
+                                            ctx.server_name = hello.server_name.clone();
+                                            ctx.offered_alpn_protocols =
+                                                hello.alpn_protocols.clone();
+                                        }
+
+                                        let mode =
+                                            match self.tunnel_handler.tls_stream_mode(&ctx).await {
+                                                TlsStreamMode::Detect => detect_tls_stream_mode(
+                                                    &ctx.offered_alpn_protocols,
+                                                ),
+                                                mode => mode,
+                                            };
+
+                                        let mut server_config = self
+                                            .ca
+                                            .gen_server_config(&server_name)
+                                            .instrument(info_span!("gen_server_config"))
+                                            .await;
+
+                                        // Raw streams negotiate the protocol the client
+                                        // prefers, as they are bridged to the server.
+                                        if mode == TlsStreamMode::Raw {
+                                            Arc::make_mut(&mut server_config).alpn_protocols =
+                                                ctx.offered_alpn_protocols.clone();
                                         }
-                                    };
-
-                                    if let Err(e) =
-                                        self.serve_stream(stream, Scheme::HTTPS, authority).await
-                                    {
-                                        if !e
-                                            .to_string()
-                                            .starts_with("error shutting down connection")
+
+                                        let stream = match TlsAcceptor::from(server_config)
+                                            .accept(upgraded)
+                                            .await
                                         {
-                                            error!("HTTPS connect error: {}", e);
+                                            Ok(stream) => stream,
+                                            Err(e) => {
+                                                let learned = self
+                                                    .adaptive_passthrough
+                                                    .as_ref()
+                                                    .is_some_and(|p| {
+                                                        p.record_failure(
+                                                            self.client_addr.ip(),
+                                                            &passthrough_host,
+                                                            hello.is_some(),
+                                                            &e,
+                                                        )
+                                                    });
+
+                                                if learned {
+                                                    info!(
+                                                        "Passing {} through after repeated certificate rejections",
+                                                        passthrough_host
+                                                    );
+                                                }
+
+                                                error!("Failed to establish TLS connection: {}", e);
+                                                return;
+                                            }
+                                        };
+
+                                        if let Some(passthrough) = &self.adaptive_passthrough {
+                                            passthrough.record_success(
+                                                self.client_addr.ip(),
+                                                &passthrough_host,
+                                            );
                                         }
-                                    }
 
-                                    return;
-                                } else {
-                                    warn!(
-                                        "Unknown protocol, read '{:02X?}' from upgraded connection",
-                                        &buffer[..bytes_read]
-                                    );
+                                        self.serve_decrypted(stream, ctx, mode).await;
+                                        return;
+                                    }
+                                    Protocol::Unknown => {
+                                        warn!(
+                                            "Unknown protocol, read '{:02X?}' from upgraded connection",
+                                            &data[..]
+                                        );
+                                    }
+                                    _ => {}
                                 }
                             }
 
+                            let profile = self.network_profile(Some(authority.host()));
+                            let mut upgraded = ThrottledIo::new(upgraded, profile.as_ref());
+
+                            if let Some(profile) = &profile {
+                                tokio::time::sleep(profile.request_latency).await;
+                            }
+
                             let mut server = match TcpStream::connect(authority.as_ref()).await {
                                 Ok(server) => server,
                                 Err(e) => {
@@ -249,10 +601,16 @@ where
                                 }
                             };
 
-                            if let Err(e) =
+                            let mut ctx = TunnelContext::new(self.client_addr, authority, protocol);
+                            ctx.server_name = hello.and_then(|hello| hello.server_name);
+
+                            if self.tunnel_handler.should_intercept(&ctx).await {
+                                tunnel::handle_tunnel(self.tunnel_handler, ctx, upgraded, server)
+                                    .await;
+                            } else if let Err(e) =
                                 tokio::io::copy_bidirectional(&mut upgraded, &mut server).await
                             {
-                                error!("Failed to tunnel to {}: {}", authority, e);
+                                error!("Failed to tunnel to {}: {}", ctx.authority, e);
                             }
                         }
                         Err(e) => error!("Upgrade error: {}", e),
@@ -266,6 +624,47 @@ where
         }
     }
 
+    #[cfg(feature = "http2")]
+    #[instrument(skip_all)]
+    fn upgrade_h2c(self, mut req: Request<Incoming>) -> Response<Body> {
+        let Some(authority) = req.uri().authority().cloned() else {
+            return bad_request();
+        };
+
+        let frames = h2c::encode_request(&req);
+        let span = info_span!("h2c");
+        let fut = async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => {
+                    let stream = match h2c::replay_request(TokioIo::new(upgraded), frames).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Failed to read HTTP/2 connection preface: {}", e);
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = self
+                        .serve_stream(TokioIo::new(stream), Scheme::HTTP, authority)
+                        .await
+                    {
+                        error!("h2c upgrade error: {}", e);
+                    }
+                }
+                Err(e) => error!("Upgrade error: {}", e),
+            }
+        };
+
+        spawn_with_trace(fut, span);
+
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(hyper::header::CONNECTION, "Upgrade")
+            .header(hyper::header::UPGRADE, "h2c")
+            .body(Body::empty())
+            .expect("Failed to build response")
+    }
+
     #[instrument(skip_all)]
     fn upgrade_websocket(self, req: Request<Body>) -> Response<Body> {
         let mut req = {
@@ -321,6 +720,9 @@ where
         req: Request<()>,
     ) -> Result<(), tungstenite::Error> {
         let uri = req.uri().clone();
+        let method = req.method().clone();
+        let id = NEXT_WEBSOCKET_ID.fetch_add(1, Ordering::Relaxed);
+        let profile = self.network_profile(uri.host());
 
         #[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
         let (server_socket, _) = tokio_tungstenite::connect_async_tls_with_config(
@@ -336,6 +738,8 @@ where
 
         let (server_sink, server_stream) = server_socket.split();
         let (client_sink, client_stream) = client_socket.split();
+        let server_stream = ThrottledStream::downstream(server_stream, profile.as_ref());
+        let client_stream = ThrottledStream::upstream(client_stream, profile.as_ref());
 
         let InternalProxy {
             websocket_handler, ..
@@ -348,6 +752,8 @@ where
             WebSocketContext::ServerToClient {
                 src: uri.clone(),
                 dst: self.client_addr,
+                id,
+                method: method.clone(),
             },
         );
 
@@ -358,13 +764,73 @@ where
             WebSocketContext::ClientToServer {
                 src: self.client_addr,
                 dst: uri,
+                id,
+                method,
             },
         );
 
         Ok(())
     }
 
+    /// Serve a TLS stream decrypted by the proxy as HTTP, or bridge it to a TLS
+    /// connection to the server.
     #[instrument(skip_all)]
+    async fn serve_decrypted<I>(
+        mut self,
+        mut stream: server::TlsStream<I>,
+        mut ctx: TunnelContext,
+        mode: TlsStreamMode,
+    ) where
+        I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let authority = ctx.authority.clone();
+        ctx.alpn_protocol = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+
+        let (is_http, data) = match mode {
+            TlsStreamMode::Http => (true, Bytes::new()),
+            TlsStreamMode::Raw => (false, Bytes::new()),
+            TlsStreamMode::Detect => match self.protocol_sniffer.sniff(&mut stream).await {
+                Ok((protocol, data)) => (
+                    matches!(protocol, Protocol::Http1 | Protocol::Http2),
+                    Bytes::from(data),
+                ),
+                Err(e) => {
+                    error!("Failed to read from TLS connection: {}", e);
+                    return;
+                }
+            },
+        };
+
+        let mut stream = Rewind::new(stream, data);
+
+        if is_http {
+            if let Err(e) = self
+                .serve_stream(TokioIo::new(stream), Scheme::HTTPS, authority)
+                .await
+            {
+                if !e.to_string().starts_with("error shutting down connection") {
+                    error!("HTTPS connect error: {}", e);
+                }
+            }
+
+            return;
+        }
+
+        let mut server = match tunnel::connect_tls(self.websocket_connector.as_ref(), &ctx).await {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Failed to connect to {}: {}", authority, e);
+                return;
+            }
+        };
+
+        if self.tunnel_handler.should_intercept(&ctx).await {
+            tunnel::handle_tunnel(self.tunnel_handler, ctx, stream, server).await;
+        } else if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut server).await {
+            error!("Failed to tunnel to {}: {}", authority, e);
+        }
+    }
+
     async fn serve_stream<I>(
         self,
         stream: I,
@@ -409,6 +875,22 @@ fn spawn_message_forwarder(
     spawn_with_trace(fut, span);
 }
 
+/// Resolve [`TlsStreamMode::Detect`] from the ALPN protocols offered by a
+/// client. It is left unresolved if the client offered none, in which case the
+/// decrypted data is sniffed.
+fn detect_tls_stream_mode(offered: &[Vec<u8>]) -> TlsStreamMode {
+    if offered.is_empty() {
+        TlsStreamMode::Detect
+    } else if offered
+        .iter()
+        .any(|protocol| protocol == b"h2" || protocol == b"http/1.1")
+    {
+        TlsStreamMode::Http
+    } else {
+        TlsStreamMode::Raw
+    }
+}
+
 #[instrument(skip_all)]
 fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
     // Hyper will automatically add a Host header if needed.
@@ -420,6 +902,8 @@ fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
         cookies.insert(joined_cookies.try_into().expect("Failed to join cookies"));
     }
 
+    // The version spoken with the upstream server is negotiated by the
+    // connection, and HTTP/1.1 requests are sent as HTTP/2 when h2 is chosen.
     *req.version_mut() = hyper::Version::HTTP_11;
     req
 }
@@ -438,14 +922,37 @@ mod tests {
         }
     }
 
-    fn build_proxy() -> InternalProxy<HttpConnector, CA, crate::NoopHandler, crate::NoopHandler> {
+    fn build_proxy() -> InternalProxy<
+        HttpConnector,
+        CA,
+        crate::NoopHandler,
+        crate::NoopHandler,
+        crate::NoopHandler,
+        crate::NoopHandler,
+    > {
         InternalProxy {
             ca: Arc::new(CA),
             client: Client::builder(TokioExecutor::new()).build(HttpConnector::new()),
             server: ServerBuilder::new(TokioExecutor::new()),
             http_handler: crate::NoopHandler::new(),
             websocket_handler: crate::NoopHandler::new(),
+            sse_handler: crate::NoopHandler::new(),
+            tunnel_handler: crate::NoopHandler::new(),
             websocket_connector: None,
+            network_conditions: None,
+            protocol_sniffer: Arc::new(ProtocolSniffer::new()),
+            alt_svc_policy: Arc::new(AltSvcPolicy::new()),
+            adaptive_passthrough: None,
+            #[cfg(feature = "decoder")]
+            accept_encoding_policy: None,
+            #[cfg(feature = "decoder")]
+            dictionary_store: None,
+            #[cfg(feature = "payload")]
+            payload_limits: None,
+            #[cfg(feature = "http2")]
+            upstream_versions: None,
+            #[cfg(feature = "http3")]
+            http3: None,
             client_addr: "127.0.0.1:8080".parse().unwrap(),
         }
     }
@@ -499,6 +1006,68 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "http2")]
+    mod upstream_client {
+        use super::*;
+        use tokio::net::TcpListener;
+
+        async fn start_server() -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(format!(
+                            "{:?}",
+                            req.version()
+                        ))))
+                    });
+
+                    tokio::spawn(async move {
+                        ServerBuilder::new(TokioExecutor::new())
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await
+                    });
+                }
+            });
+
+            addr
+        }
+
+        #[tokio::test]
+        async fn forces_version_for_host() {
+            use http_body_util::BodyExt;
+
+            let addr = start_server().await;
+            let client = Client::builder(TokioExecutor::new());
+            let mut http2 = client.clone();
+            http2.http2_only(true);
+
+            let mut proxy = build_proxy();
+            proxy.upstream_versions = Some(Arc::new(UpstreamVersions {
+                hosts: HashMap::from([("localhost".to_owned(), Version::HTTP_2)]),
+                http1: client.build(HttpConnector::new()),
+                http2: http2.build(HttpConnector::new()),
+            }));
+
+            for (host, expected) in [("localhost", "HTTP/2.0"), ("127.0.0.1", "HTTP/1.1")] {
+                let mut req = normalize_request(
+                    Request::builder()
+                        .uri(format!("http://{host}:{}/", addr.port()))
+                        .body(Body::empty())
+                        .unwrap(),
+                );
+
+                let res = proxy.upstream_client(&mut req).request(req).await.unwrap();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+
+                assert_eq!(body, expected);
+            }
+        }
+    }
+
     mod process_connect {
         use super::*;
 
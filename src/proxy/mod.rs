@@ -2,14 +2,28 @@ mod internal;
 
 pub mod builder;
 
+#[cfg(feature = "payload")]
+use crate::PayloadLimits;
+#[cfg(feature = "decoder")]
+use crate::{AcceptEncodingPolicy, DictionaryStore};
 use crate::{
+    AdaptivePassthrough,
+    AltSvcPolicy,
     Error,
     HttpHandler,
+    NetworkConditions,
+    ProtocolSniffer,
+    SseHandler,
+    TunnelHandler,
     WebSocketHandler,
     builder::ProxyBuilder,
     certificate_authority::CertificateAuthority,
 };
+#[cfg(feature = "http3")]
+use crate::{Http3Config, http3::Http3Client};
 use builder::{AddrOrListener, WantsAddr};
+#[cfg(feature = "http2")]
+use hyper::Version;
 use hyper::service::service_fn;
 use hyper_util::{
     client::legacy::{Builder as ClientBuilder, Client, connect::Connect},
@@ -17,6 +31,10 @@ use hyper_util::{
     server::conn::auto::Builder as ServerBuilder,
 };
 use internal::InternalProxy;
+#[cfg(feature = "http2")]
+use internal::UpstreamVersions;
+#[cfg(feature = "http2")]
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio_graceful::Shutdown;
@@ -70,31 +88,51 @@ use tracing::error;
 /// # #[cfg(not(all(feature = "rcgen-ca", feature = "rustls-client")))]
 /// # fn main() {}
 /// ```
-pub struct Proxy<C, CA, H, W, F> {
+pub struct Proxy<C, CA, H, W, S, T, F> {
     al: AddrOrListener,
     ca: Arc<CA>,
     http_connector: C,
     client: Option<ClientBuilder>,
     http_handler: H,
     websocket_handler: W,
+    sse_handler: S,
+    tunnel_handler: T,
     websocket_connector: Option<Connector>,
     server: Option<ServerBuilder<TokioExecutor>>,
+    network_conditions: Option<Arc<NetworkConditions>>,
+    protocol_sniffer: Arc<ProtocolSniffer>,
+    alt_svc_policy: Arc<AltSvcPolicy>,
+    adaptive_passthrough: Option<AdaptivePassthrough>,
+    #[cfg(feature = "decoder")]
+    accept_encoding_policy: Option<Arc<AcceptEncodingPolicy>>,
+    #[cfg(feature = "decoder")]
+    dictionary_store: Option<DictionaryStore>,
+    #[cfg(feature = "payload")]
+    payload_limits: Option<PayloadLimits>,
+    #[cfg(feature = "http2")]
+    http1_connector: Option<C>,
+    #[cfg(feature = "http2")]
+    upstream_versions: HashMap<String, Version>,
+    #[cfg(feature = "http3")]
+    http3: Option<Http3Config>,
     graceful_shutdown: F,
 }
 
-impl Proxy<(), (), (), (), ()> {
+impl Proxy<(), (), (), (), (), (), ()> {
     /// Create a new [`ProxyBuilder`].
     pub fn builder() -> ProxyBuilder<WantsAddr> {
         ProxyBuilder::new()
     }
 }
 
-impl<C, CA, H, W, F> Proxy<C, CA, H, W, F>
+impl<C, CA, H, W, S, T, F> Proxy<C, CA, H, W, S, T, F>
 where
     C: Connect + Clone + Send + Sync + 'static,
     CA: CertificateAuthority,
     H: HttpHandler,
     W: WebSocketHandler,
+    S: SseHandler,
+    T: TunnelHandler,
     F: Future<Output = ()> + Send + 'static,
 {
     /// Attempts to start the proxy server.
@@ -103,16 +141,33 @@ where
     ///
     /// This will return an error if the proxy server is unable to be started.
     pub async fn start(self) -> Result<(), Error> {
-        let client = self
-            .client
-            .unwrap_or_else(|| {
-                let mut builder = Client::builder(TokioExecutor::new());
-                builder
-                    .http1_title_case_headers(true)
-                    .http1_preserve_header_case(true);
-                builder
+        let client = self.client.unwrap_or_else(|| {
+            let mut builder = Client::builder(TokioExecutor::new());
+            builder
+                .http1_title_case_headers(true)
+                .http1_preserve_header_case(true);
+            builder
+        });
+
+        #[cfg(feature = "http2")]
+        let upstream_versions = (!self.upstream_versions.is_empty()).then(|| {
+            let mut http2 = client.clone();
+            http2.http2_only(true);
+
+            Arc::new(UpstreamVersions {
+                hosts: self.upstream_versions,
+                http1: client.build(
+                    self.http1_connector
+                        .unwrap_or_else(|| self.http_connector.clone()),
+                ),
+                http2: http2.build(self.http_connector.clone()),
             })
-            .build(self.http_connector);
+        });
+
+        let client = client.build(self.http_connector);
+
+        #[cfg(feature = "http3")]
+        let http3 = self.http3.map(Http3Client::new).transpose()?.map(Arc::new);
 
         let server = self.server.unwrap_or_else(|| {
             let mut builder = ServerBuilder::new(TokioExecutor::new());
@@ -147,7 +202,23 @@ where
                     let ca = Arc::clone(&self.ca);
                     let http_handler = self.http_handler.clone();
                     let websocket_handler = self.websocket_handler.clone();
+                    let sse_handler = self.sse_handler.clone();
+                    let tunnel_handler = self.tunnel_handler.clone();
                     let websocket_connector = self.websocket_connector.clone();
+                    let network_conditions = self.network_conditions.clone();
+                    let protocol_sniffer = Arc::clone(&self.protocol_sniffer);
+                    let alt_svc_policy = Arc::clone(&self.alt_svc_policy);
+                    let adaptive_passthrough = self.adaptive_passthrough.clone();
+                    #[cfg(feature = "decoder")]
+                    let accept_encoding_policy = self.accept_encoding_policy.clone();
+                    #[cfg(feature = "decoder")]
+                    let dictionary_store = self.dictionary_store.clone();
+                    #[cfg(feature = "payload")]
+                    let payload_limits = self.payload_limits;
+                    #[cfg(feature = "http2")]
+                    let upstream_versions = upstream_versions.clone();
+                    #[cfg(feature = "http3")]
+                    let http3 = http3.clone();
 
                     shutdown.spawn_task_fn(move |guard| async move {
                         let conn = server.serve_connection_with_upgrades(
@@ -159,7 +230,23 @@ where
                                     server: server.clone(),
                                     http_handler: http_handler.clone(),
                                     websocket_handler: websocket_handler.clone(),
+                                    sse_handler: sse_handler.clone(),
+                                    tunnel_handler: tunnel_handler.clone(),
                                     websocket_connector: websocket_connector.clone(),
+                                    network_conditions: network_conditions.clone(),
+                                    protocol_sniffer: Arc::clone(&protocol_sniffer),
+                                    alt_svc_policy: Arc::clone(&alt_svc_policy),
+                                    adaptive_passthrough: adaptive_passthrough.clone(),
+                                    #[cfg(feature = "decoder")]
+                                    accept_encoding_policy: accept_encoding_policy.clone(),
+                                    #[cfg(feature = "decoder")]
+                                    dictionary_store: dictionary_store.clone(),
+                                    #[cfg(feature = "payload")]
+                                    payload_limits,
+                                    #[cfg(feature = "http2")]
+                                    upstream_versions: upstream_versions.clone(),
+                                    #[cfg(feature = "http3")]
+                                    http3: http3.clone(),
                                     client_addr,
                                 }
                                 .proxy(req)
@@ -0,0 +1,375 @@
+use crate::{
+    ClientHello,
+    client_hello::{MAX_CLIENT_HELLO_LEN, Malformed},
+};
+use std::{fmt, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{Instant, timeout_at},
+};
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Protocol spoken by a client inside a CONNECT tunnel.
+///
+/// The detected protocol is added to the extensions of the CONNECT request
+/// before [`HttpHandler::should_intercept`](crate::HttpHandler::should_intercept)
+/// is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Protocol {
+    /// HTTP/1.x.
+    Http1,
+    /// HTTP/2 with prior knowledge.
+    Http2,
+    /// TLS.
+    Tls,
+    /// SSH.
+    Ssh,
+    /// A protocol recognised by a custom [`ProtocolDetector`].
+    Other(&'static str),
+    /// The protocol could not be recognised, either because no detector
+    /// matched or because the client did not send enough data in time.
+    Unknown,
+}
+
+/// Result of inspecting the start of a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Detection {
+    /// The data belongs to the protocol.
+    Match(Protocol),
+    /// More data is needed to tell.
+    NeedMore,
+    /// The data does not belong to the protocol.
+    NoMatch,
+}
+
+/// Recognises a protocol from the first bytes sent by a client.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Detection, Protocol, ProtocolDetector};
+///
+/// struct Redis;
+///
+/// impl ProtocolDetector for Redis {
+///     fn detect(&self, data: &[u8]) -> Detection {
+///         match data.first() {
+///             None => Detection::NeedMore,
+///             Some(b'*') => Detection::Match(Protocol::Other("redis")),
+///             Some(_) => Detection::NoMatch,
+///         }
+///     }
+/// }
+/// ```
+pub trait ProtocolDetector: Send + Sync + 'static {
+    /// Inspect the data read so far. This is called again with more data for
+    /// as long as [`Detection::NeedMore`] is returned.
+    fn detect(&self, data: &[u8]) -> Detection;
+}
+
+/// Detects the protocol spoken inside a CONNECT tunnel.
+///
+/// Data is read from the client until a detector recognises it, or until the
+/// timeout elapses, after which the tunnel is treated as [`Protocol::Unknown`].
+/// The timeout keeps protocols where the server speaks first from stalling.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::ProtocolSniffer;
+/// use std::time::Duration;
+///
+/// let sniffer = ProtocolSniffer::new().with_timeout(Duration::from_millis(500));
+/// ```
+#[derive(Clone)]
+pub struct ProtocolSniffer {
+    detectors: Vec<Arc<dyn ProtocolDetector>>,
+    timeout: Duration,
+    max_len: usize,
+}
+
+impl ProtocolSniffer {
+    /// Default time to wait for enough data to recognise a protocol.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+    /// Default number of bytes read before giving up on recognising a
+    /// protocol.
+    pub const DEFAULT_MAX_LEN: usize = 8 * 1024;
+
+    /// Create a sniffer with the built-in detectors.
+    pub fn new() -> Self {
+        Self {
+            detectors: Vec::new(),
+            timeout: Self::DEFAULT_TIMEOUT,
+            max_len: Self::DEFAULT_MAX_LEN,
+        }
+    }
+
+    /// Set the time to wait for enough data to recognise a protocol.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the number of bytes read before giving up on recognising a
+    /// protocol.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Add a custom detector. Custom detectors are consulted in the order they
+    /// were added, before the built-in ones.
+    pub fn with_detector(mut self, detector: impl ProtocolDetector) -> Self {
+        self.detectors.push(Arc::new(detector));
+        self
+    }
+
+    /// Classify the data read so far, or return `None` if more is needed. Once
+    /// no more data can be read, detectors that need more are skipped.
+    fn classify(&self, data: &[u8], done: bool) -> Option<Protocol> {
+        let detections = self
+            .detectors
+            .iter()
+            .map(|detector| detector.detect(data))
+            .chain(builtin(data));
+
+        for detection in detections {
+            match detection {
+                Detection::Match(protocol) => return Some(protocol),
+                Detection::NeedMore if !done => return None,
+                _ => {}
+            }
+        }
+
+        Some(Protocol::Unknown)
+    }
+
+    /// Read from a client until its protocol can be recognised, returning the
+    /// protocol along with the data that was read.
+    pub(crate) async fn sniff<I>(&self, io: &mut I) -> io::Result<(Protocol, Vec<u8>)>
+    where
+        I: AsyncRead + Unpin,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = Vec::with_capacity(512);
+
+        loop {
+            if let Some(protocol) = self.classify(&buf, buf.len() >= self.max_len) {
+                return Ok((protocol, buf));
+            }
+
+            let mut chunk = [0; 512];
+            let len = chunk.len().min(self.max_len - buf.len());
+
+            match timeout_at(deadline, io.read(&mut chunk[..len])).await {
+                Ok(Ok(0)) | Err(_) => {
+                    let protocol = self.classify(&buf, true).unwrap_or(Protocol::Unknown);
+                    return Ok((protocol, buf));
+                }
+                Ok(Ok(read)) => buf.extend_from_slice(&chunk[..read]),
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Keep reading from a client that started a TLS handshake until its
+    /// ClientHello can be parsed, appending to the data that was read. `None`
+    /// is returned if the ClientHello is malformed or is not complete in time.
+    pub(crate) async fn read_client_hello<I>(
+        &self,
+        io: &mut I,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<Option<ClientHello>>
+    where
+        I: AsyncRead + Unpin,
+    {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            match ClientHello::parse(buf) {
+                Ok(Some(hello)) => return Ok(Some(hello)),
+                Ok(None) if buf.len() < MAX_CLIENT_HELLO_LEN => {}
+                Ok(None) | Err(Malformed) => return Ok(None),
+            }
+
+            match timeout_at(deadline, io.read_buf(buf)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(None),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for ProtocolSniffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ProtocolSniffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtocolSniffer")
+            .field("detectors", &self.detectors.len())
+            .field("timeout", &self.timeout)
+            .field("max_len", &self.max_len)
+            .finish()
+    }
+}
+
+fn builtin(data: &[u8]) -> [Detection; 4] {
+    [
+        detect_prefix(data, HTTP2_PREFACE, Protocol::Http2),
+        detect_tls(data),
+        detect_prefix(data, b"SSH-", Protocol::Ssh),
+        detect_http1(data),
+    ]
+}
+
+fn detect_prefix(data: &[u8], prefix: &[u8], protocol: Protocol) -> Detection {
+    let len = data.len().min(prefix.len());
+
+    if data[..len] != prefix[..len] {
+        Detection::NoMatch
+    } else if len < prefix.len() {
+        Detection::NeedMore
+    } else {
+        Detection::Match(protocol)
+    }
+}
+
+/// A TLS handshake record, with a major version of 3.
+fn detect_tls(data: &[u8]) -> Detection {
+    match data {
+        [] | [0x16] => Detection::NeedMore,
+        [0x16, 0x03, ..] => Detection::Match(Protocol::Tls),
+        _ => Detection::NoMatch,
+    }
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// An HTTP/1.x request line, made of a method token, a request target and an
+/// `HTTP/1.x` version.
+fn detect_http1(data: &[u8]) -> Detection {
+    let method = data.iter().take_while(|&&b| is_tchar(b)).count();
+
+    match data.get(method) {
+        None => return Detection::NeedMore,
+        Some(b' ') if method > 0 => {}
+        Some(_) => return Detection::NoMatch,
+    }
+
+    let Some(end) = data.iter().position(|&b| b == b'\n') else {
+        return Detection::NeedMore;
+    };
+
+    let line = &data[method + 1..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    match line.rsplit(|&b| b == b' ').next() {
+        Some([b'H', b'T', b'T', b'P', b'/', b'1', b'.', v]) if v.is_ascii_digit() => {
+            Detection::Match(Protocol::Http1)
+        }
+        _ => Detection::NoMatch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn detects_builtin_protocols() {
+        let sniffer = ProtocolSniffer::new();
+
+        for (data, protocol) in [
+            (&b"GET / HTTP/1.1\r\n"[..], Protocol::Http1),
+            (b"OPTIONS * HTTP/1.0\n", Protocol::Http1),
+            (b"PROPFIND /a%20b HTTP/1.1\r\n", Protocol::Http1),
+            (HTTP2_PREFACE, Protocol::Http2),
+            (b"\x16\x03\x01\x02\x00\x01", Protocol::Tls),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Protocol::Ssh),
+            (b"GET / FTP/1.1\r\n", Protocol::Unknown),
+            (b"\x00\x01\x02", Protocol::Unknown),
+        ] {
+            assert_eq!(sniffer.classify(data, false), Some(protocol));
+        }
+
+        for data in [
+            &b""[..],
+            b"POS",
+            b"POST /upload",
+            b"PRI * HTTP/2",
+            b"\x16",
+            b"SS",
+        ] {
+            assert_eq!(sniffer.classify(data, false), None);
+            assert_eq!(sniffer.classify(data, true), Some(Protocol::Unknown));
+        }
+    }
+
+    #[test]
+    fn consults_custom_detectors_first() {
+        struct Everything;
+
+        impl ProtocolDetector for Everything {
+            fn detect(&self, data: &[u8]) -> Detection {
+                match data.len() {
+                    0..4 => Detection::NeedMore,
+                    _ => Detection::Match(Protocol::Other("everything")),
+                }
+            }
+        }
+
+        let sniffer = ProtocolSniffer::new().with_detector(Everything);
+
+        assert_eq!(sniffer.classify(b"GET", false), None);
+        assert_eq!(sniffer.classify(b"GET", true), Some(Protocol::Unknown));
+        assert_eq!(
+            sniffer.classify(b"GET / HTTP/1.1\r\n", false),
+            Some(Protocol::Other("everything"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_until_classified() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            for chunk in [
+                &b"DEL"[..],
+                b"ETE /item",
+                b"/1 HTTP/1.1\r\n",
+                b"Host: a\r\n",
+            ] {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let (protocol, data) = ProtocolSniffer::new().sniff(&mut server).await.unwrap();
+
+        assert_eq!(protocol, Protocol::Http1);
+        assert!(data.starts_with(b"DELETE /item/1 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_timeout() {
+        let (_client, mut server) = tokio::io::duplex(64);
+
+        let (protocol, data) = ProtocolSniffer::new()
+            .with_timeout(Duration::from_millis(10))
+            .sniff(&mut server)
+            .await
+            .unwrap();
+
+        assert_eq!(protocol, Protocol::Unknown);
+        assert!(data.is_empty());
+    }
+}
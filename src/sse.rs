@@ -0,0 +1,403 @@
+use crate::{Body, HttpContext, SseHandler};
+use futures::{SinkExt, StreamExt, channel::mpsc};
+use http_body_util::BodyExt;
+use hyper::{
+    Response,
+    body::Bytes,
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+};
+use std::{collections::VecDeque, fmt::Write};
+use tracing::{Instrument, info_span};
+
+/// An event of a `text/event-stream` response.
+///
+/// Lines of the `data` field are joined with `\n`. Fields that are not set are
+/// left out when the event is forwarded.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SseEvent {
+    /// The `id` field.
+    pub id: Option<String>,
+    /// The `event` field, which names the type of the event.
+    pub event: Option<String>,
+    /// The `data` field.
+    pub data: Option<String>,
+    /// The `retry` field, in milliseconds.
+    pub retry: Option<u64>,
+    /// Comment lines, such as keep-alive messages.
+    pub comments: Vec<String>,
+}
+
+impl SseEvent {
+    /// Create an event with the given data.
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: Some(data.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Serialize the event, including the blank line that ends it.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = String::new();
+        let line = |value: &str| value.replace(['\r', '\n'], "");
+
+        for comment in &self.comments {
+            for comment in comment.lines() {
+                let _ = writeln!(buf, ":{comment}");
+            }
+        }
+
+        if let Some(event) = &self.event {
+            let _ = writeln!(buf, "event: {}", line(event));
+        }
+
+        if let Some(id) = &self.id {
+            let _ = writeln!(buf, "id: {}", line(id));
+        }
+
+        if let Some(retry) = self.retry {
+            let _ = writeln!(buf, "retry: {retry}");
+        }
+
+        if let Some(data) = &self.data {
+            for data in data.split('\n') {
+                let _ = writeln!(buf, "data: {}", data.trim_end_matches('\r'));
+            }
+        }
+
+        buf.push('\n');
+        Bytes::from(buf)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Parser for `text/event-stream` bodies.
+#[derive(Debug, Default)]
+pub(crate) struct SseParser {
+    buf: Vec<u8>,
+    event: SseEvent,
+    started: bool,
+}
+
+impl SseParser {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a chunk of the body, returning the events that it completes.
+    pub(crate) fn push(&mut self, data: &[u8], events: &mut VecDeque<SseEvent>) {
+        self.buf.extend_from_slice(data);
+
+        if !self.started {
+            if self.buf.len() < 3 && b"\xef\xbb\xbf".starts_with(&self.buf) {
+                return;
+            }
+
+            if self.buf.starts_with(b"\xef\xbb\xbf") {
+                self.buf.drain(..3);
+            }
+
+            self.started = true;
+        }
+
+        let mut start = 0;
+
+        while let Some(offset) = self.buf[start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+        {
+            let end = start + offset;
+            let next = match self.buf[end] {
+                b'\r' if end + 1 == self.buf.len() => break,
+                b'\r' if self.buf[end + 1] == b'\n' => end + 2,
+                _ => end + 1,
+            };
+
+            let line = String::from_utf8_lossy(&self.buf[start..end]).into_owned();
+            self.line(&line, events);
+            start = next;
+        }
+
+        self.buf.drain(..start);
+    }
+
+    /// Finish parsing at the end of the body. An event that isn't followed by
+    /// a blank line is discarded, as it would be by the client.
+    pub(crate) fn finish(&mut self, events: &mut VecDeque<SseEvent>) {
+        if self.buf.ends_with(b"\r") {
+            self.buf.pop();
+            let line = String::from_utf8_lossy(&self.buf).into_owned();
+            self.buf.clear();
+            self.line(&line, events);
+        }
+    }
+
+    fn line(&mut self, line: &str, events: &mut VecDeque<SseEvent>) {
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.event);
+
+            if !event.is_empty() {
+                events.push_back(event);
+            }
+
+            return;
+        }
+
+        if let Some(comment) = line.strip_prefix(':') {
+            self.event.comments.push(comment.to_owned());
+            return;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event.event = Some(value.to_owned()),
+            "data" => match &mut self.event.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.event.data = Some(value.to_owned()),
+            },
+            "id" if !value.contains('\0') => self.event.id = Some(value.to_owned()),
+            "retry" => {
+                if let Ok(retry) = value.parse() {
+                    self.event.retry = Some(retry);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a response is an event stream that can be handled by an
+/// [`SseHandler`].
+fn is_event_stream(res: &Response<Body>) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Pass the events of a `text/event-stream` response through a handler, while
+/// the stream stays open.
+pub(crate) async fn handle_sse<S: SseHandler>(
+    mut handler: S,
+    ctx: &HttpContext,
+    res: Response<Body>,
+) -> Response<Body> {
+    if !is_event_stream(&res) || !handler.should_intercept(ctx, &res).await {
+        return res;
+    }
+
+    #[cfg(not(feature = "decoder"))]
+    if res.headers().contains_key(CONTENT_ENCODING) {
+        return res;
+    }
+
+    #[cfg_attr(not(feature = "decoder"), allow(unused_mut))]
+    let (mut parts, mut body) = res.into_parts();
+
+    #[cfg(feature = "decoder")]
+    if parts.headers.contains_key(CONTENT_ENCODING) {
+        if let Err(e) = crate::decoder::decode_body_in_place(
+            crate::decoder::extract_encodings(&parts.headers),
+            &mut body,
+            &crate::DecodeLimits::default(),
+            parts.extensions.get::<crate::DictionaryStore>(),
+        ) {
+            tracing::error!("Failed to decode event stream: {}", e);
+            return Response::from_parts(parts, body);
+        }
+    }
+
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+
+    let (mut tx, rx) = mpsc::channel(16);
+    let ctx = ctx.clone();
+
+    let fut = async move {
+        let mut body = body.into_data_stream();
+        let mut parser = SseParser::new();
+        let mut events = VecDeque::new();
+
+        loop {
+            let done = match body.next().await {
+                Some(Ok(data)) => {
+                    parser.push(&data, &mut events);
+                    false
+                }
+                Some(Err(e)) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+                None => {
+                    parser.finish(&mut events);
+                    true
+                }
+            };
+
+            while let Some(event) = events.pop_front() {
+                for event in handler.handle_event(&ctx, event).await {
+                    if tx.send(Ok(event.to_bytes())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+
+            if done {
+                return;
+            }
+        }
+    };
+
+    tokio::spawn(fut.instrument(info_span!("sse")));
+    Response::from_parts(parts, Body::from_stream(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    fn parse(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut parser = SseParser::new();
+        let mut events = VecDeque::new();
+
+        for chunk in chunks {
+            parser.push(chunk, &mut events);
+        }
+
+        parser.finish(&mut events);
+        events.into()
+    }
+
+    #[test]
+    fn parses_events() {
+        let events = parse(&[
+            b"\xef\xbb",
+            b"\xbf: ping\r\n\r\nevent: add\ndata: 1\ndata:2\r",
+            b"\nid: 7\nretry: 100\nretry: x\n\ndata\n\nid: partial",
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    comments: vec![" ping".to_owned()],
+                    ..Default::default()
+                },
+                SseEvent {
+                    id: Some("7".to_owned()),
+                    event: Some("add".to_owned()),
+                    data: Some("1\n2".to_owned()),
+                    retry: Some(100),
+                    comments: Vec::new(),
+                },
+                SseEvent::new(""),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_events() {
+        let event = SseEvent {
+            id: Some("1".to_owned()),
+            event: Some("update".to_owned()),
+            data: Some("a\n b".to_owned()),
+            retry: Some(5),
+            comments: vec!["hi".to_owned()],
+        };
+
+        assert_eq!(
+            &event.to_bytes()[..],
+            b":hi\nevent: update\nid: 1\nretry: 5\ndata: a\ndata:  b\n\n"
+        );
+        assert_eq!(parse(&[&event.to_bytes()]), vec![event]);
+    }
+
+    #[derive(Clone)]
+    struct Handler;
+
+    impl SseHandler for Handler {
+        async fn handle_event(&mut self, _ctx: &HttpContext, event: SseEvent) -> Vec<SseEvent> {
+            match event.data.as_deref() {
+                Some("drop") => Vec::new(),
+                Some("double") => vec![event.clone(), event],
+                _ => vec![SseEvent {
+                    event: Some("changed".to_owned()),
+                    ..event
+                }],
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_events() {
+        let ctx = HttpContext::from_request(
+            &Request::new(Body::empty()),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CONTENT_LENGTH, 42)
+            .body(Body::from("data: drop\n\ndata: double\n\ndata: x\n\n"))
+            .unwrap();
+
+        let res = handle_sse(Handler, &ctx, res).await;
+
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"data: double\n\ndata: double\n\nevent: changed\ndata: x\n\n"
+        );
+    }
+
+    #[cfg(feature = "decoder")]
+    #[tokio::test]
+    async fn forwards_undecodable_responses() {
+        let ctx = HttpContext::from_request(
+            &Request::new(Body::empty()),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CONTENT_ENCODING, "unknown")
+            .header(CONTENT_LENGTH, 12)
+            .body(Body::from("data: drop\n\n"))
+            .unwrap();
+
+        let res = handle_sse(Handler, &ctx, res).await;
+
+        assert_eq!(res.headers()[CONTENT_ENCODING], "unknown");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "12");
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"data: drop\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_other_responses() {
+        let ctx = HttpContext::from_request(
+            &Request::new(Body::empty()),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        let res = Response::new(Body::from("data: drop\n\n"));
+
+        let res = handle_sse(Handler, &ctx, res).await;
+
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"data: drop\n\n"
+        );
+    }
+}
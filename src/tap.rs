@@ -0,0 +1,251 @@
+use crate::{Body, Error};
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::HeaderMap,
+};
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+/// Totals reported once a tapped body has finished streaming.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TapSummary {
+    /// Number of data frames observed.
+    pub frames: usize,
+    /// Number of data bytes observed.
+    pub bytes: u64,
+    /// Trailers sent at the end of the body, if any.
+    pub trailers: Option<HeaderMap>,
+    /// Whether the body was read to the end without an error.
+    pub complete: bool,
+}
+
+/// Observes a body as it streams through the proxy.
+///
+/// Observers are attached with [`Body::tap`]. Frames are passed to the
+/// observer as they are forwarded, so the body is never buffered.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     Body,
+///     BodyObserver,
+///     HttpContext,
+///     HttpHandler,
+///     TapSummary,
+///     hyper::{Response, body::Bytes},
+/// };
+///
+/// struct Sampler {
+///     sample: Vec<u8>,
+/// }
+///
+/// impl BodyObserver for Sampler {
+///     fn on_data(&mut self, data: &Bytes) {
+///         let len = data.len().min(64 - self.sample.len());
+///         self.sample.extend_from_slice(&data[..len]);
+///     }
+///
+///     fn on_end(&mut self, summary: TapSummary) {
+///         println!("{} bytes, starting with {:?}", summary.bytes, self.sample);
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         res: Response<Body>,
+///     ) -> Response<Body> {
+///         res.map(|body| body.tap(Sampler { sample: Vec::new() }))
+///     }
+/// }
+/// ```
+pub trait BodyObserver: Send + Sync + 'static {
+    /// This will be called for each data frame of the body.
+    fn on_data(&mut self, _data: &Bytes) {}
+
+    /// This will be called with the trailers of the body, if it has any. They
+    /// are also included in the [`TapSummary`].
+    fn on_trailers(&mut self, _trailers: &HeaderMap) {}
+
+    /// This will be called once when the body ends, fails, or is dropped
+    /// before it was read to the end.
+    fn on_end(&mut self, _summary: TapSummary) {}
+}
+
+pub(crate) struct Tap<O: BodyObserver> {
+    inner: Body,
+    observer: O,
+    summary: TapSummary,
+    ended: bool,
+}
+
+impl<O: BodyObserver> Tap<O> {
+    pub(crate) fn new(inner: Body, observer: O) -> Self {
+        Self {
+            inner,
+            observer,
+            summary: TapSummary::default(),
+            ended: false,
+        }
+    }
+
+    fn end(&mut self, complete: bool) {
+        if !self.ended {
+            self.ended = true;
+            self.summary.complete = complete;
+            self.observer.on_end(std::mem::take(&mut self.summary));
+        }
+    }
+}
+
+// The observer is never pinned, only the body is polled through the pin.
+impl<O: BodyObserver> Unpin for Tap<O> {}
+
+impl<O: BodyObserver> HttpBody for Tap<O> {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.summary.frames += 1;
+                    this.summary.bytes += data.len() as u64;
+                    this.observer.on_data(data);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    this.observer.on_trailers(trailers);
+                    this.summary.trailers = Some(trailers.clone());
+                }
+
+                // Consumers such as hyper stop polling once the body reports
+                // its end, without waiting for `None`.
+                if this.inner.is_end_stream() {
+                    this.end(true);
+                }
+            }
+            Some(Err(_)) => this.end(false),
+            None => this.end(true),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<O: BodyObserver> Drop for Tap<O> {
+    fn drop(&mut self) {
+        let complete = self.inner.is_end_stream();
+        self.end(complete);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        data: Arc<Mutex<Vec<u8>>>,
+        trailers: Arc<Mutex<Vec<HeaderMap>>>,
+        summaries: Arc<Mutex<Vec<TapSummary>>>,
+    }
+
+    impl BodyObserver for Recorder {
+        fn on_data(&mut self, data: &Bytes) {
+            self.data.lock().unwrap().extend_from_slice(data);
+        }
+
+        fn on_trailers(&mut self, trailers: &HeaderMap) {
+            self.trailers.lock().unwrap().push(trailers.clone());
+        }
+
+        fn on_end(&mut self, summary: TapSummary) {
+            self.summaries.lock().unwrap().push(summary);
+        }
+    }
+
+    #[tokio::test]
+    async fn observes_frames_and_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+
+        let body = Body::from(StreamBody::new(futures::stream::iter(vec![
+            Ok(Frame::data(Bytes::from_static(b"hello, "))),
+            Ok(Frame::data(Bytes::from_static(b"world"))),
+            Ok(Frame::trailers(trailers.clone())),
+        ])));
+
+        let recorder = Recorder::default();
+        let collected = body.tap(recorder.clone()).collect().await.unwrap();
+
+        assert_eq!(&collected.to_bytes()[..], b"hello, world");
+        assert_eq!(&recorder.data.lock().unwrap()[..], b"hello, world");
+        assert_eq!(recorder.trailers.lock().unwrap()[..], [trailers.clone()]);
+        assert_eq!(
+            recorder.summaries.lock().unwrap()[..],
+            [TapSummary {
+                frames: 2,
+                bytes: 12,
+                trailers: Some(trailers),
+                complete: true,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_complete_body_at_end_stream() {
+        let recorder = Recorder::default();
+        let mut body = Body::from(Full::new(Bytes::from_static(b"hello"))).tap(recorder.clone());
+
+        // Stop at `is_end_stream` like hyper does, rather than waiting for
+        // `None`.
+        while !body.is_end_stream() {
+            body.frame().await.unwrap().unwrap();
+        }
+        drop(body);
+
+        assert_eq!(
+            recorder.summaries.lock().unwrap()[..],
+            [TapSummary {
+                frames: 1,
+                bytes: 5,
+                trailers: None,
+                complete: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_incomplete_body_on_drop() {
+        let recorder = Recorder::default();
+        drop(Body::from(Full::new(Bytes::from_static(b"hello"))).tap(recorder.clone()));
+
+        let summaries = recorder.summaries.lock().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(!summaries[0].complete);
+    }
+}
@@ -0,0 +1,500 @@
+use crate::{Protocol, TunnelHandler};
+use futures::future;
+use http::uri::Authority;
+use hyper::body::Bytes;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+use tokio_tungstenite::Connector;
+use tracing::error;
+
+#[cfg(feature = "rustls-client")]
+use std::sync::LazyLock;
+#[cfg(feature = "rustls-client")]
+use tokio_rustls::rustls::{ClientConfig, crypto::aws_lc_rs};
+
+const CHUNK_LEN: usize = 8 * 1024;
+
+/// Context for a tunnel passed to a [`TunnelHandler`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TunnelContext {
+    /// Address of the client.
+    pub client_addr: SocketAddr,
+    /// Authority the client asked to connect to.
+    pub authority: Authority,
+    /// Protocol detected at the start of the tunnel.
+    pub protocol: Protocol,
+    /// Whether the proxy terminated TLS with the client, in which case the
+    /// handler sees decrypted data and the server is reached over a separate
+    /// TLS connection.
+    pub decrypted: bool,
+    /// Server name sent by the client in its TLS ClientHello.
+    pub server_name: Option<String>,
+    /// Protocols offered by the client through ALPN in its TLS ClientHello, in
+    /// order of preference.
+    pub offered_alpn_protocols: Vec<Vec<u8>>,
+    /// Protocol negotiated with the client through ALPN, if the tunnel was
+    /// decrypted.
+    pub alpn_protocol: Option<Vec<u8>>,
+    from_client: Arc<AtomicU64>,
+    from_server: Arc<AtomicU64>,
+}
+
+impl TunnelContext {
+    pub(crate) fn new(client_addr: SocketAddr, authority: Authority, protocol: Protocol) -> Self {
+        Self {
+            client_addr,
+            authority,
+            protocol,
+            decrypted: false,
+            server_name: None,
+            offered_alpn_protocols: Vec::new(),
+            alpn_protocol: None,
+            from_client: Arc::default(),
+            from_server: Arc::default(),
+        }
+    }
+
+    /// Number of bytes read from the client so far, before they were handled.
+    pub fn bytes_from_client(&self) -> u64 {
+        self.from_client.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read from the server so far, before they were handled.
+    pub fn bytes_from_server(&self) -> u64 {
+        self.from_server.load(Ordering::Relaxed)
+    }
+
+    fn count(&self, direction: TunnelDirection, len: usize) {
+        let counter = match direction {
+            TunnelDirection::ClientToServer => &self.from_client,
+            TunnelDirection::ServerToClient => &self.from_server,
+        };
+
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+/// Direction in which a chunk is travelling through a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TunnelDirection {
+    /// From the client to the server.
+    ClientToServer,
+    /// From the server to the client.
+    ServerToClient,
+}
+
+/// What to do with a chunk read from a tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TunnelAction {
+    /// Forward the given data in place of the chunk. Forwarding empty data
+    /// drops the chunk.
+    Forward(Bytes),
+    /// Close both sides of the tunnel.
+    Close,
+}
+
+/// How a TLS stream decrypted by the proxy is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TlsStreamMode {
+    /// Serve the stream as HTTP.
+    Http,
+    /// Bridge the stream to a TLS connection to the server, passing it through
+    /// the [`TunnelHandler`].
+    Raw,
+    /// Serve the stream as HTTP if the client offered an HTTP protocol through
+    /// ALPN, and bridge it if it only offered other protocols. If it offered
+    /// none, the stream is served as HTTP if the decrypted data looks like
+    /// HTTP, and bridged otherwise.
+    ///
+    /// Protocols where the server speaks first are only detected without ALPN
+    /// once the timeout of the [`ProtocolSniffer`](crate::ProtocolSniffer)
+    /// elapses.
+    #[default]
+    Detect,
+}
+
+/// A stream that can be used in either direction of a tunnel.
+pub(crate) trait TunnelIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunnelIo for T {}
+
+/// Client config used to reach the server of a decrypted tunnel when no
+/// connector is configured, trusting the webpki roots.
+#[cfg(feature = "rustls-client")]
+static DEFAULT_CLIENT_CONFIG: LazyLock<Arc<ClientConfig>> = LazyLock::new(|| {
+    use hyper_rustls::ConfigBuilderExt;
+
+    let config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("the default provider supports the default protocol versions")
+        .with_webpki_roots()
+        .with_no_client_auth();
+
+    Arc::new(config)
+});
+
+/// Open a TLS connection to the server of a decrypted tunnel, using the server
+/// name and ALPN protocol negotiated with the client.
+///
+/// Without a connector, a rustls client trusting the webpki roots is used, or
+/// a native-tls client if only the `native-tls-client` feature is enabled.
+#[cfg_attr(
+    not(any(feature = "rustls-client", feature = "native-tls-client")),
+    allow(unused_variables)
+)]
+pub(crate) async fn connect_tls(
+    connector: Option<&Connector>,
+    ctx: &TunnelContext,
+) -> io::Result<Box<dyn TunnelIo>> {
+    let host = ctx
+        .server_name
+        .as_deref()
+        .unwrap_or_else(|| ctx.authority.host());
+
+    match connector {
+        #[cfg(feature = "rustls-client")]
+        Some(Connector::Rustls(config)) => connect_rustls(config, host, ctx).await,
+        #[cfg(feature = "rustls-client")]
+        None => connect_rustls(&DEFAULT_CLIENT_CONFIG, host, ctx).await,
+        #[cfg(feature = "native-tls-client")]
+        Some(Connector::NativeTls(connector)) => {
+            connect_native_tls(connector.clone(), host, ctx).await
+        }
+        #[cfg(all(feature = "native-tls-client", not(feature = "rustls-client")))]
+        None => {
+            let connector =
+                tokio_native_tls::native_tls::TlsConnector::new().map_err(io::Error::other)?;
+            connect_native_tls(connector, host, ctx).await
+        }
+        _ => Err(io::Error::other("no TLS connector is configured")),
+    }
+}
+
+#[cfg(feature = "rustls-client")]
+async fn connect_rustls(
+    config: &ClientConfig,
+    host: &str,
+    ctx: &TunnelContext,
+) -> io::Result<Box<dyn TunnelIo>> {
+    use tokio_rustls::{TlsConnector, rustls::pki_types::ServerName};
+
+    let server_name = ServerName::try_from(host.trim_matches(['[', ']']).to_owned())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut config = config.clone();
+    config.alpn_protocols = ctx.alpn_protocol.iter().cloned().collect();
+
+    let tcp = tokio::net::TcpStream::connect(ctx.authority.as_str()).await?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp)
+        .await?;
+
+    Ok(Box::new(stream))
+}
+
+#[cfg(feature = "native-tls-client")]
+async fn connect_native_tls(
+    connector: tokio_native_tls::native_tls::TlsConnector,
+    host: &str,
+    ctx: &TunnelContext,
+) -> io::Result<Box<dyn TunnelIo>> {
+    let tcp = tokio::net::TcpStream::connect(ctx.authority.as_str()).await?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, tcp)
+        .await
+        .map_err(io::Error::other)?;
+
+    Ok(Box::new(stream))
+}
+
+/// Pass the chunks of both directions of a tunnel through a handler, until
+/// both sides are closed or the handler closes the tunnel.
+///
+/// The directions are driven concurrently, so a write blocked on one side
+/// doesn't stop the other side from being read.
+pub(crate) async fn handle_tunnel<T, A, B>(handler: T, ctx: TunnelContext, client: A, server: B)
+where
+    T: TunnelHandler,
+    A: AsyncRead + AsyncWrite + Send + Unpin,
+    B: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(server);
+    let handler = Mutex::new(handler);
+
+    // The first direction to close the tunnel drops the other, closing both
+    // sides.
+    let _ = future::try_join(
+        pipe(
+            &handler,
+            &ctx,
+            TunnelDirection::ClientToServer,
+            client_read,
+            server_write,
+        ),
+        pipe(
+            &handler,
+            &ctx,
+            TunnelDirection::ServerToClient,
+            server_read,
+            client_write,
+        ),
+    )
+    .await;
+
+    handler.into_inner().handle_close(&ctx).await;
+}
+
+/// Signal from one direction of a tunnel that the whole tunnel should close.
+struct Closed;
+
+/// Pass the chunks of one direction of a tunnel through a handler, until the
+/// reader is done.
+async fn pipe<T, R, W>(
+    handler: &Mutex<T>,
+    ctx: &TunnelContext,
+    direction: TunnelDirection,
+    mut reader: R,
+    mut writer: W,
+) -> Result<(), Closed>
+where
+    T: TunnelHandler,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; CHUNK_LEN];
+
+    loop {
+        let chunk = match reader.read(&mut buf).await {
+            Ok(0) => {
+                let _ = writer.shutdown().await;
+                return Ok(());
+            }
+            Ok(len) => Bytes::copy_from_slice(&buf[..len]),
+            Err(e) => {
+                error!("Failed to read from tunnel to {}: {}", ctx.authority, e);
+                return Err(Closed);
+            }
+        };
+
+        ctx.count(direction, chunk.len());
+
+        let action = handler
+            .lock()
+            .await
+            .handle_chunk(ctx, direction, chunk)
+            .await;
+
+        match action {
+            TunnelAction::Forward(chunk) => {
+                if let Err(e) = writer.write_all(&chunk).await {
+                    error!("Failed to write to tunnel to {}: {}", ctx.authority, e);
+                    return Err(Closed);
+                }
+            }
+            TunnelAction::Close => return Err(Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Mutex, time::Duration};
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        chunks: Arc<Mutex<Vec<(TunnelDirection, Bytes)>>>,
+        closed: Arc<Mutex<Option<(u64, u64)>>>,
+    }
+
+    impl TunnelHandler for Recorder {
+        async fn handle_chunk(
+            &mut self,
+            _ctx: &TunnelContext,
+            direction: TunnelDirection,
+            chunk: Bytes,
+        ) -> TunnelAction {
+            self.chunks.lock().unwrap().push((direction, chunk.clone()));
+
+            match &chunk[..] {
+                b"quit" => TunnelAction::Close,
+                _ => TunnelAction::Forward(Bytes::from(chunk.to_ascii_uppercase())),
+            }
+        }
+
+        async fn handle_close(&mut self, ctx: &TunnelContext) {
+            *self.closed.lock().unwrap() = Some((ctx.bytes_from_client(), ctx.bytes_from_server()));
+        }
+    }
+
+    fn context() -> TunnelContext {
+        TunnelContext::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            Authority::from_static("example.com:6379"),
+            Protocol::Unknown,
+        )
+    }
+
+    #[tokio::test]
+    async fn passes_chunks_through_handler() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (server, mut server_peer) = tokio::io::duplex(64);
+        let handler = Recorder::default();
+
+        let tunnel = tokio::spawn(handle_tunnel(handler.clone(), context(), client, server));
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"PING");
+
+        server_peer.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        client_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"PONG!");
+
+        drop(server_peer);
+        drop(client_peer);
+        tunnel.await.unwrap();
+
+        assert_eq!(*handler.closed.lock().unwrap(), Some((4, 5)));
+        assert_eq!(
+            *handler.chunks.lock().unwrap(),
+            [
+                (TunnelDirection::ClientToServer, Bytes::from_static(b"ping")),
+                (
+                    TunnelDirection::ServerToClient,
+                    Bytes::from_static(b"pong!")
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_while_writes_are_blocked() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (server, server_peer) = tokio::io::duplex(64);
+        let handler = Recorder::default();
+        let payload = vec![b'a'; 64 * 1024];
+
+        let tunnel = tokio::spawn(handle_tunnel(handler.clone(), context(), client, server));
+
+        // The server echoes everything back, so neither side of the tunnel can
+        // make progress unless both directions are handled at once.
+        let (mut server_read, mut server_write) = tokio::io::split(server_peer);
+        tokio::spawn(async move { tokio::io::copy(&mut server_read, &mut server_write).await });
+
+        let (mut client_read, mut client_write) = tokio::io::split(&mut client_peer);
+        let write = async {
+            client_write.write_all(&payload).await.unwrap();
+        };
+        let read = async {
+            let mut buf = vec![0; payload.len()];
+            client_read.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+        let (_, echoed) = tokio::time::timeout(Duration::from_secs(5), future::join(write, read))
+            .await
+            .expect("tunnel deadlocked");
+
+        assert!(echoed.iter().all(|&b| b == b'A'));
+
+        drop(client_peer);
+        tunnel.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_when_handler_asks() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (server, mut server_peer) = tokio::io::duplex(64);
+        let handler = Recorder::default();
+
+        let tunnel = tokio::spawn(handle_tunnel(handler.clone(), context(), client, server));
+
+        client_peer.write_all(b"quit").await.unwrap();
+        tunnel.await.unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(server_peer.read_to_end(&mut buf).await.unwrap(), 0);
+        assert_eq!(*handler.closed.lock().unwrap(), Some((4, 0)));
+    }
+
+    #[cfg(all(feature = "rcgen-ca", feature = "rustls-client"))]
+    #[tokio::test]
+    async fn connects_with_client_alpn() {
+        use tokio::net::TcpListener;
+        use tokio_rustls::{
+            TlsAcceptor,
+            rustls::{
+                ClientConfig,
+                RootCertStore,
+                ServerConfig,
+                crypto::aws_lc_rs,
+                pki_types::PrivateKeyDer,
+            },
+        };
+
+        let key = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+        let cert = key.cert.der().clone();
+        let private_key = PrivateKeyDer::try_from(key.signing_key.serialize_der()).unwrap();
+
+        let mut server_config =
+            ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.clone()], private_key)
+                .unwrap();
+        server_config.alpn_protocols = vec![b"mqtt".to_vec()];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = TlsAcceptor::from(Arc::new(server_config))
+                .accept(tcp)
+                .await
+                .unwrap();
+            let alpn = stream.get_ref().1.alpn_protocol().unwrap().to_vec();
+            stream.write_all(&alpn).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config =
+            ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+        let mut ctx = TunnelContext::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            format!("localhost:{port}").parse().unwrap(),
+            Protocol::Tls,
+        );
+        ctx.decrypted = true;
+        ctx.alpn_protocol = Some(b"mqtt".to_vec());
+
+        let connector = Connector::Rustls(Arc::new(client_config));
+        let mut stream = connect_tls(Some(&connector), &ctx).await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"mqtt");
+    }
+}
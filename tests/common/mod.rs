@@ -116,13 +116,29 @@ pub async fn start_http_server() -> Result<(SocketAddr, Sender<()>), Box<dyn std
 
 pub async fn start_https_server(
     ca: impl CertificateAuthority,
+) -> Result<(SocketAddr, Sender<()>), Box<dyn std::error::Error>> {
+    start_https_server_with_alpn(ca, None).await
+}
+
+pub async fn start_http2_only_server(
+    ca: impl CertificateAuthority,
+) -> Result<(SocketAddr, Sender<()>), Box<dyn std::error::Error>> {
+    start_https_server_with_alpn(ca, Some(vec![b"h2".to_vec()])).await
+}
+
+async fn start_https_server_with_alpn(
+    ca: impl CertificateAuthority,
+    alpn_protocols: Option<Vec<Vec<u8>>>,
 ) -> Result<(SocketAddr, Sender<()>), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
     let addr = listener.local_addr()?;
-    let acceptor: tokio_rustls::TlsAcceptor = ca
-        .gen_server_config(&"localhost".parse().unwrap())
-        .await
-        .into();
+    let mut server_config = ca.gen_server_config(&"localhost".parse().unwrap()).await;
+
+    if let Some(alpn_protocols) = alpn_protocols {
+        Arc::make_mut(&mut server_config).alpn_protocols = alpn_protocols;
+    }
+
+    let acceptor: tokio_rustls::TlsAcceptor = server_config.into();
     let (tx, rx) = tokio::sync::oneshot::channel();
 
     tokio::spawn(async move {
@@ -164,7 +180,7 @@ pub fn plain_websocket_connector() -> tokio_tungstenite::Connector {
     tokio_tungstenite::Connector::Plain
 }
 
-fn rustls_client_config() -> rustls::ClientConfig {
+pub fn rustls_client_config() -> rustls::ClientConfig {
     let mut roots = rustls::RootCertStore::empty();
 
     for cert in rustls_native_certs::load_native_certs().unwrap() {
@@ -196,6 +212,15 @@ pub fn rustls_http_connector() -> hyper_rustls::HttpsConnector<HttpConnector> {
         .build()
 }
 
+#[cfg(feature = "http2")]
+pub fn rustls_http2_connector() -> hyper_rustls::HttpsConnector<HttpConnector> {
+    hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(rustls_client_config())
+        .https_or_http()
+        .enable_all_versions()
+        .build()
+}
+
 fn native_tls_connector() -> native_tls::TlsConnector {
     let ca_cert =
         native_tls::Certificate::from_pem(include_bytes!("../../examples/ca/hudsucker.cer"))
@@ -0,0 +1,89 @@
+use hudsucker::{
+    Body,
+    HttpContext,
+    HttpHandler,
+    Proxy,
+    certificate_authority::RcgenAuthority,
+    hyper::{Response, Version},
+    rcgen::{Issuer, KeyPair},
+    rustls::crypto::aws_lc_rs,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::net::TcpListener;
+
+#[allow(unused)]
+mod common;
+
+fn build_ca() -> RcgenAuthority {
+    let key_pair = include_str!("../examples/ca/hudsucker.key");
+    let ca_cert = include_str!("../examples/ca/hudsucker.cer");
+    let key_pair = KeyPair::from_pem(key_pair).expect("Failed to parse private key");
+    let issuer =
+        Issuer::from_ca_cert_pem(ca_cert, key_pair).expect("Failed to parse CA certificate");
+
+    RcgenAuthority::new(issuer, 1000, aws_lc_rs::default_provider())
+}
+
+/// Client and upstream versions seen by `handle_response`.
+type Versions = Vec<(Version, Option<Version>)>;
+
+#[derive(Clone, Default)]
+struct VersionRecorder {
+    versions: Arc<Mutex<Versions>>,
+}
+
+impl HttpHandler for VersionRecorder {
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.versions
+            .lock()
+            .unwrap()
+            .push((ctx.version, ctx.upstream_version));
+        res
+    }
+}
+
+#[tokio::test]
+async fn negotiates_http2_upstream() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = tokio::sync::oneshot::channel::<()>();
+    let handler = VersionRecorder::default();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_ca(build_ca())
+        .with_http_connector(common::rustls_http2_connector())
+        .with_http_handler(handler.clone())
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.start());
+
+    let (server_addr, stop_server) = common::start_http2_only_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .version(Version::HTTP_11)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.version(), Version::HTTP_11);
+    assert_eq!(res.text().await.unwrap(), common::HELLO_WORLD);
+    assert_eq!(
+        handler.versions.lock().unwrap()[..],
+        [(Version::HTTP_11, Some(Version::HTTP_2))]
+    );
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
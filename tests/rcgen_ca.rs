@@ -5,6 +5,7 @@ use hudsucker::{
 };
 use std::sync::atomic::Ordering;
 
+#[allow(unused)]
 mod common;
 
 fn build_ca() -> RcgenAuthority {
@@ -0,0 +1,143 @@
+use async_http_proxy::http_connect_tokio;
+use hudsucker::{
+    Proxy,
+    TunnelAction,
+    TunnelContext,
+    TunnelDirection,
+    TunnelHandler,
+    certificate_authority::{CertificateAuthority, RcgenAuthority},
+    hyper::body::Bytes,
+    rcgen::{Issuer, KeyPair},
+    rustls::{crypto::aws_lc_rs, pki_types::ServerName},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[allow(unused)]
+mod common;
+
+fn build_ca() -> RcgenAuthority {
+    let key_pair = include_str!("../examples/ca/hudsucker.key");
+    let ca_cert = include_str!("../examples/ca/hudsucker.cer");
+    let key_pair = KeyPair::from_pem(key_pair).expect("Failed to parse private key");
+    let issuer =
+        Issuer::from_ca_cert_pem(ca_cert, key_pair).expect("Failed to parse CA certificate");
+
+    RcgenAuthority::new(issuer, 1000, aws_lc_rs::default_provider())
+}
+
+/// Chunks passed to `handle_chunk`, with the ALPN protocol of their tunnel.
+type Chunks = Vec<(TunnelDirection, Option<Vec<u8>>, Bytes)>;
+
+#[derive(Clone, Default)]
+struct ChunkRecorder {
+    chunks: Arc<Mutex<Chunks>>,
+}
+
+impl TunnelHandler for ChunkRecorder {
+    async fn handle_chunk(
+        &mut self,
+        ctx: &TunnelContext,
+        direction: TunnelDirection,
+        chunk: Bytes,
+    ) -> TunnelAction {
+        self.chunks
+            .lock()
+            .unwrap()
+            .push((direction, ctx.alpn_protocol.clone(), chunk.clone()));
+        TunnelAction::Forward(chunk)
+    }
+}
+
+/// Start a TLS server that only speaks `mqtt`, and echoes what it reads.
+async fn start_mqtt_server() -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server_config = build_ca()
+        .gen_server_config(&"localhost".parse().unwrap())
+        .await;
+    Arc::make_mut(&mut server_config).alpn_protocols = vec![b"mqtt".to_vec()];
+
+    tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut stream = TlsAcceptor::from(server_config).accept(tcp).await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn bridges_non_http_alpn() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = tokio::sync::oneshot::channel::<()>();
+    let handler = ChunkRecorder::default();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_ca(build_ca())
+        .with_http_connector(common::rustls_http_connector())
+        .with_tunnel_handler(handler.clone())
+        .with_websocket_connector(common::rustls_websocket_connector())
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.start());
+
+    let server_addr = start_mqtt_server().await;
+
+    let mut tcp = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(&mut tcp, "localhost", server_addr.port())
+        .await
+        .unwrap();
+
+    let mut client_config = common::rustls_client_config();
+    client_config.alpn_protocols = vec![b"mqtt".to_vec()];
+    let mut stream = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"mqtt"[..]));
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"ping");
+
+    let mqtt = Some(b"mqtt".to_vec());
+    assert_eq!(
+        handler.chunks.lock().unwrap()[..],
+        [
+            (
+                TunnelDirection::ClientToServer,
+                mqtt.clone(),
+                Bytes::from_static(b"ping")
+            ),
+            (
+                TunnelDirection::ServerToClient,
+                mqtt,
+                Bytes::from_static(b"ping")
+            ),
+        ]
+    );
+
+    stop_proxy.send(()).unwrap();
+}